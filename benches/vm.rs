@@ -0,0 +1,108 @@
+//! Run with `cargo bench`, `cargo bench --no-default-features` measures the VM with tracing compiled out
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use game_lang::{
+    bytecode::{chunk::CodeChunk, object::ObjectHeap, vm::VM},
+    compile_source,
+    compiler::parser::ParserConfig,
+};
+
+const ITERATIONS: u64 = 10_000_000;
+
+fn compile(source: &str) -> (CodeChunk, ObjectHeap) {
+    compile_source(source, ParserConfig::default()).unwrap()
+}
+
+fn counting_loop(c: &mut Criterion) {
+    // The parentheses keep the generic GET LOCAL, CONSTANT, ADD, SET LOCAL sequence
+    let generic = compile(&format!("{{ let i = 0; while i < {ITERATIONS} {{ i = (i + 1); }} }}"));
+    let increment = compile(&format!("{{ let i = 0; while i < {ITERATIONS} {{ i += 1; }} }}"));
+
+    let mut group = c.benchmark_group("counting_loop");
+    group.sample_size(10);
+    for (name, (code, mut heap)) in [("add_set_local", generic), ("inc_local", increment)] {
+        group.bench_function(name, |b| b.iter(|| VM::init(&code, &mut heap).run().unwrap()));
+    }
+    group.finish();
+}
+
+/// Instruction dispatch with calls, globals and locals, nothing in it allocates.
+///
+/// Tracing is off here, so this is the cost of the trace check that `--no-default-features` removes.
+fn dispatch(c: &mut Criterion) {
+    let (code, mut heap) = compile(
+        "fn step(x) { return x + 1; } let total = 0; { let i = 0; while i < 1000000 { total = step(total); i += 1; } }",
+    );
+    log::set_max_level(log::LevelFilter::Info);
+    let mut group = c.benchmark_group("dispatch");
+    group.sample_size(10);
+    group.bench_function("calls_and_globals", |b| b.iter(|| VM::init(&code, &mut heap).run().unwrap()));
+    group.finish();
+}
+
+/// Reads and writes of globals, which scripts without a `main` use for everything at the top level
+fn globals(c: &mut Criterion) {
+    let (code, mut heap) = compile("let total = 0; let i = 0; while i < 1000000 { total = total + i; i = i + 1; }");
+    let mut group = c.benchmark_group("globals");
+    group.sample_size(10);
+    group.bench_function("top_level_loop", |b| b.iter(|| VM::init(&code, &mut heap).run().unwrap()));
+    group.finish();
+}
+
+/// Deep calls with arguments and locals, and lists of values, so most of the time goes to moving values around.
+///
+/// Compare `cargo bench --bench vm stack` with and without `--features nan-boxing`.
+fn stack(c: &mut Criterion) {
+    let (calls, mut calls_heap) = compile(
+        "fn deep(n, a, b, c) { if n == 0 { return a + b + c; } let x = a + 1; let y = b * 2.0; return deep(n - 1, x, y, c) + 1; } \
+         { let i = 0; let total = 0; while i < 2000 { total += deep(500, i, 0.5, 1); i += 1; } }",
+    );
+    let (lists, mut lists_heap) = compile(
+        "{ let xs = [0; 100000]; let i = 0; while i < 100000 { xs[i] = i * 0.5; i += 1; } \
+         let total = 0; let j = 0; while j < 100000 { total = total + xs[j]; j += 1; } }",
+    );
+    let mut group = c.benchmark_group("stack");
+    group.sample_size(10);
+    group.bench_function("deep_calls", |b| b.iter(|| VM::init(&calls, &mut calls_heap).run().unwrap()));
+    group.bench_function("lists", |b| b.iter(|| VM::init(&lists, &mut lists_heap).run().unwrap()));
+    group.finish();
+}
+
+/// Every workload runs as compiled and with `--optimize`, on a fresh heap each time
+fn workloads(c: &mut Criterion) {
+    let workloads = [
+        (
+            "arithmetic",
+            "{ let i = 0; let x = 0.0; while i < 100000 { x = (x * 3 + i) % 1000 - i / 7 + 2 ** 3; i += 1; } }",
+        ),
+        (
+            "string_concat",
+            r#"{ let s = ""; let i = 0; while i < 1000 { s = s + "ab" + i; i += 1; } }"#,
+        ),
+        (
+            "fibonacci",
+            "fn fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); } fib(20);",
+        ),
+    ];
+    let pipelines = [
+        ("plain", ParserConfig::default()),
+        ("optimized", ParserConfig { optimize: true, ..ParserConfig::default() }),
+    ];
+
+    for (workload, source) in workloads {
+        let mut group = c.benchmark_group(workload);
+        for (pipeline, config) in pipelines {
+            group.bench_function(pipeline, |b| {
+                b.iter_batched(
+                    || compile_source(source, config).unwrap(),
+                    |(code, mut heap)| VM::init(&code, &mut heap).run().unwrap(),
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, counting_loop, dispatch, globals, stack, workloads);
+criterion_main!(benches);
@@ -1,16 +1,18 @@
 use std::{fmt::Display, ops::Range};
 
-use super::object::ObjectHeap;
+use owo_colors::Style;
+
+use super::object::{ObjectHeap, ObjectKey, ObjectKind};
 use super::opcodes::*;
 
-use super::value::Value;
+use super::value::{Value, ValueKind};
 
 /// An executable chunk of code
 #[derive(Debug, Clone)]
 pub struct CodeChunk {
-    code: Vec<u8>,
-    constants: Vec<Value>,
-    span_info: Vec<(usize, Range<usize>)>
+    pub(super) code: Vec<u8>,
+    pub(super) constants: Vec<Value>,
+    pub(super) span_info: Vec<(usize, Range<usize>)>
 }
 
 // ===== Public interface
@@ -23,13 +25,36 @@ impl CodeChunk {
         self.code.push(code);
     }
 
-    pub fn push_constant(&mut self, constant: Value) -> u8 {
+    /// Adds a constant to the pool, returns `None` once the pool is full (256)
+    pub fn push_constant(&mut self, constant: Value) -> Option<u8> {
+        let idx = self.constants.len().try_into().ok()?;
         self.constants.push(constant);
-        (self.constants.len() - 1).try_into().expect("Exceeded maximum numbers of constants in a pool (256)")
+        Some(idx)
+    }
+
+    /// Pool index of a constant referring to the object `key`
+    pub fn find_object_constant(&self, key: ObjectKey) -> Option<u8> {
+        let idx = self
+            .constants
+            .iter()
+            .position(|constant| matches!(constant.kind(), ValueKind::Object(k) if k == key))?;
+        idx.try_into().ok()
     }
 
+    /// Code pushed from now on belongs to `span`, repeated spans are only stored once
     pub fn push_span_info(&mut self, span: Range<usize>) {
-        self.span_info.push((self.code.len(), span))
+        let offset = self.code.len();
+        match self.span_info.last_mut() {
+            Some((_, last)) if *last == span => {}
+            // Nothing was pushed for the previous span
+            Some((last_offset, last)) if *last_offset == offset => *last = span,
+            _ => self.span_info.push((offset, span)),
+        }
+    }
+
+    pub fn push_code_spanned(&mut self, code: u8, span: Range<usize>) {
+        self.push_span_info(span);
+        self.push_code(code);
     }
 
     pub fn get_byte(&self, offset: usize) -> Option<u8> {
@@ -48,6 +73,19 @@ impl CodeChunk {
         self.code.len()
     }
 
+    pub fn constant_count(&self) -> usize {
+        self.constants.len()
+    }
+
+    /// Drops the code from `size` and the constants from `constant_count` on, along with their spans
+    pub fn truncate(&mut self, size: usize, constant_count: usize) {
+        self.code.truncate(size);
+        self.constants.truncate(constant_count);
+        // The first span always stays, it starts at 0
+        let spans = self.span_info.partition_point(|(offset, _)| *offset < size).max(1);
+        self.span_info.truncate(spans);
+    }
+
 }
 
 impl Default for CodeChunk {
@@ -60,16 +98,18 @@ impl Default for CodeChunk {
 
 impl CodeChunk {
     pub fn dissasemble(&self) -> Dissasembler<'_,'_> {
-        Dissasembler { chunk: self, offset: None, heap: None }
+        Dissasembler { chunk: self, offset: None, heap: None, color: false }
     }
 
     fn find_span_offset_of(&self, offset: usize) -> usize {
         self.span_info.partition_point(|&(i,_)| i <= offset)
     }
 
+    /// The span covering the instruction at `offset`, an empty `0..0` one for code without spans
     pub fn find_span_of(&self, offset: usize) -> &(usize, Range<usize>) {
+        static NO_SPAN: (usize, Range<usize>) = (0, 0..0);
         let span_offset = self.find_span_offset_of(offset);
-        &self.span_info[span_offset - 1]
+        span_offset.checked_sub(1).and_then(|idx| self.span_info.get(idx)).unwrap_or(&NO_SPAN)
     }
 
     // fn next_span_offset(&self, current_span: usize, current_offset: usize) -> usize {
@@ -91,7 +131,8 @@ impl Display for CodeChunk {
 pub struct Dissasembler<'code, 'heap> {
     chunk: &'code CodeChunk,
     offset: Option<usize>,
-    heap: Option<&'heap ObjectHeap>
+    heap: Option<&'heap ObjectHeap>,
+    color: bool,
 }
 
 impl<'code, 'heap> Dissasembler<'code, 'heap> {
@@ -103,13 +144,21 @@ impl<'code, 'heap> Dissasembler<'code, 'heap> {
         self.heap = Some(heap);
         self
     }
+    /// Plain by default, callers that write to a terminal decide whether it gets colors
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+    fn style(&self, style: Style) -> Style {
+        if self.color { style } else { Style::new() }
+    }
     #[rustfmt::skip]
     fn dissasemble_instruction(&self, f: &mut impl std::fmt::Write, offset: usize) -> Result<usize, std::fmt::Error> {
         use owo_colors::OwoColorize;
 
         let instr = self.chunk.code[offset];
         let (span_code_offset, span) = self.chunk.find_span_of(offset);
-        write!(f, "{:04} ", offset.red())?;
+        write!(f, "{:04} ", offset.style(self.style(Style::new().red())))?;
         if *span_code_offset == offset {
             write!(f, "{:>3}:{:<3} ", span.start, span.end)?;
         } else {
@@ -123,24 +172,52 @@ impl<'code, 'heap> Dissasembler<'code, 'heap> {
             OP_SUB => { self.dissasemble_op(f, "SUB")?; 1 }
             OP_MUL => { self.dissasemble_op(f, "MUL")?; 1 }
             OP_DIV => { self.dissasemble_op(f, "DIV")?; 1 }
+            OP_REM => { self.dissasemble_op(f, "REM")?; 1 }
+            OP_POW => { self.dissasemble_op(f, "POW")?; 1 }
             OP_NOT => { self.dissasemble_op(f, "NOT")?; 1 }
             OP_AND => { self.dissasemble_op(f, "AND")?; 1 }
             OP_OR => { self.dissasemble_op(f, "OR")?; 1 }
             OP_EQUAL => { self.dissasemble_op(f, "EQUAL")?; 1 }
+            OP_NOT_EQUAL => { self.dissasemble_op(f, "NOT EQUAL")?; 1 }
             OP_GREATER => { self.dissasemble_op(f, "GREATER")?; 1 }
             OP_LESS => { self.dissasemble_op(f, "LESS")?; 1 }
             OP_TRUE => { self.dissasemble_op(f, "TRUE")?; 1 }
             OP_FALSE => { self.dissasemble_op(f, "FALSE")?; 1 }
             OP_NIL => { self.dissasemble_op(f, "NIL")?; 1 }
             OP_PRINT => { self.dissasemble_op(f, "PRINT")?; 1 }
+            OP_WRITE => { self.dissasemble_op(f, "WRITE")?; 1 }
+            OP_ASSERT => { self.dissasemble_op(f, "ASSERT")?; self.dissasemble_arg(f, offset + 1)?; 2 }
             OP_POP => { self.dissasemble_op(f, "POP")?; 1 }
-            OP_DEF_GLOBAL => { self.dissasemble_op(f, "DEF GLOBAL")?; self.dissasemble_constant(f, offset + 1)?; 2 }
-            OP_GET_GLOBAL => { self.dissasemble_op(f, "GET GLOBAL")?; self.dissasemble_constant(f, offset + 1)?; 2 }
-            OP_SET_GLOBAL => { self.dissasemble_op(f, "SET GLOBAL")?; self.dissasemble_constant(f, offset + 1)?; 2 }
+            OP_POPN => { self.dissasemble_op(f, "POPN")?; self.dissasemble_arg(f, offset + 1)?; 2 }
+            OP_DEF_GLOBAL => { self.dissasemble_op(f, "DEF GLOBAL")?; self.dissasemble_identifier(f, offset + 1, "var")?; 2 }
+            OP_GET_GLOBAL => { self.dissasemble_op(f, "GET GLOBAL")?; self.dissasemble_identifier(f, offset + 1, "var")?; 2 }
+            OP_SET_GLOBAL => { self.dissasemble_op(f, "SET GLOBAL")?; self.dissasemble_identifier(f, offset + 1, "var")?; 2 }
+            OP_GET_GLOBAL_SLOT => { self.dissasemble_op(f, "GET GLOBAL SLOT")?; self.dissasemble_global_slot(f, offset + 1)?; 3 }
+            OP_SET_GLOBAL_SLOT => { self.dissasemble_op(f, "SET GLOBAL SLOT")?; self.dissasemble_global_slot(f, offset + 1)?; 3 }
             OP_GET_LOCAL => { self.dissasemble_op(f, "GET LOCAL")?; self.dissasemble_arg(f, offset + 1)?; 2 }
             OP_SET_LOCAL => { self.dissasemble_op(f, "SET LOCAL")?; self.dissasemble_arg(f, offset + 1)?; 2 }
+            OP_GET_LOCAL_LONG => { self.dissasemble_op(f, "GET LOCAL LONG")?; self.dissasemble_wide_arg(f, offset + 1)?; 3 }
+            OP_SET_LOCAL_LONG => { self.dissasemble_op(f, "SET LOCAL LONG")?; self.dissasemble_wide_arg(f, offset + 1)?; 3 }
+            OP_INC_LOCAL => { self.dissasemble_op(f, "INC LOCAL")?; self.dissasemble_arg(f, offset + 1)?; self.dissasemble_constant(f, offset + 2)?; 3 }
             OP_JUMP => { self.dissasemble_op(f, "JUMP")?; self.dissasemble_jump_target(f, offset + 1)?; 3 }
             OP_JUMP_F => { self.dissasemble_op(f, "JUMPF")?; self.dissasemble_jump_target(f, offset + 1)?; 3 }
+            OP_JUMP_NIL => { self.dissasemble_op(f, "JUMPNIL")?; self.dissasemble_jump_target(f, offset + 1)?; 3 }
+            OP_CALL => { self.dissasemble_op(f, "CALL")?; self.dissasemble_arg(f, offset + 1)?; 2 }
+            OP_CONCAT_N => { self.dissasemble_op(f, "CONCAT N")?; self.dissasemble_arg(f, offset + 1)?; 2 }
+            OP_BUILD_LIST => { self.dissasemble_op(f, "BUILD LIST")?; self.dissasemble_arg(f, offset + 1)?; 2 }
+            OP_INDEX_GET => { self.dissasemble_op(f, "INDEX GET")?; 1 }
+            OP_INDEX_SET => { self.dissasemble_op(f, "INDEX SET")?; 1 }
+            OP_YIELD => { self.dissasemble_op(f, "YIELD")?; 1 }
+            OP_ARRAY_REPEAT => { self.dissasemble_op(f, "ARRAY REPEAT")?; 1 }
+            OP_IS_CLASS => { self.dissasemble_op(f, "IS CLASS")?; 1 }
+            OP_IS => { self.dissasemble_op(f, "IS")?; self.dissasemble_identifier(f, offset + 1, "type")?; 2 }
+            OP_CLASS => { self.dissasemble_op(f, "CLASS")?; self.dissasemble_identifier(f, offset + 1, "class")?; 2 }
+            OP_METHOD => { self.dissasemble_op(f, "METHOD")?; self.dissasemble_identifier(f, offset + 1, "method")?; 2 }
+            OP_GET_FIELD => { self.dissasemble_op(f, "GET FIELD")?; self.dissasemble_identifier(f, offset + 1, "field")?; 2 }
+            OP_SET_FIELD => { self.dissasemble_op(f, "SET FIELD")?; self.dissasemble_identifier(f, offset + 1, "field")?; 2 }
+            OP_INVOKE => { self.dissasemble_op(f, "INVOKE")?; self.dissasemble_identifier(f, offset + 1, "method")?; self.dissasemble_arg(f, offset + 2)?; 3 }
+            OP_BUILD_SET => { self.dissasemble_op(f, "BUILD SET")?; self.dissasemble_arg(f, offset + 1)?; 2 }
+            OP_BUILD_MAP => { self.dissasemble_op(f, "BUILD MAP")?; self.dissasemble_arg(f, offset + 1)?; 2 }
             _ => { self.dissasemble_op(f, "UNKNOWN")?; 1 }
         };
     
@@ -150,7 +227,7 @@ impl<'code, 'heap> Dissasembler<'code, 'heap> {
     fn dissasemble_op(&self, f: &mut impl std::fmt::Write, name: &str) -> Result<(), std::fmt::Error> {
         use owo_colors::OwoColorize;
 
-        write!(f, "{:<10}", name.bold())
+        write!(f, "{:<10}", name.style(self.style(Style::new().bold())))
     }
 
     fn dissasemble_constant(&self, f: &mut impl std::fmt::Write, offset: usize) -> Result<(), std::fmt::Error> {
@@ -159,8 +236,25 @@ impl<'code, 'heap> Dissasembler<'code, 'heap> {
         let constant = self.chunk.code[offset];
         let constant_value = &self.chunk.constants[constant as usize];
         match self.heap {
-            Some(heap) => write!(f, " {:>3} '{}'", constant.green(), constant_value.print_with_heap(heap).green().bold()),
-            None => write!(f, " {:>3} '{}'", constant.green(), constant_value.green().bold()),
+            Some(heap) if constant_value.as_string(heap).is_some() => {
+                write!(f, " {:>3} str {}", constant.style(self.style(Style::new().green())), constant_value.print_with_heap(heap).quoted().style(self.style(Style::new().green().bold())))
+            }
+            Some(heap) => write!(f, " {:>3} '{}'", constant.style(self.style(Style::new().green())), constant_value.print_with_heap(heap).style(self.style(Style::new().green().bold()))),
+            // Objects already render as `<obj#N>`, quoting them would make them look like strings
+            None if matches!(constant_value.kind(), ValueKind::Object(_)) => write!(f, " {:>3} {}", constant.style(self.style(Style::new().green())), constant_value.style(self.style(Style::new().green().bold()))),
+            None => write!(f, " {:>3} '{}'", constant.style(self.style(Style::new().green())), constant_value.style(self.style(Style::new().green().bold()))),
+        }
+    }
+
+    /// Like [`Self::dissasemble_constant`], but the constant names a global variable or a method
+    fn dissasemble_identifier(&self, f: &mut impl std::fmt::Write, offset: usize, kind: &str) -> Result<(), std::fmt::Error> {
+        use owo_colors::OwoColorize;
+
+        let constant = self.chunk.code[offset];
+        let constant_value = &self.chunk.constants[constant as usize];
+        match self.heap {
+            Some(heap) => write!(f, " {:>3} {kind} '{}'", constant.style(self.style(Style::new().green())), constant_value.print_with_heap(heap).style(self.style(Style::new().green().bold()))),
+            None => write!(f, " {:>3} {kind} {}", constant.style(self.style(Style::new().green())), constant_value.style(self.style(Style::new().green().bold()))),
         }
     }
 
@@ -168,14 +262,33 @@ impl<'code, 'heap> Dissasembler<'code, 'heap> {
         use owo_colors::OwoColorize;
         
         let arg = self.chunk.code[offset];
-        write!(f, " {:>3}", arg.green())
+        write!(f, " {:>3}", arg.style(self.style(Style::new().green())))
+    }
+
+    fn dissasemble_wide_arg(&self, f: &mut impl std::fmt::Write, offset: usize) -> Result<(), std::fmt::Error> {
+        use owo_colors::OwoColorize;
+
+        let arg = u16::from_be_bytes([self.chunk.code[offset], self.chunk.code[offset+1]]);
+        write!(f, " {:>3}", arg.style(self.style(Style::new().green())))
+    }
+
+    /// Like [`Self::dissasemble_wide_arg`], the heap knows the name of the global in the slot
+    fn dissasemble_global_slot(&self, f: &mut impl std::fmt::Write, offset: usize) -> Result<(), std::fmt::Error> {
+        use owo_colors::OwoColorize;
+
+        self.dissasemble_wide_arg(f, offset)?;
+        let slot = u16::from_be_bytes([self.chunk.code[offset], self.chunk.code[offset+1]]);
+        match self.heap.and_then(|heap| Some((heap, heap.global_name(slot as usize)?))) {
+            Some((heap, name)) => write!(f, " var '{}'", Value::object(name).print_with_heap(heap).style(self.style(Style::new().green().bold()))),
+            None => Ok(()),
+        }
     }
 
     fn dissasemble_jump_target(&self, f: &mut impl std::fmt::Write, offset: usize) -> Result<(), std::fmt::Error> {
         use owo_colors::OwoColorize;
 
         let arg = i16::from_be_bytes([self.chunk.code[offset], self.chunk.code[offset+1]]);
-        write!(f, " {:>3} -> {:>04}", arg.green(), (offset as isize + arg as isize + 2).red())
+        write!(f, " {:>3} -> {:>04}", arg.style(self.style(Style::new().green())), (offset as isize + arg as isize + 2).style(self.style(Style::new().red())))
     }
     
     fn dissasemble_chunk(&self, f: &mut impl std::fmt::Write) -> Result<(), std::fmt::Error> {
@@ -185,6 +298,18 @@ impl<'code, 'heap> Dissasembler<'code, 'heap> {
             writeln!(f)?;
             offset += len;
         }
+
+        // Functions are compiled into their own chunks, which are only reachable through the heap
+        if let Some(heap) = self.heap {
+            for constant in &self.chunk.constants {
+                let ValueKind::Object(key) = constant.kind() else { continue };
+                let Ok(object) = heap.get_object(key) else { continue };
+                if let ObjectKind::Function(function) = &object.kind {
+                    writeln!(f, "== {} ==", function.name)?;
+                    function.chunk.dissasemble().with_heap(heap).with_color(self.color).dissasemble_chunk(f)?;
+                }
+            }
+        }
     
         Ok(())
     }
@@ -208,11 +333,145 @@ mod tests {
     fn test() {
         let mut chunk = CodeChunk::new();
         chunk.push_span_info(0..10);
-        let constant = chunk.push_constant(Value::Number(1.2));
+        let constant = chunk.push_constant(Value::number(1.2)).unwrap();
         chunk.push_code(OP_CONSTANT);
         chunk.push_code(constant);
         chunk.push_span_info(10..20);
         chunk.push_code(OP_RETURN);
         eprintln!("{chunk}");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn span_compression_test() {
+        let mut chunk = CodeChunk::new();
+        chunk.push_code_spanned(OP_NIL, 0..3);
+        chunk.push_code_spanned(OP_NIL, 0..3);
+        chunk.push_span_info(4..5);
+        chunk.push_code_spanned(OP_POP, 6..7);
+
+        assert_eq!(chunk.span_info, [(0, 0..3), (2, 6..7)]);
+        assert_eq!(chunk.find_span_of(1).1, 0..3);
+        assert_eq!(CodeChunk::new().find_span_of(0), &(0, 0..0));
+    }
+
+    #[test]
+    fn int_and_float_constants_test() {
+        let mut chunk = CodeChunk::new();
+        for value in [Value::int(5), Value::number(5.0), Value::number(0.1)] {
+            let constant = chunk.push_constant(value).unwrap();
+            chunk.push_code(OP_CONSTANT);
+            chunk.push_code(constant);
+        }
+        let dump = plain(&chunk.dissasemble().to_string());
+
+        assert!(dump.contains("CONSTANT     0 '5'"), "{dump}");
+        assert!(dump.contains("CONSTANT     1 '5.0'"), "{dump}");
+        assert!(dump.contains("CONSTANT     2 '0.1'"), "{dump}");
+    }
+
+    /// Drops the color escape codes
+    fn plain(colored: &str) -> String {
+        let mut plain = String::new();
+        let mut chars = colored.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|c| *c == 'm');
+            } else {
+                plain.push(c);
+            }
+        }
+        plain
+    }
+
+    #[test]
+    fn identifiers_and_strings_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        crate::compiler::parser::Parser::parse_source(r#"let foo = "foo"; print foo;"#, &mut code, &mut heap).unwrap();
+        let dump = plain(&code.dissasemble().with_heap(&heap).to_string());
+        // Natives take the first global slots
+        let foo = heap.intern_string("foo".into());
+        let slot = heap.global_slot(foo).unwrap();
+
+        assert!(dump.contains(r#"CONSTANT     0 str "foo""#), "{dump}");
+        assert!(dump.contains("DEF GLOBAL   0 var 'foo'"), "{dump}");
+        assert!(dump.contains(&format!("GET GLOBAL SLOT {slot:>3} var 'foo'")), "{dump}");
+
+        let dump = code.dissasemble().with_color(false).to_string();
+        assert!(dump.contains("CONSTANT     0 <obj#"), "{dump}");
+        assert!(dump.contains("DEF GLOBAL   0 var <obj#"), "{dump}");
+        assert!(dump.lines().any(|line| line.ends_with(&format!("GET GLOBAL SLOT {slot:>3}"))), "{dump}");
+    }
+
+    #[test]
+    fn compound_assignment_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        crate::compiler::parser::Parser::parse_source("let x = 1; { let y = 2; y *= 3; } x += 4;", &mut code, &mut heap).unwrap();
+        // Instructions without their offsets and spans
+        let instructions: Vec<String> = code
+            .dissasemble()
+            .with_heap(&heap)
+            .with_color(false)
+            .to_string()
+            .lines()
+            .map(|line| line.split_whitespace().skip(2).collect::<Vec<_>>().join(" "))
+            .collect();
+
+        let x = heap.intern_string("x".into());
+        let slot = heap.global_slot(x).unwrap();
+
+        let local = ["GET LOCAL 0", "CONSTANT 3 '3'", "MUL", "SET LOCAL 0"];
+        let get = format!("GET GLOBAL SLOT {slot} var 'x'");
+        let set = format!("SET GLOBAL SLOT {slot} var 'x'");
+        let global = [get.as_str(), "CONSTANT 4 '4'", "ADD", set.as_str()];
+        assert!(instructions.windows(4).any(|window| window == local), "{instructions:#?}");
+        assert!(instructions.windows(4).any(|window| window == global), "{instructions:#?}");
+    }
+
+    #[test]
+    fn inc_local_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let source = "{ let i = 0; i = i + 1; i += 2.5; i = i + 1 * 2; i += i; } let g = 0; g += 1;";
+        crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        let dump = plain(&code.dissasemble().with_heap(&heap).to_string());
+
+        assert!(dump.contains("INC LOCAL    0   1 '1'"), "{dump}");
+        assert!(dump.contains("INC LOCAL    0   2 '2.5'"), "{dump}");
+        // Only plain number literals on locals get the fast path
+        assert_eq!(dump.matches("INC LOCAL").count(), 2, "{dump}");
+        assert_eq!(dump.matches("ADD").count(), 3, "{dump}");
+    }
+
+    #[test]
+    fn literal_folding_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let source = r#"let x = 2 * 3.14 * 10; print "a" + "b" + 1; print -(1 + 1) < 0;"#;
+        crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        let dump = plain(&code.dissasemble().with_heap(&heap).to_string());
+
+        assert!(dump.contains("CONSTANT     0 '62.800000000000004'"), "{dump}");
+        assert!(dump.contains("CONSTANT     2 str \"ab1\""), "{dump}");
+        assert!(dump.contains("TRUE"), "{dump}");
+        // The operands are gone from the pool along with their instructions
+        assert_eq!(code.constant_count(), 3, "{dump}");
+        assert_eq!(code.get_byte(2), Some(OP_DEF_GLOBAL), "{dump}");
+        // The folded value is spanned over the whole expression
+        assert_eq!(&source[code.find_span_of(0).1.clone()], "2 * 3.14 * 10");
+    }
+
+    #[test]
+    fn without_color_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        crate::compiler::parser::Parser::parse_source("fn f(x) { return x + 1; } print f(1);", &mut code, &mut heap).unwrap();
+        let colored = code.dissasemble().with_heap(&heap).with_color(true).to_string();
+        let dump = code.dissasemble().with_heap(&heap).with_color(false).to_string();
+
+        assert!(colored.contains('\x1b'));
+        assert!(!dump.contains('\x1b'), "{dump}");
+        assert_eq!(dump, plain(&colored));
+    }
+}
@@ -2,4 +2,7 @@ pub mod chunk;
 pub mod value;
 pub mod vm;
 pub mod opcodes;
-pub mod object;
\ No newline at end of file
+pub mod object;
+pub mod serialize;
+pub mod natives;
+pub mod optimize;
\ No newline at end of file
@@ -0,0 +1,947 @@
+use std::{sync::OnceLock, time::{Duration, Instant}};
+
+use ahash::{AHashMap, AHashSet};
+use ecow::eco_format;
+
+use super::{
+    object::{NativeBody, NativeFunction, Object, ObjectHeap, ObjectKind},
+    value::{MapKey, Value, ValueKind, TYPE_NAMES},
+    vm::{CoroutineStatus, Resumed, RuntimeError, VM},
+};
+
+/// Functions implemented in Rust, defined as globals in every new heap
+const NATIVES: &[NativeFunction] = &[
+    NativeFunction {
+        name: "str",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(str),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "len",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(len),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "int",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(int),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "float",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(float),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "is_nil",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(is_nil),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "clamp",
+        arity: 3,
+        optional: 0,
+        function: NativeBody::Heap(clamp),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "in_range",
+        arity: 3,
+        optional: 0,
+        function: NativeBody::Heap(in_range),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "wrap",
+        arity: 3,
+        optional: 0,
+        function: NativeBody::Heap(wrap),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "sort",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(sort),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "sort_by",
+        arity: 2,
+        optional: 0,
+        function: NativeBody::Vm(sort_by),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "bind",
+        arity: 2,
+        optional: 0,
+        function: NativeBody::Heap(bind),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "assert_type",
+        arity: 2,
+        optional: 0,
+        function: NativeBody::Heap(assert_type),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "set_add",
+        arity: 2,
+        optional: 0,
+        function: NativeBody::Heap(set_add),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "set_contains",
+        arity: 2,
+        optional: 0,
+        function: NativeBody::Heap(set_contains),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "set_remove",
+        arity: 2,
+        optional: 0,
+        function: NativeBody::Heap(set_remove),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "set_len",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(set_len),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "coroutine",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Vm(coroutine),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "resume",
+        arity: 2,
+        optional: 1,
+        function: NativeBody::Vm(resume),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "coroutine_done",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(coroutine_done),
+        deterministic: true,
+    },
+    NativeFunction {
+        name: "now",
+        arity: 0,
+        optional: 0,
+        function: NativeBody::Heap(now),
+        deterministic: false,
+    },
+    NativeFunction {
+        name: "sleep",
+        arity: 1,
+        optional: 0,
+        function: NativeBody::Heap(sleep),
+        deterministic: true,
+    },
+];
+
+/// Methods of the built-in types by the type name of their receiver, which is passed as the first argument.
+///
+/// Methods that only change the receiver return it, so calls can be chained.
+const METHODS: &[(&str, NativeFunction)] = &[
+    ("List", NativeFunction { name: "push", arity: 1, optional: 0, function: NativeBody::Heap(list_push), deterministic: true }),
+    ("List", NativeFunction { name: "pop", arity: 0, optional: 0, function: NativeBody::Heap(list_pop), deterministic: true }),
+    ("List", NativeFunction { name: "len", arity: 0, optional: 0, function: NativeBody::Heap(len), deterministic: true }),
+    ("Map", NativeFunction { name: "len", arity: 0, optional: 0, function: NativeBody::Heap(len), deterministic: true }),
+    ("Map", NativeFunction { name: "has", arity: 1, optional: 0, function: NativeBody::Heap(map_has), deterministic: true }),
+    ("Map", NativeFunction { name: "get", arity: 1, optional: 0, function: NativeBody::Heap(map_get), deterministic: true }),
+    ("Map", NativeFunction { name: "remove", arity: 1, optional: 0, function: NativeBody::Heap(map_remove), deterministic: true }),
+    ("String", NativeFunction { name: "len", arity: 0, optional: 0, function: NativeBody::Heap(len), deterministic: true }),
+    ("String", NativeFunction { name: "substring", arity: 2, optional: 0, function: NativeBody::Heap(string_substring), deterministic: true }),
+    ("String", NativeFunction { name: "contains", arity: 1, optional: 0, function: NativeBody::Heap(string_contains), deterministic: true }),
+    ("String", NativeFunction { name: "upper", arity: 0, optional: 0, function: NativeBody::Heap(string_upper), deterministic: true }),
+    ("String", NativeFunction { name: "lower", arity: 0, optional: 0, function: NativeBody::Heap(string_lower), deterministic: true }),
+];
+
+pub(super) fn find_method(receiver: &str, name: &str) -> Option<NativeFunction> {
+    METHODS
+        .iter()
+        .find(|(method_receiver, method)| *method_receiver == receiver && method.name == name)
+        .map(|(_, method)| *method)
+}
+
+pub(super) fn register(heap: &mut ObjectHeap) {
+    for native in NATIVES {
+        let identifier = heap.intern_string(native.name.into());
+        let key = heap.alloc_object(Object::new(ObjectKind::Native(*native)));
+        heap.put_as_global(identifier, Value::object(key));
+    }
+}
+
+/// `str(value)`, the value as `print` would show it
+fn str(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args[0].as_string(heap).is_some() {
+        return Ok(args[0]);
+    }
+    let string = eco_format!("{}", args[0].print_with_heap(heap));
+    Ok(Value::object(heap.try_intern_string(string)?))
+}
+
+/// `len(value)`, the number of characters of a string or elements of a list, map or set
+fn len(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let len = match args[0].kind() {
+        ValueKind::Object(key) => match &heap.get_object(key)?.kind {
+            ObjectKind::String(string) => Some(string.chars().count()),
+            ObjectKind::List(list) => Some(list.len()),
+            ObjectKind::Map(map) => Some(map.len()),
+            ObjectKind::Set(set) => Some(set.len()),
+            _ => None,
+        },
+        _ => None,
+    };
+    match len {
+        Some(len) => Ok(Value::int(len as i64)),
+        None => Err(RuntimeError::TypeError {
+            op: "take the length of",
+            lhs: args[0].type_name(heap),
+            rhs: None,
+        }),
+    }
+}
+
+/// `is_nil(value)`, only `nil` is nil, even though `false` is falsey too
+fn is_nil(_heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::bool(args[0].is_nil()))
+}
+
+/// `bind(f, arg)`, a function calling `f` with `arg` before the arguments it gets
+fn bind(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args[0].type_name(heap) != "Function" {
+        return Err(RuntimeError::TypeError {
+            op: "bind",
+            lhs: args[0].type_name(heap),
+            rhs: None,
+        });
+    }
+    let bound = ObjectKind::BoundMethod {
+        receiver: args[1],
+        function: args[0],
+        is_method: false,
+    };
+    Ok(Value::object(heap.try_alloc_object(Object::new(bound))?))
+}
+
+/// Every name [`Value::type_name`] can return for a live value
+/// `assert_type(value, type)`, returns `value` if its type is named `type`, like `"Int"`.
+///
+/// Names are matched exactly, so an `Int` isn't a `Number`.
+fn assert_type(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Some(name) = args[1].as_string(heap) else {
+        return Err(RuntimeError::InvalidArgument(eco_format!(
+            "assert_type expects a type name, got {}",
+            args[1].type_name(heap)
+        )));
+    };
+    let Some(expected) = TYPE_NAMES.iter().find(|expected| **expected == name.as_str()) else {
+        return Err(RuntimeError::InvalidArgument(eco_format!(
+            "Unknown type '{name}', expected one of {}",
+            TYPE_NAMES.join(", ")
+        )));
+    };
+    let actual = args[0].type_name(heap);
+    if actual != *expected {
+        return Err(RuntimeError::TypeAssertionFailed { expected, actual });
+    }
+    Ok(args[0])
+}
+
+fn conversion_error(heap: &ObjectHeap, value: &Value, to: &str) -> RuntimeError {
+    let value = match value.as_string(heap) {
+        Some(_) => eco_format!("{}", value.print_with_heap(heap).quoted()),
+        None => eco_format!("{}", value.type_name(heap)),
+    };
+    RuntimeError::InvalidArgument(eco_format!("Cannot convert {value} to {to}"))
+}
+
+/// `int(value)`, floats are truncated towards zero and strings parsed
+fn int(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let int = match args[0].kind() {
+        ValueKind::Int(num) => Some(num),
+        ValueKind::Number(num) => float_to_int(num),
+        _ => args[0].as_string(heap).and_then(|string| {
+            let string = string.trim();
+            string.parse().ok().or_else(|| string.parse().ok().and_then(float_to_int))
+        }),
+    };
+    int.map(Value::int).ok_or_else(|| conversion_error(heap, &args[0], "Int"))
+}
+
+/// Truncates towards zero, `None` for NaN, infinities and values out of the `Int` range
+fn float_to_int(num: f64) -> Option<i64> {
+    let num = num.trunc();
+    Value::in_int_range(num).then_some(num as i64)
+}
+
+/// `float(value)`, ints are widened and strings parsed
+fn float(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let number = match args[0].kind() {
+        ValueKind::Int(num) => Some(num as f64),
+        ValueKind::Number(num) => Some(num),
+        _ => args[0].as_string(heap).and_then(|string| string.trim().parse::<f64>().ok()),
+    };
+    number
+        .map(Value::number)
+        .ok_or_else(|| conversion_error(heap, &args[0], "Number"))
+}
+
+fn list_mut<'heap>(heap: &'heap mut ObjectHeap, list: &Value) -> Result<&'heap mut Vec<Value>, RuntimeError> {
+    match list.kind() {
+        ValueKind::Object(key) => match &mut heap.get_object_mut(key)?.kind {
+            ObjectKind::List(list) => Ok(list),
+            _ => Err(RuntimeError::UnknownError),
+        },
+        _ => Err(RuntimeError::UnknownError),
+    }
+}
+
+/// `list.push(value)`, appends to the end and returns the list
+fn list_push(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    heap.grow(std::mem::size_of::<Value>())?;
+    list_mut(heap, &args[0])?.push(args[1]);
+    Ok(args[0])
+}
+
+/// `list.pop()`, removes the last element and returns it, `nil` for an empty list
+fn list_pop(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(list_mut(heap, &args[0])?.pop().unwrap_or(Value::NIL))
+}
+
+fn map_mut<'heap>(heap: &'heap mut ObjectHeap, map: &Value) -> Result<&'heap mut AHashMap<MapKey, Value>, RuntimeError> {
+    match map.kind() {
+        ValueKind::Object(key) => match &mut heap.get_object_mut(key)?.kind {
+            ObjectKind::Map(map) => Ok(map),
+            _ => Err(RuntimeError::UnknownError),
+        },
+        _ => Err(RuntimeError::UnknownError),
+    }
+}
+
+/// `map.has(key)`, whether the map has an entry for `key`
+fn map_has(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let key = MapKey::new(args[1], heap);
+    Ok(Value::bool(map_mut(heap, &args[0])?.contains_key(&key)))
+}
+
+/// `map.get(key)`, the same as `map[key]`
+fn map_get(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let key = MapKey::new(args[1], heap);
+    Ok(map_mut(heap, &args[0])?.get(&key).copied().unwrap_or(Value::NIL))
+}
+
+/// `map.remove(key)`, removes the entry and returns its value, `nil` if there was none
+fn map_remove(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let key = MapKey::new(args[1], heap);
+    Ok(map_mut(heap, &args[0])?.remove(&key).unwrap_or(Value::NIL))
+}
+
+/// The set passed to a `set_` native, `op` describes the native for the error when it's not a set
+fn set_mut<'heap>(heap: &'heap mut ObjectHeap, set: &Value, op: &'static str) -> Result<&'heap mut AHashSet<MapKey>, RuntimeError> {
+    let lhs = set.type_name(heap);
+    if let ValueKind::Object(key) = set.kind() {
+        if let ObjectKind::Set(set) = &mut heap.get_object_mut(key)?.kind {
+            return Ok(set);
+        }
+    }
+    Err(RuntimeError::TypeError { op, lhs, rhs: None })
+}
+
+/// `set_add(set, value)`, adds `value` if it isn't in the set yet and returns the set
+fn set_add(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    heap.reserve(std::mem::size_of::<MapKey>())?;
+    let element = MapKey::new(args[1], heap);
+    if set_mut(heap, &args[0], "add an element to")?.insert(element) {
+        heap.grow(std::mem::size_of::<MapKey>())?;
+    }
+    Ok(args[0])
+}
+
+/// `set_contains(set, value)`, whether `value` is in the set
+fn set_contains(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let element = MapKey::new(args[1], heap);
+    let set = set_mut(heap, &args[0], "look for an element in")?;
+    Ok(Value::bool(set.contains(&element)))
+}
+
+/// `set_remove(set, value)`, removes `value` and returns whether it was in the set
+fn set_remove(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let element = MapKey::new(args[1], heap);
+    let removed = set_mut(heap, &args[0], "remove an element from")?.remove(&element);
+    if removed {
+        heap.shrink(std::mem::size_of::<MapKey>());
+    }
+    Ok(Value::bool(removed))
+}
+
+/// `set_len(set)`, the number of elements in the set
+fn set_len(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let set = set_mut(heap, &args[0], "take the set length of")?;
+    Ok(Value::int(set.len() as i64))
+}
+
+/// The receiver of a `String` method
+fn receiver_string<'heap>(heap: &'heap ObjectHeap, receiver: &Value) -> Result<&'heap str, RuntimeError> {
+    receiver.as_string(heap).map(|string| string.as_str()).ok_or(RuntimeError::UnknownError)
+}
+
+/// A bound of `substring`, which can also be the length itself
+fn substring_bound(heap: &ObjectHeap, bound: Value, len: usize) -> Result<usize, RuntimeError> {
+    let ValueKind::Int(index) = bound.kind() else {
+        return Err(RuntimeError::InvalidIndex {
+            kind: "String",
+            index: bound.type_name(heap),
+        });
+    };
+    usize::try_from(index)
+        .ok()
+        .filter(|idx| *idx <= len)
+        .ok_or(RuntimeError::IndexOutOfBounds { kind: "String", index, len })
+}
+
+/// `string.substring(start, end)`, the characters from `start` up to `end`, not including it
+fn string_substring(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = receiver_string(heap, &args[0])?;
+    let len = string.chars().count();
+    let start = substring_bound(heap, args[1], len)?;
+    let end = substring_bound(heap, args[2], len)?;
+    if start > end {
+        return Err(RuntimeError::InvalidArgument(eco_format!(
+            "substring expects start <= end, got {start} and {end}"
+        )));
+    }
+    let substring = string.chars().skip(start).take(end - start).collect();
+    Ok(Value::object(heap.try_intern_string(substring)?))
+}
+
+/// `string.contains(needle)`, whether `needle` appears anywhere in the string
+fn string_contains(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let string = receiver_string(heap, &args[0])?;
+    let Some(needle) = args[1].as_string(heap) else {
+        return Err(RuntimeError::TypeError {
+            op: "search a String for",
+            lhs: args[1].type_name(heap),
+            rhs: None,
+        });
+    };
+    Ok(Value::bool(string.contains(needle.as_str())))
+}
+
+/// `string.upper()`, a new string in upper case
+fn string_upper(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let upper = receiver_string(heap, &args[0])?.to_uppercase();
+    Ok(Value::object(heap.try_intern_string(upper.into())?))
+}
+
+/// `string.lower()`, a new string in lower case
+fn string_lower(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let lower = receiver_string(heap, &args[0])?.to_lowercase();
+    Ok(Value::object(heap.try_intern_string(lower.into())?))
+}
+
+/// Elements of the list argument of `op`, copied so the heap can be used while sorting them
+fn list_elements(heap: &ObjectHeap, op: &'static str, list: &Value) -> Result<Vec<Value>, RuntimeError> {
+    if let ValueKind::Object(key) = list.kind() {
+        if let ObjectKind::List(list) = &heap.get_object(key)?.kind {
+            return Ok(list.clone());
+        }
+    }
+    Err(RuntimeError::TypeError {
+        op,
+        lhs: list.type_name(heap),
+        rhs: None,
+    })
+}
+
+/// Stable merge sort with a comparison that can fail, `less(a, b)` is true when `a` goes before `b`
+fn merge_sort<E>(
+    items: &mut Vec<Value>,
+    less: &mut impl FnMut(&Value, &Value) -> Result<bool, E>,
+) -> Result<(), E> {
+    if items.len() < 2 {
+        return Ok(());
+    }
+    let mut right = items.split_off(items.len() / 2);
+    merge_sort(items, less)?;
+    merge_sort(&mut right, less)?;
+
+    let mut left = std::mem::take(items).into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        // Equal elements are taken from the left first, which keeps them in order
+        let next = if less(b, a)? { right.next() } else { left.next() };
+        items.extend(next);
+    }
+    items.extend(left);
+    items.extend(right);
+    Ok(())
+}
+
+/// `sort(list)`, sorts numbers or strings in place in ascending order and returns the list
+fn sort(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut items = list_elements(heap, "sort", &args[0])?;
+    // Checked up front, comparing alone would miss mixed lists that happen to be in order
+    if let Some(first) = items.first() {
+        let is_number = first.as_float().is_some();
+        if !is_number && first.as_string(heap).is_none() {
+            return Err(RuntimeError::TypeError {
+                op: "sort",
+                lhs: first.type_name(heap),
+                rhs: None,
+            });
+        }
+        let comparable = |value: &&Value| match is_number {
+            true => value.as_float().is_some(),
+            false => value.as_string(heap).is_some(),
+        };
+        if let Some(other) = items.iter().find(|value| !comparable(value)) {
+            return Err(RuntimeError::TypeError {
+                op: "sort",
+                lhs: first.type_name(heap),
+                rhs: Some(other.type_name(heap)),
+            });
+        }
+    }
+    merge_sort(&mut items, &mut |a, b| Ok::<_, RuntimeError>(!a.less(b, heap)?.is_falsey()))?;
+    *list_mut(heap, &args[0])? = items;
+    Ok(args[0])
+}
+
+/// `sort_by(list, less)`, like `sort` but `less(a, b)` decides if `a` goes before `b`
+fn sort_by(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut items = list_elements(vm.heap(), "sort_by", &args[0])?;
+    merge_sort(&mut items, &mut |a, b| Ok::<_, RuntimeError>(!vm.call_from_native(args[1], &[*a, *b])?.is_falsey()))?;
+    *list_mut(vm.heap_mut(), &args[0])? = items;
+    Ok(args[0])
+}
+
+/// `coroutine(f)`, a coroutine that calls `f` without arguments when it's first resumed
+fn coroutine(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    vm.new_coroutine(args[0])
+}
+
+/// `resume(co, value)`, runs `co` until it yields or returns and evaluates to that value.
+/// The `yield` it stopped at evaluates to `value`, `nil` for `resume(co)`.
+fn resume(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match vm.resume_coroutine(args[0], args[1])? {
+        Resumed::Yielded(value) | Resumed::Returned(value) => Ok(value),
+    }
+}
+
+/// `coroutine_done(co)`, whether the function of `co` returned, so it can't be resumed again
+fn coroutine_done(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0].as_object().map(|key| heap.get_object(key)).transpose()?.map(|object| &object.kind) {
+        Some(ObjectKind::Coroutine(coroutine)) => Ok(Value::bool(coroutine.status() == CoroutineStatus::Finished)),
+        _ => Err(RuntimeError::TypeError {
+            op: "check if done",
+            lhs: args[0].type_name(heap),
+            rhs: None,
+        }),
+    }
+}
+
+/// When `now` started counting, the first time the clock was read
+static CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
+/// `now()`, monotonic milliseconds as a float, only differences between readings are meaningful
+fn now(_heap: &mut ObjectHeap, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let start = CLOCK_START.get_or_init(Instant::now);
+    Ok(Value::number(start.elapsed().as_secs_f64() * 1000.0))
+}
+
+/// `sleep(ms)`, blocks the whole VM. It's a single instruction, so it barely counts against
+/// the instruction limit of [`VM::run_with_limit`].
+fn sleep(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Some(ms) = args[0].as_float() else {
+        return Err(RuntimeError::TypeError {
+            op: "sleep for",
+            lhs: args[0].type_name(heap),
+            rhs: None,
+        });
+    };
+    let duration = Duration::try_from_secs_f64(ms / 1000.0).map_err(|_| {
+        RuntimeError::InvalidArgument(eco_format!("sleep expects a non-negative duration, got {}", args[0]))
+    })?;
+    std::thread::sleep(duration);
+    Ok(Value::NIL)
+}
+
+/// Arguments of the range natives, `Int`s are only kept when all of them are `Int`s
+enum RangeArgs {
+    Int { value: i64, lo: i64, hi: i64 },
+    Number { value: f64, lo: f64, hi: f64 },
+}
+
+impl RangeArgs {
+    /// Checks the `(value, lo, hi)` arguments, `op` names the native in errors
+    fn from_args(heap: &ObjectHeap, op: &'static str, args: &[Value]) -> Result<Self, RuntimeError> {
+        if let Some(arg) = args.iter().find(|arg| arg.as_float().is_none()) {
+            return Err(RuntimeError::TypeError {
+                op,
+                lhs: arg.type_name(heap),
+                rhs: None,
+            });
+        }
+        let [value, lo, hi] = args else {
+            return Err(RuntimeError::UnknownError);
+        };
+        let range = match (value.kind(), lo.kind(), hi.kind()) {
+            (ValueKind::Int(value), ValueKind::Int(lo), ValueKind::Int(hi)) => RangeArgs::Int { value, lo, hi },
+            _ => RangeArgs::Number {
+                value: value.as_float().unwrap_or_default(),
+                lo: lo.as_float().unwrap_or_default(),
+                hi: hi.as_float().unwrap_or_default(),
+            },
+        };
+        let empty = match range {
+            RangeArgs::Int { lo, hi, .. } => lo > hi,
+            RangeArgs::Number { lo, hi, .. } => lo > hi,
+        };
+        if empty {
+            return Err(RuntimeError::InvalidArgument(eco_format!(
+                "{op} expects lo <= hi, got {} and {}",
+                args[1],
+                args[2]
+            )));
+        }
+        Ok(range)
+    }
+}
+
+/// `clamp(value, lo, hi)`, `value` limited to `lo..=hi`
+fn clamp(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(match RangeArgs::from_args(heap, "clamp", args)? {
+        RangeArgs::Int { value, lo, hi } => Value::int(value.clamp(lo, hi)),
+        RangeArgs::Number { value, lo, hi } => Value::number(value.clamp(lo, hi)),
+    })
+}
+
+/// `in_range(value, lo, hi)`, whether `value` is in `lo..=hi`
+fn in_range(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::bool(match RangeArgs::from_args(heap, "in_range", args)? {
+        RangeArgs::Int { value, lo, hi } => (lo..=hi).contains(&value),
+        RangeArgs::Number { value, lo, hi } => (lo..=hi).contains(&value),
+    }))
+}
+
+/// `wrap(value, lo, hi)`, `value` wrapped around into `lo..hi`, so `wrap(370, 0, 360)` is `10`
+fn wrap(heap: &mut ObjectHeap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let range = RangeArgs::from_args(heap, "wrap", args)?;
+    let empty = match range {
+        RangeArgs::Int { lo, hi, .. } => lo == hi,
+        RangeArgs::Number { lo, hi, .. } => lo == hi,
+    };
+    if empty {
+        return Err(RuntimeError::InvalidArgument(eco_format!(
+            "wrap expects lo < hi, got {} and {}",
+            args[1],
+            args[2]
+        )));
+    }
+    Ok(match range {
+        RangeArgs::Int { value, lo, hi } => {
+            // Wide enough that the differences can't overflow
+            let wrapped = (value as i128 - lo as i128).rem_euclid(hi as i128 - lo as i128) + lo as i128;
+            Value::int(wrapped as i64)
+        }
+        RangeArgs::Number { value, lo, hi } => Value::number((value - lo).rem_euclid(hi - lo) + lo),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{run_source, RunError};
+
+    fn run_lines(source: &str) -> Vec<String> {
+        run_source(source).unwrap().lines().map(String::from).collect()
+    }
+
+    fn runtime_error(source: &str) -> String {
+        match run_source(source) {
+            Err(RunError::Runtime { error, .. }) => error.to_string(),
+            res => panic!("expected a runtime error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn coroutine_test() {
+        let source = r#"
+            fn ticks() {
+                let got = yield 1;
+                print "got " + got;
+                yield 2;
+                return "done";
+            }
+            let co = coroutine(ticks);
+            print resume(co, nil);
+            print resume(co, "a");
+            print coroutine_done(co);
+            print resume(co, nil);
+            print coroutine_done(co);
+
+            // Resuming itself from the inside finds it running
+            let inner = nil;
+            fn nested() { return resume(inner, nil); }
+            inner = coroutine(nested);
+            resume(inner, nil);
+        "#;
+        match run_source(source) {
+            Err(RunError::Runtime { error, .. }) => {
+                assert_eq!(error.to_string(), "Can't resume a coroutine that is already running")
+            }
+            res => panic!("expected a runtime error, got {res:?}"),
+        }
+        assert_eq!(
+            run_lines(&source.replace("resume(inner, nil);\n", "")),
+            ["1", "got a", "2", "false", "done", "true"]
+        );
+        assert_eq!(runtime_error("resume(1, nil);"), "Cannot resume Int");
+        assert_eq!(runtime_error("coroutine_done(nil);"), "Cannot check if done Nil");
+    }
+
+    #[test]
+    fn clamp_test() {
+        let source = "
+            print clamp(-5, 0, 10);
+            print clamp(5, 0, 10);
+            print clamp(15, 0, 10);
+            print clamp(1.5, 0, 1);
+        ";
+        assert_eq!(run_lines(source), ["0", "5", "10", "1.0"]);
+        assert_eq!(runtime_error("clamp(1, 10, 0);"), "clamp expects lo <= hi, got 10 and 0");
+        assert_eq!(runtime_error("clamp(\"a\", 0, 1);"), "Cannot clamp String");
+    }
+
+    #[test]
+    fn in_range_test() {
+        let source = "
+            print in_range(0, 0, 10);
+            print in_range(10, 0, 10);
+            print in_range(10.5, 0, 10);
+            print in_range(-1, 0, 10);
+        ";
+        assert_eq!(run_lines(source), ["true", "true", "false", "false"]);
+    }
+
+    #[test]
+    fn wrap_test() {
+        let source = "
+            print wrap(370, 0, 360);
+            print wrap(-90, 0, 360);
+            print wrap(360, 0, 360);
+            print wrap(7.5, 0, 5);
+            print wrap(3, 1, 4);
+        ";
+        assert_eq!(run_lines(source), ["10", "270", "0", "2.5", "3"]);
+        assert_eq!(runtime_error("wrap(1, 2, 2);"), "wrap expects lo < hi, got 2 and 2");
+    }
+
+    #[test]
+    fn method_chain_test() {
+        let source = r#"
+            let list = [];
+            list.push(1).push(2);
+            print list;
+            print [3].push(4).len();
+            print list.pop();
+            print list;
+            print [].pop();
+            print "héllo".len() + {"a": 1}.len();
+        "#;
+        assert_eq!(run_lines(source), ["[1, 2]", "2", "2", "[1]", "nil", "6"]);
+        assert_eq!(runtime_error("[].shove(1);"), "List has no method 'shove'");
+        assert_eq!(runtime_error("let n = 1; n.push(1);"), "Int has no method 'push'");
+        assert_eq!(runtime_error("[].push();"), "Expected 1 arguments but got 0");
+    }
+
+    #[test]
+    fn conversion_test() {
+        let source = r#"
+            print int(3.9) == 3;
+            print int(-3.9);
+            print int(" 42 ") + int("2.5");
+            print float(3) == 3.0;
+            print float(3);
+            print float("0.5") + float(7);
+        "#;
+        assert_eq!(run_lines(source), ["true", "-3", "44", "true", "3.0", "7.5"]);
+        assert_eq!(runtime_error(r#"int("abc");"#), r#"Cannot convert "abc" to Int"#);
+        assert_eq!(runtime_error("int(nil);"), "Cannot convert Nil to Int");
+        assert_eq!(runtime_error("int(1.0 / 0.0);"), "Cannot convert Number to Int");
+        assert_eq!(runtime_error("float([]);"), "Cannot convert List to Number");
+    }
+
+    #[test]
+    fn sort_test() {
+        let source = r#"
+            let numbers = [3, 1.5, -2, 10, 1];
+            sort(numbers);
+            print numbers;
+            print sort(["pear", "apple", "fig"]);
+            print sort([]);
+        "#;
+        assert_eq!(run_lines(source), ["[-2, 1, 1.5, 3, 10]", r#"["apple", "fig", "pear"]"#, "[]"]);
+        assert_eq!(runtime_error(r#"sort([1, "a"]);"#), "Cannot sort Int and String");
+        assert_eq!(runtime_error("sort([true, false]);"), "Cannot sort Bool");
+        assert_eq!(runtime_error(r#"sort("abc");"#), "Cannot sort String");
+    }
+
+    #[test]
+    fn sort_by_test() {
+        let source = r#"
+            fn desc(a, b) { return a > b; }
+            print sort_by([3, 1, 2], desc);
+
+            // Entries with the same score keep their order
+            fn by_score(a, b) { return a[1] < b[1]; }
+            let scores = [["ann", 2], ["bob", 1], ["cid", 2], ["dee", 1]];
+            sort_by(scores, by_score);
+            print scores;
+        "#;
+        assert_eq!(
+            run_lines(source),
+            ["[3, 2, 1]", r#"[["bob", 1], ["dee", 1], ["ann", 2], ["cid", 2]]"#]
+        );
+        assert_eq!(runtime_error("sort_by([1, 2], 3);"), "Cannot call Int");
+        assert_eq!(runtime_error("fn f(a) { return true; } sort_by([1, 2], f);"), "Expected 1 arguments but got 2");
+    }
+
+    #[test]
+    fn string_methods_test() {
+        let source = r#"
+            let name = "Zażółć gęślą";
+            print name.len();
+            print name.substring(2, 6);
+            print name.substring(7, name.len());
+            print name.substring(3, 3) == "";
+            print name.contains("gęś");
+            print name.contains("x");
+            print name.upper();
+            print "ÄBC".lower();
+            print "straße".upper().len();
+        "#;
+        assert_eq!(
+            run_lines(source),
+            ["12", "żółć", "gęślą", "true", "true", "false", "ZAŻÓŁĆ GĘŚLĄ", "äbc", "7"]
+        );
+        assert_eq!(runtime_error(r#""abc".substring(1, 4);"#), "Index 4 is out of bounds for a string of length 3");
+        assert_eq!(runtime_error(r#""abc".substring(-1, 2);"#), "Index -1 is out of bounds for a string of length 3");
+        assert_eq!(runtime_error(r#""abc".substring(2, 1);"#), "substring expects start <= end, got 2 and 1");
+        assert_eq!(runtime_error(r#""abc".substring(0, 1.5);"#), "String indices must be Int, not Number");
+        assert_eq!(runtime_error(r#""abc".contains(1);"#), "Cannot search a String for Int");
+    }
+
+    #[test]
+    fn map_methods_test() {
+        let source = r#"
+            let scores = {"ann": 2, 1: "one"};
+            print scores.has("ann");
+            print scores.has(1.0);
+            print scores.has("bob");
+            print scores.get("bob");
+            print scores.get(1);
+            print scores.remove("ann");
+            print scores.remove("ann");
+            print scores;
+        "#;
+        assert_eq!(run_lines(source), ["true", "true", "false", "nil", "one", "2", "nil", r#"{1: "one"}"#]);
+    }
+
+    #[test]
+    fn bind_test() {
+        let source = r#"
+            fn add(a, b) { return a + b; }
+            let add_ten = bind(add, 10);
+            print add_ten(5);
+            print bind(add_ten, 1)();
+            print add_ten is Function;
+
+            let list = [];
+            let push = list.push;
+            push(1).push(2);
+            print list;
+            print "abc".upper();
+            let upper = "abc".upper;
+            print upper();
+        "#;
+        assert_eq!(run_lines(source), ["15", "11", "true", "[1, 2]", "ABC", "ABC"]);
+        assert_eq!(runtime_error("bind(1, 2);"), "Cannot bind Int");
+        assert_eq!(runtime_error("fn add(a, b) { return a + b; } bind(add, 1)();"), "Expected 2 arguments but got 1");
+        assert_eq!(runtime_error("let f = [].missing;"), "List has no method 'missing'");
+    }
+
+    #[test]
+    fn set_test() {
+        let source = r#"
+            let s = {1, 1, 2, 2.0, "a", "a"};
+            print set_len(s);
+            print s;
+            print set_contains(s, 2) + " " + set_contains(s, "a") + " " + set_contains(s, 3);
+            print set_len(set_add(set_add(s, 3), 3));
+            print set_remove(s, 1) + " " + set_remove(s, 1);
+            print set_contains(s, 1);
+            print len(s);
+        "#;
+        assert_eq!(run_lines(source), ["3", r#"{1, 2, "a"}"#, "true true false", "4", "true false", "false", "3"]);
+        assert_eq!(runtime_error("set_add([], 1);"), "Cannot add an element to List");
+        assert_eq!(runtime_error(r#"set_len({"a": 1});"#), "Cannot take the set length of Map");
+    }
+
+    #[test]
+    fn assert_type_test() {
+        let source = r#"
+            fn speed(value) { return assert_type(value, "Number") * 2; }
+            print speed(1.5);
+            print assert_type("a", "String");
+            print assert_type(nil, "Nil");
+            print assert_type([1], "List").len();
+            print assert_type(speed, "Function") == speed;
+        "#;
+        assert_eq!(run_lines(source), ["3.0", "a", "nil", "1", "true"]);
+        assert_eq!(
+            runtime_error(r#"assert_type(1, "Number");"#),
+            "Expected a value of type Number but got Int"
+        );
+        assert_eq!(
+            runtime_error(r#"assert_type("1", "Map");"#),
+            "Expected a value of type Map but got String"
+        );
+        assert_eq!(
+            runtime_error(r#"assert_type(1, "number");"#),
+            "Unknown type 'number', expected one of Nil, Number, Int, Bool, String, Function, List, Map, Set, Class, Instance, Coroutine"
+        );
+        assert_eq!(runtime_error("assert_type(1, 2);"), "assert_type expects a type name, got Int");
+    }
+
+    #[test]
+    fn sleep_test() {
+        // Allows for the float conversion, the clock itself is monotonic
+        let source = "let start = now(); sleep(10); print now() - start >= 9.9; print sleep(0);";
+        assert_eq!(run_lines(source), ["true", "nil"]);
+        assert_eq!(runtime_error(r#"sleep("1");"#), "Cannot sleep for String");
+        assert_eq!(runtime_error("sleep(-1);"), "sleep expects a non-negative duration, got -1");
+    }
+}
@@ -1,10 +1,15 @@
-use std::{fmt::Display, mem};
+use std::{fmt::Display, mem, rc::Rc};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use ecow::EcoString;
 use slotmap::{new_key_type, SlotMap};
 
-use super::value::Value;
+use super::{
+    chunk::CodeChunk,
+    natives,
+    value::{MapKey, Value},
+    vm::{Coroutine, RuntimeError, VM},
+};
 
 #[derive(Debug)]
 pub struct Object {
@@ -14,6 +19,72 @@ pub struct Object {
 #[derive(Debug)]
 pub enum ObjectKind {
     String(EcoString),
+    Function(Rc<Function>),
+    List(Vec<Value>),
+    Map(AHashMap<MapKey, Value>),
+    /// Elements are keyed like the keys of a map, so `1` and `1.0` are the same element
+    Set(AHashSet<MapKey>),
+    Native(NativeFunction),
+    /// Calls `function` with `receiver` before its own arguments, made by `bind` and method references.
+    ///
+    /// Class methods get the receiver as `this` in slot 0 instead, `is_method` tells them apart.
+    BoundMethod { receiver: Value, function: Value, is_method: bool },
+    Class(Class),
+    Instance(Instance),
+    Coroutine(Coroutine),
+}
+
+/// A compiled function, shared with the call frames executing it
+#[derive(Debug)]
+pub struct Function {
+    pub name: EcoString,
+    pub arity: u8,
+    pub chunk: CodeChunk,
+}
+
+/// Made by a `class` declaration, calling it makes an [`Instance`]
+#[derive(Debug)]
+pub struct Class {
+    pub name: EcoString,
+    /// Keyed by the interned method name
+    pub methods: AHashMap<ObjectKey, Value>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjectKey,
+    /// Keyed by the interned field name
+    pub fields: AHashMap<ObjectKey, Value>,
+}
+
+pub type NativeFn = fn(&mut ObjectHeap, &[Value]) -> Result<Value, RuntimeError>;
+
+/// A native that calls back into the VM, like `sort_by` calling its comparator
+pub type VmNativeFn = fn(&mut VM<'_, '_>, &[Value]) -> Result<Value, RuntimeError>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum NativeBody {
+    Heap(NativeFn),
+    Vm(VmNativeFn),
+}
+
+/// A function implemented in Rust, its arguments are checked against `arity` before it's called
+#[derive(Debug, Clone, Copy)]
+pub struct NativeFunction {
+    pub name: &'static str,
+    pub arity: u8,
+    /// Trailing arguments that can be left out, they are passed as `nil`
+    pub optional: u8,
+    pub function: NativeBody,
+    /// `false` if the result depends on more than the arguments, like the current time
+    pub deterministic: bool,
+}
+
+impl NativeFunction {
+    /// Whether a call with `argc` arguments gives all but the optional ones
+    pub fn accepts(&self, argc: usize) -> bool {
+        (self.arity - self.optional) as usize <= argc && argc <= self.arity as usize
+    }
 }
 
 impl Object {
@@ -25,40 +96,135 @@ impl Object {
 #[derive(Debug, Clone, Copy)]
 pub enum HeapError {
     ObjectNotFound,
-    GlobalVariableNotFound
+    GlobalVariableNotFound,
+    /// An allocation would go over [`ObjectHeap::set_max_bytes`]
+    OutOfMemory,
+}
+
+impl Display for HeapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeapError::ObjectNotFound => write!(f, "Object was already freed"),
+            HeapError::GlobalVariableNotFound => write!(f, "Undefined variable"),
+            HeapError::OutOfMemory => write!(f, "Out of memory"),
+        }
+    }
 }
 
 new_key_type! { pub struct ObjectKey; }
 
+/// Global variables by slot, compiled code reads the ones it knows about without hashing their names
+#[derive(Debug, Default)]
+struct Globals {
+    /// Keyed by the interned name
+    slots: AHashMap<ObjectKey, usize>,
+    /// Name of every slot
+    names: Vec<ObjectKey>,
+    /// `None` until the global is defined, the compiler declares slots before the code defining them runs
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    fn declare(&mut self, name: ObjectKey) -> usize {
+        *self.slots.entry(name).or_insert_with(|| {
+            self.names.push(name);
+            self.values.push(None);
+            self.names.len() - 1
+        })
+    }
+
+    fn get(&self, slot: usize) -> Option<Value> {
+        self.values.get(slot).copied().flatten()
+    }
+
+    /// Only globals that are already defined can be assigned
+    fn set(&mut self, slot: usize, value: Value) -> Result<(), HeapError> {
+        match self.values.get_mut(slot) {
+            Some(global @ Some(_)) => {
+                *global = Some(value);
+                Ok(())
+            }
+            _ => Err(HeapError::GlobalVariableNotFound),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ObjectHeap {
     heap: SlotMap<ObjectKey, Object>,
     interner: AHashMap<EcoString, ObjectKey>,
-    globals: AHashMap<ObjectKey, Value>,
+    globals: Globals,
     dynamic_memory_used: usize,
+    /// Ceiling for `dynamic_memory_used`, only checked by the fallible `try_` allocations
+    max_bytes: Option<usize>,
 }
 
 impl ObjectHeap {
     pub fn new() -> Self {
-        Self {
+        let mut heap = Self {
             heap: SlotMap::with_key(),
             interner: AHashMap::new(),
-            globals: AHashMap::new(),
+            globals: Globals::default(),
             dynamic_memory_used: 0,
-        }
+            max_bytes: None,
+        };
+        natives::register(&mut heap);
+        heap
+    }
+
+    /// Limits the memory running code can allocate, the compiler isn't limited
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
     }
 
     pub fn alloc_object(&mut self, object: Object) -> ObjectKey {
-        Self::inner_heap_alloc(&mut self.heap, &mut self.dynamic_memory_used, object)
+        self.dynamic_memory_used += Self::object_size(&object);
+        self.heap.insert(object)
     }
 
     pub fn intern_string(&mut self, string: EcoString) -> ObjectKey {
         *self.interner.entry(string).or_insert_with_key(|s| {
             let obj = Object::new(ObjectKind::String(s.clone()));
-            Self::inner_heap_alloc(&mut self.heap, &mut self.dynamic_memory_used, obj)
+            self.dynamic_memory_used += Self::object_size(&obj);
+            self.heap.insert(obj)
         })
     }
 
+    /// Like [`ObjectHeap::alloc_object`], but fails instead of going over the memory limit
+    pub fn try_alloc_object(&mut self, object: Object) -> Result<ObjectKey, HeapError> {
+        self.reserve(Self::object_size(&object))?;
+        Ok(self.alloc_object(object))
+    }
+
+    /// Like [`ObjectHeap::intern_string`], but fails instead of going over the memory limit
+    pub fn try_intern_string(&mut self, string: EcoString) -> Result<ObjectKey, HeapError> {
+        if let Some(key) = self.interner.get(&string) {
+            return Ok(*key);
+        }
+        self.reserve(string.len())?;
+        Ok(self.intern_string(string))
+    }
+
+    /// Accounts for `bytes` more held by an existing object, like a list that grew
+    pub fn grow(&mut self, bytes: usize) -> Result<(), HeapError> {
+        self.reserve(bytes)?;
+        self.dynamic_memory_used += bytes;
+        Ok(())
+    }
+
+    /// Accounts for `bytes` less held by an existing object, like a set that lost an element
+    pub fn shrink(&mut self, bytes: usize) {
+        self.dynamic_memory_used = self.dynamic_memory_used.saturating_sub(bytes);
+    }
+
+    /// Checks that `bytes` more still fit under the limit, there is no collector to free anything first
+    pub fn reserve(&self, bytes: usize) -> Result<(), HeapError> {
+        match self.max_bytes {
+            Some(max) if self.dynamic_memory_used.saturating_add(bytes) > max => Err(HeapError::OutOfMemory),
+            _ => Ok(()),
+        }
+    }
+
     pub fn put_as_global(&mut self, identifier: ObjectKey, object: Value) {
         assert!(matches!(
             self.heap.get(identifier),
@@ -67,14 +233,55 @@ impl ObjectHeap {
                 ..
             })
         ));
-        self.globals.insert(identifier, object);
+        let slot = self.globals.declare(identifier);
+        self.globals.values[slot] = Some(object);
     }
 
     pub fn get_global(&self, identifier: ObjectKey) -> Result<Value, HeapError> {
+        let slot = self.global_slot(identifier).ok_or(HeapError::GlobalVariableNotFound)?;
+        self.get_global_at(slot)
+    }
+
+    /// Assigns a global that is already defined
+    pub fn set_global(&mut self, identifier: ObjectKey, value: Value) -> Result<(), HeapError> {
+        let slot = self.global_slot(identifier).ok_or(HeapError::GlobalVariableNotFound)?;
+        self.globals.set(slot, value)
+    }
+
+    /// Gives the global `identifier` a slot without defining it, returns the existing slot if it already has one
+    pub fn declare_global(&mut self, identifier: ObjectKey) -> usize {
+        self.globals.declare(identifier)
+    }
+
+    pub fn global_slot(&self, identifier: ObjectKey) -> Option<usize> {
+        self.globals.slots.get(&identifier).copied()
+    }
+
+    /// Name of the global in `slot`
+    pub fn global_name(&self, slot: usize) -> Option<ObjectKey> {
+        self.globals.names.get(slot).copied()
+    }
+
+    /// Names of every declared global, in slot order
+    pub fn global_names(&self) -> &[ObjectKey] {
+        &self.globals.names
+    }
+
+    pub fn get_global_at(&self, slot: usize) -> Result<Value, HeapError> {
+        self.globals.get(slot).ok_or(HeapError::GlobalVariableNotFound)
+    }
+
+    pub fn set_global_at(&mut self, slot: usize, value: Value) -> Result<(), HeapError> {
+        self.globals.set(slot, value)
+    }
+
+    /// Every defined global with the key of its name
+    pub fn globals(&self) -> impl Iterator<Item = (ObjectKey, Value)> + '_ {
         self.globals
-            .get(&identifier)
-            .copied()
-            .ok_or(HeapError::GlobalVariableNotFound)
+            .names
+            .iter()
+            .zip(&self.globals.values)
+            .filter_map(|(name, value)| Some((*name, (*value)?)))
     }
 
     pub fn get_object(&self, key: ObjectKey) -> Result<&Object, HeapError> {
@@ -83,6 +290,12 @@ impl ObjectHeap {
             .ok_or(HeapError::ObjectNotFound)
     }
 
+    pub fn get_object_mut(&mut self, key: ObjectKey) -> Result<&mut Object, HeapError> {
+        self.heap
+            .get_mut(key)
+            .ok_or(HeapError::ObjectNotFound)
+    }
+
     pub fn live_count(&self) -> usize {
         self.heap.len()
     }
@@ -91,15 +304,19 @@ impl ObjectHeap {
         self.dynamic_memory_used
     }
 
-    fn inner_heap_alloc(
-        inner_heap: &mut SlotMap<ObjectKey, Object>,
-        mem_counter: &mut usize,
-        obj: Object,
-    ) -> ObjectKey {
+    fn object_size(obj: &Object) -> usize {
         match &obj.kind {
-            ObjectKind::String(s) => *mem_counter += mem::size_of_val(s.as_bytes()),
+            ObjectKind::String(s) => mem::size_of_val(s.as_bytes()),
+            ObjectKind::Function(function) => function.chunk.size(),
+            ObjectKind::List(list) => mem::size_of_val(list.as_slice()),
+            ObjectKind::Map(map) => map.len() * mem::size_of::<(MapKey, Value)>(),
+            ObjectKind::Set(set) => set.len() * mem::size_of::<MapKey>(),
+            ObjectKind::Native(_) => 0,
+            ObjectKind::BoundMethod { .. } => 2 * mem::size_of::<Value>(),
+            ObjectKind::Class(class) => class.methods.len() * mem::size_of::<(ObjectKey, Value)>(),
+            ObjectKind::Instance(instance) => instance.fields.len() * mem::size_of::<(ObjectKey, Value)>(),
+            ObjectKind::Coroutine(coroutine) => coroutine.stack_len() * mem::size_of::<Value>(),
         }
-        inner_heap.insert(obj)
     }
 }
 
@@ -107,6 +324,15 @@ impl Display for ObjectKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ObjectKind::String(string) => write!(f, "{}", string),
+            ObjectKind::Function(function) => write!(f, "<fn {}>", function.name),
+            ObjectKind::List(list) => write!(f, "<list of {}>", list.len()),
+            ObjectKind::Map(map) => write!(f, "<map of {}>", map.len()),
+            ObjectKind::Set(set) => write!(f, "<set of {}>", set.len()),
+            ObjectKind::Native(native) => write!(f, "<native fn {}>", native.name),
+            ObjectKind::BoundMethod { .. } => write!(f, "<bound fn>"),
+            ObjectKind::Class(class) => write!(f, "<class {}>", class.name),
+            ObjectKind::Instance(_) => write!(f, "<instance>"),
+            ObjectKind::Coroutine(_) => write!(f, "<coroutine>"),
         }
     }
 }
@@ -23,4 +23,35 @@ pub const OP_SET_GLOBAL : u8 = 20;
 pub const OP_GET_LOCAL : u8 = 21;
 pub const OP_SET_LOCAL : u8 = 22;
 pub const OP_JUMP : u8 = 23;
-pub const OP_JUMP_F : u8 = 24;
\ No newline at end of file
+pub const OP_JUMP_F : u8 = 24;
+pub const OP_CALL : u8 = 25;
+pub const OP_REM : u8 = 26;
+pub const OP_CONCAT_N : u8 = 27;
+pub const OP_JUMP_NIL : u8 = 28;
+pub const OP_BUILD_LIST : u8 = 29;
+pub const OP_INDEX_GET : u8 = 30;
+pub const OP_INDEX_SET : u8 = 31;
+pub const OP_YIELD : u8 = 32;
+pub const OP_BUILD_MAP : u8 = 33;
+pub const OP_POW : u8 = 34;
+pub const OP_INVOKE : u8 = 35;
+pub const OP_IS : u8 = 36;
+pub const OP_ARRAY_REPEAT : u8 = 37;
+pub const OP_INC_LOCAL : u8 = 38;
+pub const OP_NOT_EQUAL : u8 = 39;
+pub const OP_POPN : u8 = 40;
+pub const OP_GET_FIELD : u8 = 41;
+pub const OP_SET_FIELD : u8 = 42;
+pub const OP_ASSERT : u8 = 43;
+pub const OP_CLASS : u8 = 44;
+pub const OP_METHOD : u8 = 45;
+pub const OP_WRITE : u8 = 46;
+pub const OP_BUILD_SET : u8 = 47;
+/// Like [`OP_GET_LOCAL`] and [`OP_SET_LOCAL`], with a two byte slot for functions with more than 256 locals
+pub const OP_GET_LOCAL_LONG : u8 = 48;
+pub const OP_SET_LOCAL_LONG : u8 = 49;
+/// Like [`OP_GET_GLOBAL`] and [`OP_SET_GLOBAL`], with the two byte slot the compiler gave the global instead of its name
+pub const OP_GET_GLOBAL_SLOT : u8 = 50;
+pub const OP_SET_GLOBAL_SLOT : u8 = 51;
+/// `value is Class` for a class that isn't one of the built in types, the class is on the stack above the value
+pub const OP_IS_CLASS : u8 = 52;
\ No newline at end of file
@@ -0,0 +1,511 @@
+//! Optional passes that rewrite a compiled [`CodeChunk`] into shorter code doing the same thing
+
+use std::ops::Range;
+
+use super::{chunk::CodeChunk, object::ObjectHeap, opcodes::*, value::Value};
+
+/// Runs all of the passes until none of them finds anything left to do
+pub fn optimize(chunk: &mut CodeChunk) {
+    loop {
+        let size = chunk.size();
+        thread_jumps(chunk);
+        peephole(chunk);
+        fold_constants(chunk);
+        if chunk.size() == size {
+            return;
+        }
+    }
+}
+
+/// Replaces arithmetic on two numeric constants, like `2 + 3 * 4`, with a single constant of the result.
+///
+/// Operations that would fail or give NaN or an infinity are left for the VM to report,
+/// so are operands that a jump lands between. Constants that are no longer used are dropped.
+pub fn fold_constants(chunk: &mut CodeChunk) {
+    let Some((instructions, jump_targets)) = decode(chunk) else {
+        return;
+    };
+
+    // Only used for `Value::add`, which doesn't touch the heap for numbers
+    let mut heap = ObjectHeap::new();
+    let is_target = |instruction: &Instruction| jump_targets.contains(&instruction.offset);
+    let mut folded: Vec<Instruction> = Vec::with_capacity(instructions.len());
+    for instruction in instructions {
+        if let [.., a, b] = folded.as_slice() {
+            if a.bytes[0] == OP_CONSTANT && b.bytes[0] == OP_CONSTANT && !is_target(b) && !is_target(&instruction) {
+                let a_value = chunk.constants[a.bytes[1] as usize];
+                let b_value = chunk.constants[b.bytes[1] as usize];
+                let result = fold(instruction.bytes[0], a_value, b_value, &mut heap);
+                if let Some(constant) = result.and_then(|value| chunk.push_constant(value)) {
+                    folded.pop();
+                    let a = folded.last_mut().expect("two instructions were matched");
+                    a.bytes[1] = constant;
+                    a.span = a.span.start..instruction.span.end;
+                    continue;
+                }
+            }
+        }
+        folded.push(instruction);
+    }
+
+    rebuild(chunk, folded);
+}
+
+/// Makes jumps that land on a `JUMP`, like the end of an inner `else` in nested `if`s,
+/// go straight to where the chain of `JUMP`s ends.
+///
+/// Only the jump offsets change, so the code keeps its size and spans.
+pub fn thread_jumps(chunk: &mut CodeChunk) {
+    let Some((instructions, _)) = decode(chunk) else {
+        return;
+    };
+    let at = |offset: usize| {
+        instructions
+            .binary_search_by_key(&offset, |instruction| instruction.offset)
+            .ok()
+            .map(|idx| &instructions[idx])
+    };
+    for instruction in &instructions {
+        if !matches!(instruction.bytes[0], OP_JUMP | OP_JUMP_F | OP_JUMP_NIL) {
+            continue;
+        }
+        let mut target = jump_target(instruction);
+        // A chain can't be longer than the code, unless it's an endless loop
+        for _ in 0..instructions.len() {
+            match at(target) {
+                Some(next) if next.bytes[0] == OP_JUMP && jump_target(next) != target => target = jump_target(next),
+                _ => break,
+            }
+        }
+        let from = instruction.offset + instruction.bytes.len();
+        if let Ok(jump) = i16::try_from(target as isize - from as isize) {
+            chunk.code[instruction.offset + 1..from].copy_from_slice(&jump.to_be_bytes());
+        }
+    }
+}
+
+/// Rewrites short sequences of instructions, until there is nothing left to rewrite:
+/// - jumps to the next instruction are removed, they only peek at the condition
+/// - `EQUAL NOT` becomes `NOT EQUAL`, and back
+/// - `NOT NOT` is removed after an instruction that leaves a bool, like in `!(a < b)`
+/// - `TRUE JUMPF` never jumps and `FALSE JUMPF` always does, the bool stays on the stack either way
+/// - consecutive `POP`s become a single `POPN`
+/// - `CONSTANT NEG` becomes a constant of the negated number
+///
+/// Instructions a jump lands on are only rewritten together with the ones before them.
+pub fn peephole(chunk: &mut CodeChunk) {
+    // Only used for `Value::neg`, which doesn't touch the heap
+    let mut heap = ObjectHeap::new();
+    while let Some((instructions, jump_targets)) = decode(chunk) {
+        let before = chunk.size();
+        let is_target = |instruction: &Instruction| jump_targets.contains(&instruction.offset);
+        let mut optimized: Vec<Instruction> = Vec::with_capacity(instructions.len());
+        for mut instruction in instructions {
+            let op = instruction.bytes[0];
+            if matches!(op, OP_JUMP | OP_JUMP_F | OP_JUMP_NIL) && jump_target(&instruction) == instruction.offset + 3 {
+                continue;
+            }
+            if is_target(&instruction) {
+                optimized.push(instruction);
+                continue;
+            }
+            match (optimized.as_mut_slice(), op) {
+                ([.., producer, not], OP_NOT) if not.bytes[0] == OP_NOT && !is_target(not) && leaves_bool(producer) => {
+                    optimized.pop();
+                }
+                ([.., equal], OP_NOT) if equal.bytes[0] == OP_EQUAL => equal.bytes[0] = OP_NOT_EQUAL,
+                ([.., not_equal], OP_NOT) if not_equal.bytes[0] == OP_NOT_EQUAL => not_equal.bytes[0] = OP_EQUAL,
+                ([.., condition], OP_JUMP_F) if condition.bytes[0] == OP_TRUE => {}
+                ([.., condition], OP_JUMP_F) if condition.bytes[0] == OP_FALSE => {
+                    instruction.bytes[0] = OP_JUMP;
+                    optimized.push(instruction);
+                }
+                ([.., pop], OP_POP) if pop.bytes[0] == OP_POP => pop.bytes = vec![OP_POPN, 2],
+                ([.., pop], OP_POP) if pop.bytes[0] == OP_POPN && pop.bytes[1] < u8::MAX => pop.bytes[1] += 1,
+                ([.., constant], OP_NEG) if constant.bytes[0] == OP_CONSTANT => {
+                    let value = chunk.constants[constant.bytes[1] as usize];
+                    let negated = value.as_float().and_then(|_| value.neg(&mut heap).ok());
+                    match negated.and_then(|value| chunk.push_constant(value)) {
+                        Some(negated) => {
+                            constant.bytes[1] = negated;
+                            constant.span = instruction.span.start..constant.span.end;
+                        }
+                        None => optimized.push(instruction),
+                    }
+                }
+                _ => optimized.push(instruction),
+            }
+        }
+        rebuild(chunk, optimized);
+        if chunk.size() == before {
+            return;
+        }
+    }
+}
+
+/// Size of the instruction `op` with its operands, `None` for unknown opcodes
+pub(super) fn instruction_len(op: u8) -> Option<usize> {
+    let len = match op {
+        OP_JUMP | OP_JUMP_F | OP_JUMP_NIL | OP_INVOKE | OP_INC_LOCAL | OP_GET_LOCAL_LONG | OP_SET_LOCAL_LONG
+        | OP_GET_GLOBAL_SLOT | OP_SET_GLOBAL_SLOT => 3,
+        OP_CONSTANT | OP_DEF_GLOBAL | OP_GET_GLOBAL | OP_SET_GLOBAL | OP_GET_LOCAL | OP_SET_LOCAL | OP_CALL
+        | OP_CONCAT_N | OP_BUILD_LIST | OP_BUILD_MAP | OP_BUILD_SET | OP_IS | OP_POPN | OP_GET_FIELD | OP_SET_FIELD
+        | OP_ASSERT | OP_CLASS | OP_METHOD => 2,
+        OP_RETURN | OP_NEG | OP_ADD | OP_SUB | OP_MUL | OP_DIV | OP_REM | OP_POW | OP_NOT | OP_AND | OP_OR
+        | OP_EQUAL | OP_NOT_EQUAL | OP_GREATER | OP_LESS | OP_TRUE | OP_FALSE | OP_NIL | OP_PRINT | OP_WRITE | OP_POP
+        | OP_INDEX_GET | OP_INDEX_SET | OP_YIELD | OP_ARRAY_REPEAT | OP_IS_CLASS => 1,
+        _ => return None,
+    };
+    Some(len)
+}
+
+/// An instruction being rewritten, `offset` is where it started before the rewrite
+struct Instruction {
+    offset: usize,
+    bytes: Vec<u8>,
+    span: Range<usize>,
+}
+
+fn leaves_bool(instruction: &Instruction) -> bool {
+    matches!(
+        instruction.bytes[0],
+        OP_EQUAL | OP_NOT_EQUAL | OP_GREATER | OP_LESS | OP_NOT | OP_TRUE | OP_FALSE | OP_IS | OP_IS_CLASS
+    )
+}
+
+/// Offset the jump `instruction` lands on
+fn jump_target(instruction: &Instruction) -> usize {
+    let jump = i16::from_be_bytes([instruction.bytes[1], instruction.bytes[2]]);
+    (instruction.offset as isize + instruction.bytes.len() as isize + jump as isize) as usize
+}
+
+/// Splits the code into instructions and collects the offsets jumps land on.
+///
+/// `None` for code with unknown opcodes, which isn't something the compiler made and is better left alone.
+fn decode(chunk: &CodeChunk) -> Option<(Vec<Instruction>, Vec<usize>)> {
+    let mut instructions = Vec::new();
+    let mut jump_targets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = chunk.code[offset];
+        let len = instruction_len(op).filter(|len| offset + len <= chunk.code.len())?;
+        let instruction = Instruction {
+            offset,
+            bytes: chunk.code[offset..offset + len].to_vec(),
+            span: chunk.find_span_of(offset).1.clone(),
+        };
+        if let OP_JUMP | OP_JUMP_F | OP_JUMP_NIL = op {
+            jump_targets.push(jump_target(&instruction));
+        }
+        instructions.push(instruction);
+        offset += len;
+    }
+    Some((instructions, jump_targets))
+}
+
+/// Result of the binary instruction `op` on two numbers, if it can be computed ahead of time
+fn fold(op: u8, a: Value, b: Value, heap: &mut ObjectHeap) -> Option<Value> {
+    if a.as_float().is_none() || b.as_float().is_none() {
+        return None;
+    }
+    let result = match op {
+        OP_ADD => a.add(&b, heap),
+        OP_SUB => a.sub(&b, heap),
+        OP_MUL => a.mul(&b, heap),
+        OP_DIV => a.div(&b, heap),
+        OP_REM => a.rem(&b, heap),
+        OP_POW => a.pow(&b, heap),
+        _ => return None,
+    };
+    // Strict math would turn these into errors at runtime
+    result.ok().filter(|value| value.as_float().is_some_and(f64::is_finite))
+}
+
+/// Replaces the code of `chunk` with `instructions`, fixing up jumps and dropping unused constants.
+///
+/// Jumps to a removed instruction land on the next one that was kept.
+fn rebuild(chunk: &mut CodeChunk, mut instructions: Vec<Instruction>) {
+    let mut new_offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in &instructions {
+        new_offsets.push((instruction.offset, offset));
+        offset += instruction.bytes.len();
+    }
+    // Jumps can land right after the last instruction
+    new_offsets.push((usize::MAX, offset));
+    let new_offset = |old: usize| new_offsets[new_offsets.partition_point(|(offset, _)| *offset < old)].1;
+
+    let mut constants = Vec::new();
+    let mut constant_indices = vec![None; chunk.constants.len()];
+    for instruction in &mut instructions {
+        let op = instruction.bytes[0];
+        match op {
+            OP_JUMP | OP_JUMP_F | OP_JUMP_NIL => {
+                let from = new_offset(instruction.offset) + instruction.bytes.len();
+                let to = new_offset(jump_target(instruction));
+                let jump = (to as isize - from as isize) as i16;
+                instruction.bytes[1..].copy_from_slice(&jump.to_be_bytes());
+            }
+            OP_CONSTANT | OP_DEF_GLOBAL | OP_GET_GLOBAL | OP_SET_GLOBAL | OP_IS | OP_INVOKE | OP_INC_LOCAL
+            | OP_GET_FIELD | OP_SET_FIELD | OP_CLASS | OP_METHOD => {
+                // The local slot comes before the constant
+                let operand = if op == OP_INC_LOCAL { 2 } else { 1 };
+                let old = instruction.bytes[operand] as usize;
+                let new = *constant_indices[old].get_or_insert_with(|| {
+                    constants.push(chunk.constants[old]);
+                    (constants.len() - 1) as u8
+                });
+                instruction.bytes[operand] = new;
+            }
+            _ => {}
+        }
+    }
+
+    chunk.code.clear();
+    chunk.constants = constants;
+    chunk.span_info = vec![(0, 0..0)];
+    for instruction in instructions {
+        chunk.push_span_info(instruction.span);
+        chunk.code.extend(instruction.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bytecode::vm::VM, compiler::parser::Parser};
+
+    use super::*;
+
+    fn compile(source: &str, heap: &mut ObjectHeap) -> CodeChunk {
+        let mut code = CodeChunk::new();
+        Parser::parse_source(source, &mut code, heap).unwrap();
+        code
+    }
+
+    /// Runs `chunk` and returns what it printed
+    fn run_printed(chunk: &CodeChunk, heap: &mut ObjectHeap) -> String {
+        let mut out = Vec::new();
+        VM::init_with_output(chunk, heap, &mut out).run().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Disassembly without offsets, spans and colors
+    fn instructions(chunk: &CodeChunk, heap: &ObjectHeap) -> Vec<String> {
+        let dump = chunk.dissasemble().with_heap(heap).with_color(false).to_string();
+        dump.lines().map(|line| line.split_whitespace().skip(2).collect::<Vec<_>>().join(" ")).collect()
+    }
+
+    /// Checks the instructions of `source` after `pass`, and that it still prints the same
+    fn assert_optimized(source: &str, pass: fn(&mut CodeChunk), expected: &[&str]) -> (CodeChunk, CodeChunk) {
+        let mut heap = ObjectHeap::new();
+        let code = compile(source, &mut heap);
+        let mut optimized = code.clone();
+        pass(&mut optimized);
+
+        assert_eq!(instructions(&optimized, &heap), expected);
+        assert_eq!(run_printed(&optimized, &mut heap), run_printed(&code, &mut heap));
+        assert!(optimized.size() < code.size());
+        (code, optimized)
+    }
+
+    /// A chunk with hand written code, the parser already folds literals in the source
+    fn chunk_of(constants: &[Value], code: Vec<u8>) -> CodeChunk {
+        let mut chunk = CodeChunk::new();
+        for value in constants {
+            chunk.push_constant(*value).unwrap();
+        }
+        chunk.code = code;
+        chunk
+    }
+
+    #[test]
+    fn fold_constants_test() {
+        // print 2 + 3 * 4; print 1.0 / 0.0;
+        let constants = [Value::int(2), Value::int(3), Value::int(4), Value::number(1.0), Value::number(0.0)];
+        let code = vec![
+            OP_CONSTANT, 0, OP_CONSTANT, 1, OP_CONSTANT, 2, OP_MUL, OP_ADD, OP_PRINT,
+            OP_CONSTANT, 3, OP_CONSTANT, 4, OP_DIV, OP_PRINT, OP_RETURN,
+        ];
+        let chunk = chunk_of(&constants, code);
+        let mut folded = chunk.clone();
+        fold_constants(&mut folded);
+
+        let mut heap = ObjectHeap::new();
+        assert_eq!(run_printed(&folded, &mut heap), run_printed(&chunk, &mut heap));
+        // The operands that were folded away are dropped from the pool, infinity is left for the VM
+        assert_eq!(instructions(&folded, &heap), [
+            "CONSTANT 0 '14'",
+            "PRINT",
+            "CONSTANT 1 '1.0'",
+            "CONSTANT 2 '0.0'",
+            "DIV",
+            "PRINT",
+            "RETURN",
+        ]);
+    }
+
+    #[test]
+    fn fold_constants_jump_target_test() {
+        // 1 + 2, jumping over the 100 onto the second operand
+        let constants = [Value::int(1), Value::int(100), Value::int(2)];
+        let code = vec![OP_CONSTANT, 0, OP_JUMP, 0, 2, OP_CONSTANT, 1, OP_CONSTANT, 2, OP_ADD, OP_PRINT, OP_RETURN];
+        let chunk = chunk_of(&constants, code);
+        let mut folded = chunk.clone();
+        fold_constants(&mut folded);
+
+        let mut heap = ObjectHeap::new();
+        assert_eq!(run_printed(&folded, &mut heap), "3\n");
+        assert_eq!(folded.size(), chunk.size());
+    }
+
+    #[test]
+    fn dead_jump_test() {
+        let mut chunk = CodeChunk::new();
+        chunk.push_constant(Value::int(1)).unwrap();
+        chunk.push_constant(Value::int(2)).unwrap();
+        chunk.code = vec![OP_CONSTANT, 0, OP_JUMP_F, 0, 4, OP_JUMP, 0, 0, OP_POP, OP_CONSTANT, 1, OP_PRINT, OP_RETURN];
+        chunk.span_info = vec![(0, 0..1), (5, 5..6), (8, 8..9)];
+        let mut optimized = chunk.clone();
+        peephole(&mut optimized);
+
+        let mut heap = ObjectHeap::new();
+        assert_eq!(run_printed(&optimized, &mut heap), run_printed(&chunk, &mut heap));
+        assert_eq!(optimized.size(), chunk.size() - 3);
+        assert_eq!(instructions(&optimized, &heap)[1], "JUMPF 1 -> 0006");
+        // The POP keeps its own span after moving
+        assert_eq!(optimized.find_span_of(5), &(5, 8..9));
+    }
+
+    #[test]
+    fn not_equal_test() {
+        let source = "let x = 1; print x != 2; print !(x != 1); print !!x;";
+        // `x` takes the first slot after the natives
+        let get_x = format!("GET GLOBAL SLOT {} var 'x'", ObjectHeap::new().global_names().len());
+        assert_optimized(source, peephole, &[
+            "CONSTANT 0 '1'",
+            "DEF GLOBAL 1 var 'x'",
+            &get_x,
+            "CONSTANT 2 '2'",
+            "NOT EQUAL",
+            "PRINT",
+            &get_x,
+            "CONSTANT 3 '1'",
+            "EQUAL",
+            "PRINT",
+            // Turns `x` into a bool, so it stays
+            &get_x,
+            "NOT",
+            "NOT",
+            "PRINT",
+            "RETURN",
+        ]);
+    }
+
+    #[test]
+    fn constant_condition_test() {
+        let source = "while false { print 1; } if true { print 2; }";
+        assert_optimized(source, peephole, &[
+            "FALSE",
+            "JUMP 7 -> 0011",
+            "POP",
+            "CONSTANT 0 '1'",
+            "PRINT",
+            "JUMP -11 -> 0000",
+            "POP",
+            "TRUE",
+            "POP",
+            "CONSTANT 1 '2'",
+            "PRINT",
+            "JUMP 1 -> 0021",
+            "POP",
+            "RETURN",
+        ]);
+    }
+
+    #[test]
+    fn popn_test() {
+        let source = "{ let a = 1; let b = 2; let c = -3; print a + b + c; }";
+        let (_, optimized) = assert_optimized(source, peephole, &[
+            "CONSTANT 0 '1'",
+            "CONSTANT 1 '2'",
+            "CONSTANT 2 '-3'",
+            "GET LOCAL 0",
+            "GET LOCAL 1",
+            "ADD",
+            "GET LOCAL 2",
+            "ADD",
+            "PRINT",
+            "POPN 3",
+            "RETURN",
+        ]);
+        // The negated constant covers both the `-` and the number
+        let source_span = optimized.find_span_of(4).1.clone();
+        assert_eq!(&source[source_span], "-3");
+    }
+
+    /// Runs `chunk` one instruction at a time, returns what it printed and how many jumps it took
+    fn run_counting_jumps(chunk: &CodeChunk, heap: &mut ObjectHeap) -> (String, usize) {
+        let mut out = Vec::new();
+        let mut vm = VM::init_with_output(chunk, heap, &mut out);
+        let mut jumps = 0;
+        loop {
+            if vm.chunk().get_byte(vm.pc()) == Some(OP_JUMP) {
+                jumps += 1;
+            }
+            if let crate::bytecode::vm::RuntimeStep::Halt = vm.step().unwrap() {
+                break;
+            }
+        }
+        drop(vm);
+        (String::from_utf8(out).unwrap(), jumps)
+    }
+
+    #[test]
+    fn thread_jumps_test() {
+        let source = "
+            let mut i = 0;
+            while i < 3 {
+                if i != 1 { if i == 0 { print 0; } else { print 2; } } else { print 1; }
+                i += 1;
+            }
+        ";
+        let mut heap = ObjectHeap::new();
+        let code = compile(source, &mut heap);
+        let mut threaded = code.clone();
+        thread_jumps(&mut threaded);
+
+        // The end of the inner `if` jumps straight past the outer `else`
+        let dump = instructions(&threaded, &heap);
+        assert!(dump.contains(&"JUMP 11 -> 0052".to_string()), "{dump:#?}");
+        assert!(!dump.contains(&"JUMP 4 -> 0045".to_string()), "{dump:#?}");
+        assert_eq!(threaded.size(), code.size());
+        assert_eq!(threaded.span_info, code.span_info);
+
+        let (printed, jumps) = run_counting_jumps(&code, &mut heap);
+        let (threaded_printed, threaded_jumps) = run_counting_jumps(&threaded, &mut heap);
+        assert_eq!(printed, "0\n1\n2\n");
+        assert_eq!(threaded_printed, printed);
+        // The `print 0` branch saves one
+        assert_eq!(threaded_jumps, jumps - 1);
+    }
+
+    #[test]
+    fn endless_jump_test() {
+        // A loop of two jumps, threading has to stop
+        let chunk = chunk_of(&[], vec![OP_JUMP, 0, 0, OP_JUMP, 255, 250, OP_RETURN]);
+        let mut threaded = chunk.clone();
+        thread_jumps(&mut threaded);
+        assert_eq!(threaded.code.len(), chunk.code.len());
+    }
+
+    #[test]
+    fn optimize_test() {
+        // print -2 * 3 + 1;
+        let constants = [Value::int(2), Value::int(3), Value::int(1)];
+        let code = vec![OP_CONSTANT, 0, OP_NEG, OP_CONSTANT, 1, OP_MUL, OP_CONSTANT, 2, OP_ADD, OP_PRINT, OP_RETURN];
+        let mut chunk = chunk_of(&constants, code);
+        optimize(&mut chunk);
+
+        assert_eq!(instructions(&chunk, &ObjectHeap::new()), ["CONSTANT 0 '-5'", "PRINT", "RETURN"]);
+    }
+}
@@ -0,0 +1,429 @@
+use std::fmt::Display;
+use std::rc::Rc;
+
+use ecow::EcoString;
+
+use super::chunk::CodeChunk;
+use super::object::{Function, Object, ObjectHeap, ObjectKind};
+use super::opcodes::*;
+use super::optimize::instruction_len;
+use super::value::{Value, ValueKind};
+
+/// Every serialized chunk starts with these bytes
+pub const MAGIC: &[u8; 4] = b"GLBC";
+/// Bumped whenever the encoding changes
+pub const VERSION: u8 = 3;
+
+const TAG_NIL: u8 = 0;
+const TAG_NUMBER: u8 = 1;
+const TAG_INT: u8 = 2;
+const TAG_BOOL: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    UnknownConstantTag(u8),
+    InvalidString,
+    /// The heap already gave the global another slot than the one the code was compiled with
+    GlobalSlotMismatch,
+    UnknownOpcode(u8),
+    /// An instruction refers to a constant past the end of its chunk's pool
+    ConstantOutOfRange(u8),
+    /// An instruction refers to a global slot the file never declared
+    GlobalSlotOutOfRange(u16),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "Not a compiled program"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "Compiled with bytecode version {version}, this build reads version {VERSION}")
+            }
+            DecodeError::UnexpectedEnd => write!(f, "The file ends too early"),
+            DecodeError::UnknownConstantTag(tag) => write!(f, "Unknown constant tag {tag}"),
+            DecodeError::InvalidString => write!(f, "A string constant isn't valid UTF-8"),
+            DecodeError::GlobalSlotMismatch => write!(f, "The globals don't match the slots the code was compiled with"),
+            DecodeError::UnknownOpcode(op) => write!(f, "Unknown opcode {op}"),
+            DecodeError::ConstantOutOfRange(constant) => write!(f, "Constant {constant} is past the end of the constants"),
+            DecodeError::GlobalSlotOutOfRange(slot) => write!(f, "Global slot {slot} was never declared"),
+        }
+    }
+}
+
+// ===== Public interface
+impl CodeChunk {
+    /// Encodes the chunk into bytes, flattening the heap objects its constants refer to
+    pub fn serialize(&self, heap: &ObjectHeap) -> Vec<u8> {
+        let mut encoder = Encoder { bytes: Vec::new() };
+        encoder.bytes.extend_from_slice(MAGIC);
+        encoder.bytes.push(VERSION);
+        encoder.chunk(self, heap);
+        encoder.globals(heap);
+        encoder.bytes
+    }
+
+    /// Decodes a chunk produced by [`CodeChunk::serialize`], allocating its objects in `heap`.
+    ///
+    /// The globals the code was compiled with are declared in `heap` in the same slots, a fresh heap always has room for them.
+    pub fn deserialize(bytes: &[u8], heap: &mut ObjectHeap) -> Result<CodeChunk, DecodeError> {
+        let mut decoder = Decoder { bytes, pos: 0, max_global_slot: None };
+        if decoder.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = decoder.u8()?;
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let chunk = decoder.chunk(heap)?;
+        decoder.globals(heap)?;
+        if let Some(slot) = decoder.max_global_slot {
+            if slot as usize >= heap.global_names().len() {
+                return Err(DecodeError::GlobalSlotOutOfRange(slot));
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u32(&mut self, value: usize) {
+        let value = u32::try_from(value).expect("Chunk too big to serialize");
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, string: &str) {
+        self.u32(string.len());
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn chunk(&mut self, chunk: &CodeChunk, heap: &ObjectHeap) {
+        self.u32(chunk.code.len());
+        self.bytes.extend_from_slice(&chunk.code);
+
+        self.u32(chunk.constants.len());
+        for constant in &chunk.constants {
+            self.constant(constant, heap);
+        }
+
+        self.u32(chunk.span_info.len());
+        for (offset, span) in &chunk.span_info {
+            self.u32(*offset);
+            self.u32(span.start);
+            self.u32(span.end);
+        }
+    }
+
+    /// Names of the global slots, compiled code can refer to any of them
+    fn globals(&mut self, heap: &ObjectHeap) {
+        self.u32(heap.global_names().len());
+        for name in heap.global_names() {
+            let name = heap.get_object(*name).expect("Internal panic: Global name refers to a missing object");
+            let ObjectKind::String(name) = &name.kind else {
+                panic!("Internal panic: Global names are strings");
+            };
+            self.string(name);
+        }
+    }
+
+    fn constant(&mut self, constant: &Value, heap: &ObjectHeap) {
+        match constant.kind() {
+            ValueKind::Nil => self.bytes.push(TAG_NIL),
+            ValueKind::Number(num) => {
+                self.bytes.push(TAG_NUMBER);
+                self.bytes.extend_from_slice(&num.to_le_bytes());
+            }
+            ValueKind::Int(num) => {
+                self.bytes.push(TAG_INT);
+                self.bytes.extend_from_slice(&num.to_le_bytes());
+            }
+            ValueKind::Bool(val) => {
+                self.bytes.push(TAG_BOOL);
+                self.bytes.push(val as u8);
+            }
+            ValueKind::Object(key) => match &heap
+                .get_object(key)
+                .expect("Internal panic: Constant refers to a missing object")
+                .kind
+            {
+                ObjectKind::String(string) => {
+                    self.bytes.push(TAG_STRING);
+                    self.string(string);
+                }
+                ObjectKind::Function(function) => {
+                    self.bytes.push(TAG_FUNCTION);
+                    self.string(&function.name);
+                    self.bytes.push(function.arity);
+                    self.chunk(&function.chunk, heap);
+                }
+                ObjectKind::List(_) | ObjectKind::Map(_) | ObjectKind::Set(_) | ObjectKind::Class(_) | ObjectKind::Instance(_)
+                | ObjectKind::Coroutine(_) => {
+                    panic!("Internal panic: Collections and classes are built at runtime, never constants")
+                }
+                ObjectKind::Native(_) | ObjectKind::BoundMethod { .. } => panic!("Internal panic: Natives are globals, never constants"),
+            },
+        }
+    }
+}
+
+struct Decoder<'bytes> {
+    bytes: &'bytes [u8],
+    pos: usize,
+    /// Highest global slot any chunk refers to, checked once the globals are declared
+    max_global_slot: Option<u16>,
+}
+
+impl<'bytes> Decoder<'bytes> {
+    fn take(&mut self, len: usize) -> Result<&'bytes [u8], DecodeError> {
+        let end = self.pos.checked_add(len).ok_or(DecodeError::UnexpectedEnd)?;
+        let slice = self.bytes.get(self.pos..end).ok_or(DecodeError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.take(N)?.try_into().expect("Slice has the requested length"))
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, DecodeError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn string(&mut self) -> Result<EcoString, DecodeError> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        let string = std::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidString)?;
+        Ok(EcoString::from(string))
+    }
+
+    fn chunk(&mut self, heap: &mut ObjectHeap) -> Result<CodeChunk, DecodeError> {
+        let mut chunk = CodeChunk::new();
+
+        let code_len = self.u32()?;
+        chunk.code = self.take(code_len)?.to_vec();
+
+        let constants_len = self.u32()?;
+        for _ in 0..constants_len {
+            let constant = self.constant(heap)?;
+            chunk.constants.push(constant);
+        }
+
+        let span_info_len = self.u32()?;
+        chunk.span_info.clear();
+        for _ in 0..span_info_len {
+            let offset = self.u32()?;
+            let start = self.u32()?;
+            let end = self.u32()?;
+            chunk.span_info.push((offset, start..end));
+        }
+        self.operands(&chunk)?;
+        Ok(chunk)
+    }
+
+    /// Checks that every instruction is whole and its constant operands are in the chunk's pool
+    fn operands(&mut self, chunk: &CodeChunk) -> Result<(), DecodeError> {
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let op = chunk.code[offset];
+            let len = instruction_len(op).ok_or(DecodeError::UnknownOpcode(op))?;
+            let operands = chunk.code.get(offset + 1..offset + len).ok_or(DecodeError::UnexpectedEnd)?;
+            let constant = match op {
+                OP_CONSTANT | OP_DEF_GLOBAL | OP_GET_GLOBAL | OP_SET_GLOBAL | OP_IS | OP_CLASS | OP_METHOD
+                | OP_GET_FIELD | OP_SET_FIELD | OP_INVOKE => Some(operands[0]),
+                OP_INC_LOCAL => Some(operands[1]),
+                OP_GET_GLOBAL_SLOT | OP_SET_GLOBAL_SLOT => {
+                    let slot = u16::from_be_bytes([operands[0], operands[1]]);
+                    self.max_global_slot = self.max_global_slot.max(Some(slot));
+                    None
+                }
+                _ => None,
+            };
+            if let Some(constant) = constant {
+                if constant as usize >= chunk.constants.len() {
+                    return Err(DecodeError::ConstantOutOfRange(constant));
+                }
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn globals(&mut self, heap: &mut ObjectHeap) -> Result<(), DecodeError> {
+        let globals_len = self.u32()?;
+        for slot in 0..globals_len {
+            let name = heap.intern_string(self.string()?);
+            if heap.declare_global(name) != slot {
+                return Err(DecodeError::GlobalSlotMismatch);
+            }
+        }
+        Ok(())
+    }
+
+    fn constant(&mut self, heap: &mut ObjectHeap) -> Result<Value, DecodeError> {
+        let value = match self.u8()? {
+            TAG_NIL => Value::NIL,
+            TAG_NUMBER => Value::number(f64::from_le_bytes(self.array()?)),
+            TAG_INT => Value::int(i64::from_le_bytes(self.array()?)),
+            TAG_BOOL => Value::bool(self.u8()? != 0),
+            TAG_STRING => Value::object(heap.intern_string(self.string()?)),
+            TAG_FUNCTION => {
+                let name = self.string()?;
+                let arity = self.u8()?;
+                let chunk = self.chunk(heap)?;
+                let function = Function { name, arity, chunk };
+                Value::object(heap.alloc_object(Object::new(ObjectKind::Function(Rc::new(function)))))
+            }
+            tag => return Err(DecodeError::UnknownConstantTag(tag)),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bytecode::vm::VM, compiler::parser::Parser};
+
+    use super::*;
+
+    const SOURCE: &str = r#"
+        fn greet(name) { return "hello " + name; }
+        let greeting = greet("world");
+        let total = 0;
+        while total < 10 { total = total + 2.5; }
+    "#;
+
+    fn global(heap: &mut ObjectHeap, name: &str) -> String {
+        let key = heap.intern_string(EcoString::from(name));
+        heap.get_global(key).unwrap().print_with_heap(heap).to_string()
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        Parser::parse_source(SOURCE, &mut code, &mut heap).unwrap();
+        let bytes = code.serialize(&heap);
+
+        let mut loaded_heap = ObjectHeap::new();
+        let loaded = CodeChunk::deserialize(&bytes, &mut loaded_heap).unwrap();
+        assert_eq!(
+            code.dissasemble().with_heap(&heap).to_string(),
+            loaded.dissasemble().with_heap(&loaded_heap).to_string()
+        );
+
+        VM::init(&code, &mut heap).run().unwrap();
+        VM::init(&loaded, &mut loaded_heap).run().unwrap();
+        for name in ["greeting", "total"] {
+            assert_eq!(global(&mut heap, name), global(&mut loaded_heap, name));
+        }
+        assert_eq!(global(&mut loaded_heap, "greeting"), "hello world");
+    }
+
+    #[test]
+    fn optimized_round_trip_test() {
+        // Only the top level is optimized, `count` keeps the INC LOCAL the compiler gave it
+        let source = r#"
+            fn count(n) { let i = 0; while i != n { i += 1; } return i; }
+            { let a = "x" + 2 * 3; let b = -1.5; print a; print !(b == 1.5); print count(3); }
+        "#;
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        crate::bytecode::optimize::optimize(&mut code);
+        // Folded constants, NOT EQUAL from `!(==)` and a POPN for the block's locals
+        let dump = code.dissasemble().with_heap(&heap).to_string();
+        for instruction in [r#"str "x6""#, "NOT EQUAL", "POPN"] {
+            assert!(dump.contains(instruction), "{instruction} in {dump}");
+        }
+
+        let mut loaded_heap = ObjectHeap::new();
+        let loaded = CodeChunk::deserialize(&code.serialize(&heap), &mut loaded_heap).unwrap();
+        let mut printed = Vec::new();
+        VM::init_with_output(&loaded, &mut loaded_heap, &mut printed).run().unwrap();
+        assert_eq!(String::from_utf8(printed).unwrap(), "x6\ntrue\n3\n");
+    }
+
+    #[test]
+    fn rejects_bad_input_test() {
+        let mut heap = ObjectHeap::new();
+        let mut code = CodeChunk::new();
+        Parser::parse_source(SOURCE, &mut code, &mut heap).unwrap();
+        let bytes = code.serialize(&heap);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(
+            CodeChunk::deserialize(&bad_magic, &mut heap).unwrap_err(),
+            DecodeError::BadMagic
+        );
+
+        let mut bad_version = bytes.clone();
+        bad_version[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            CodeChunk::deserialize(&bad_version, &mut heap).unwrap_err(),
+            DecodeError::UnsupportedVersion(VERSION + 1)
+        );
+
+        assert_eq!(
+            CodeChunk::deserialize(&bytes[..bytes.len() - 1], &mut heap).unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+
+        // `other` takes the slot `greet` was compiled with
+        let mut used_heap = ObjectHeap::new();
+        let other = used_heap.intern_string(EcoString::from("other"));
+        used_heap.declare_global(other);
+        assert_eq!(
+            CodeChunk::deserialize(&bytes, &mut used_heap).unwrap_err(),
+            DecodeError::GlobalSlotMismatch
+        );
+    }
+
+    /// Bytes of a file holding just `code` and `constants`, with no spans or globals
+    fn encode(code: &[u8], constants: &[Value], heap: &ObjectHeap) -> Vec<u8> {
+        let mut chunk = CodeChunk::new();
+        for &byte in code {
+            chunk.push_code(byte);
+        }
+        for &constant in constants {
+            chunk.push_constant(constant);
+        }
+        chunk.serialize(heap)
+    }
+
+    #[test]
+    fn rejects_bad_operands_test() {
+        let heap = ObjectHeap::new();
+        let mut loaded_heap = ObjectHeap::new();
+        let globals = heap.global_names().len() as u16;
+
+        let cases = [
+            (encode(&[OP_CONSTANT, 1, OP_RETURN], &[Value::int(1)], &heap), DecodeError::ConstantOutOfRange(1)),
+            (encode(&[OP_INC_LOCAL, 0, 0, OP_RETURN], &[], &heap), DecodeError::ConstantOutOfRange(0)),
+            (encode(&[OP_JUMP, 0], &[], &heap), DecodeError::UnexpectedEnd),
+            (encode(&[0xff], &[], &heap), DecodeError::UnknownOpcode(0xff)),
+        ];
+        for (bytes, err) in cases {
+            assert_eq!(CodeChunk::deserialize(&bytes, &mut loaded_heap).unwrap_err(), err);
+        }
+
+        let [high, low] = globals.to_be_bytes();
+        let bytes = encode(&[OP_GET_GLOBAL_SLOT, high, low, OP_RETURN], &[], &heap);
+        assert_eq!(
+            CodeChunk::deserialize(&bytes, &mut ObjectHeap::new()).unwrap_err(),
+            DecodeError::GlobalSlotOutOfRange(globals)
+        );
+    }
+}
@@ -1,116 +1,508 @@
+use std::cmp::Ordering;
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 
-use ecow::eco_format;
+use ecow::{eco_format, EcoString};
+use slotmap::Key;
 
 use super::object::{HeapError, ObjectHeap, ObjectKey, ObjectKind};
 
 #[derive(Debug, Clone, Copy)]
 pub enum ValueError {
     UnSupportedOperation,
+    DivisionByZero,
     HeapError(HeapError),
 }
 
+/// Every name [`Value::type_name`] gives to a live value, `is` and `assert_type` check these without looking up a class
+pub const TYPE_NAMES: &[&str] = &["Nil", "Number", "Int", "Bool", "String", "Function", "List", "Map", "Set", "Class", "Instance", "Coroutine"];
+
+/// What a [`Value`] holds, unpacked so it can be matched on
 #[derive(Debug, Clone, Copy)]
-pub enum Value {
+pub enum ValueKind {
     Nil,
     Number(f64),
+    Int(i64),
     Bool(bool),
     Object(ObjectKey),
 }
 
+/// A value of the language.
+///
+/// By default it's a plain [`ValueKind`], with the `nan-boxing` feature it's packed into a single `u64`.
+/// Either way it's only built with the constructors and read with [`Value::kind`] and the `as_*` accessors.
+#[derive(Clone, Copy)]
+pub struct Value(repr::Repr);
+
+#[cfg(not(feature = "nan-boxing"))]
+mod repr {
+    use super::{ObjectKey, Value, ValueKind};
+
+    pub type Repr = ValueKind;
+
+    impl Value {
+        pub const NIL: Value = Value(ValueKind::Nil);
+
+        #[inline]
+        pub fn number(num: f64) -> Value {
+            Value(ValueKind::Number(num))
+        }
+        #[inline]
+        pub fn int(num: i64) -> Value {
+            Value(ValueKind::Int(num))
+        }
+        #[inline]
+        pub fn bool(val: bool) -> Value {
+            Value(ValueKind::Bool(val))
+        }
+        #[inline]
+        pub fn object(key: ObjectKey) -> Value {
+            Value(ValueKind::Object(key))
+        }
+        #[inline]
+        pub fn kind(&self) -> ValueKind {
+            self.0
+        }
+    }
+}
+
+/// Numbers are stored as their own bits, with every NaN made the same quiet NaN.
+/// Everything else is a NaN that no number can be: the quiet bits of [`QNAN`] are set, the two bits under them
+/// are the tag and the low 48 bits the payload.
+#[cfg(feature = "nan-boxing")]
+mod repr {
+    use std::sync::{OnceLock, RwLock};
+
+    use ahash::AHashMap;
+    use slotmap::{Key, KeyData};
+
+    use super::{ObjectKey, Value, ValueKind};
+
+    pub type Repr = u64;
+
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+    const TAG_SHIFT: u32 = 48;
+    const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+
+    /// `nil`, `false` and `true`, told apart by the payload
+    const TAG_SINGLETON: u64 = 0;
+    const TAG_INT: u64 = 1;
+    /// Index of the slot in the low 32 bits, its version in the 16 above
+    const TAG_OBJECT: u64 = 2;
+    /// An `Int` that doesn't fit in the payload, the payload is its index in [`BigInts`]
+    const TAG_BIG_INT: u64 = 3;
+
+    /// `Int`s stored in the payload itself, the 48 bits sign extended
+    const SMALL_INT_MIN: i64 = -(1 << 47);
+    const SMALL_INT_MAX: i64 = (1 << 47) - 1;
+
+    const NIL: u64 = 0;
+    const FALSE: u64 = 1;
+    const TRUE: u64 = 2;
+
+    const fn boxed(tag: u64, payload: u64) -> Value {
+        Value(QNAN | tag << TAG_SHIFT | payload)
+    }
+
+    /// `Int`s too large for a payload, kept for the rest of the process like objects are.
+    /// Every distinct value is stored once, so repeating a computation doesn't grow the table.
+    #[derive(Default)]
+    struct BigInts {
+        values: Vec<i64>,
+        indices: AHashMap<i64, u64>,
+    }
+
+    fn big_ints() -> &'static RwLock<BigInts> {
+        static BIG_INTS: OnceLock<RwLock<BigInts>> = OnceLock::new();
+        BIG_INTS.get_or_init(Default::default)
+    }
+
+    #[cold]
+    fn spill_int(num: i64) -> u64 {
+        if let Some(idx) = big_ints().read().unwrap().indices.get(&num) {
+            return *idx;
+        }
+        let mut big_ints = big_ints().write().unwrap();
+        let idx = big_ints.values.len() as u64;
+        let idx = *big_ints.indices.entry(num).or_insert(idx);
+        if idx == big_ints.values.len() as u64 {
+            big_ints.values.push(num);
+        }
+        idx
+    }
+
+    #[cold]
+    fn big_int(idx: u64) -> i64 {
+        big_ints().read().unwrap().values[idx as usize]
+    }
+
+    impl Value {
+        pub const NIL: Value = boxed(TAG_SINGLETON, NIL);
+
+        #[inline]
+        pub fn number(num: f64) -> Value {
+            match num.is_nan() {
+                true => Value(CANONICAL_NAN),
+                false => Value(num.to_bits()),
+            }
+        }
+        #[inline]
+        pub fn int(num: i64) -> Value {
+            match (SMALL_INT_MIN..=SMALL_INT_MAX).contains(&num) {
+                true => boxed(TAG_INT, num as u64 & PAYLOAD_MASK),
+                false => boxed(TAG_BIG_INT, spill_int(num)),
+            }
+        }
+        #[inline]
+        pub fn bool(val: bool) -> Value {
+            boxed(TAG_SINGLETON, if val { TRUE } else { FALSE })
+        }
+        #[inline]
+        pub fn object(key: ObjectKey) -> Value {
+            let ffi = key.data().as_ffi();
+            let (idx, version) = (ffi & 0xffff_ffff, ffi >> 32);
+            // Objects are never freed, so their slots are never reused and versions stay small
+            assert!(version <= 0xffff, "Object version doesn't fit in a boxed value");
+            boxed(TAG_OBJECT, version << 32 | idx)
+        }
+        #[inline]
+        pub fn kind(&self) -> ValueKind {
+            let bits = self.0;
+            if bits & QNAN != QNAN {
+                return ValueKind::Number(f64::from_bits(bits));
+            }
+            let payload = bits & PAYLOAD_MASK;
+            match (bits >> TAG_SHIFT) & 0b11 {
+                TAG_INT => ValueKind::Int(((payload << 16) as i64) >> 16),
+                TAG_OBJECT => ValueKind::Object(KeyData::from_ffi(payload).into()),
+                TAG_BIG_INT => ValueKind::Int(big_int(payload)),
+                _ => match payload {
+                    NIL => ValueKind::Nil,
+                    FALSE => ValueKind::Bool(false),
+                    _ => ValueKind::Bool(true),
+                },
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.kind().fmt(f)
+    }
+}
+
 impl Value {
+    pub const INT_MIN: i64 = i64::MIN;
+    pub const INT_MAX: i64 = i64::MAX;
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self.kind() {
+            ValueKind::Number(num) => Some(num),
+            _ => None,
+        }
+    }
+    pub fn as_int(&self) -> Option<i64> {
+        match self.kind() {
+            ValueKind::Int(num) => Some(num),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.kind() {
+            ValueKind::Bool(val) => Some(val),
+            _ => None,
+        }
+    }
+    pub fn as_object(&self) -> Option<ObjectKey> {
+        match self.kind() {
+            ValueKind::Object(key) => Some(key),
+            _ => None,
+        }
+    }
+    /// Truthiness for conditions and `!`, the only falsey values are `nil` and `false`
     pub fn is_falsey(&self) -> bool {
-        matches!(self, Value::Nil | Value::Bool(false))
+        matches!(self.kind(), ValueKind::Nil | ValueKind::Bool(false))
+    }
+    /// Unlike [`Value::is_falsey`], `false` isn't nil
+    pub fn is_nil(&self) -> bool {
+        matches!(self.kind(), ValueKind::Nil)
+    }
+    /// Name of the value's type as shown in error messages, one of [`TYPE_NAMES`]
+    pub fn type_name(&self, heap: &ObjectHeap) -> &'static str {
+        match self.kind() {
+            ValueKind::Nil => "Nil",
+            ValueKind::Number(_) => "Number",
+            ValueKind::Int(_) => "Int",
+            ValueKind::Bool(_) => "Bool",
+            ValueKind::Object(key) => match heap.get_object(key).map(|object| &object.kind) {
+                Ok(ObjectKind::String(_)) => "String",
+                Ok(ObjectKind::Function(_) | ObjectKind::Native(_) | ObjectKind::BoundMethod { .. }) => "Function",
+                Ok(ObjectKind::List(_)) => "List",
+                Ok(ObjectKind::Map(_)) => "Map",
+                Ok(ObjectKind::Set(_)) => "Set",
+                Ok(ObjectKind::Class(_)) => "Class",
+                Ok(ObjectKind::Instance(_)) => "Instance",
+                Ok(ObjectKind::Coroutine(_)) => "Coroutine",
+                Err(_) => "Object",
+            },
+        }
+    }
+    /// The string this value refers to, if it is one
+    pub fn as_string<'heap>(&self, heap: &'heap ObjectHeap) -> Option<&'heap EcoString> {
+        let ValueKind::Object(key) = self.kind() else {
+            return None;
+        };
+        match &heap.get_object(key).ok()?.kind {
+            ObjectKind::String(string) => Some(string),
+            _ => None,
+        }
+    }
+    /// Text of the value when it's joined with a string, only strings and plain values can be joined
+    fn concat_operand(&self, heap: &ObjectHeap) -> Option<EcoString> {
+        match self.kind() {
+            ValueKind::Object(_) => self.as_string(heap).cloned(),
+            _ => Some(eco_format!("{self}")),
+        }
+    }
+    /// Whether `num` is between [`Value::INT_MIN`] and [`Value::INT_MAX`], so it can be an `Int` once truncated
+    pub fn in_int_range(num: f64) -> bool {
+        // `INT_MIN` is a power of two, so it and its negation are exact floats
+        (Value::INT_MIN as f64..-(Value::INT_MIN as f64)).contains(&num)
+    }
+    /// Numeric value of both `Int` and `Number`, used to promote mixed arithmetic to floats
+    pub fn as_float(&self) -> Option<f64> {
+        match self.kind() {
+            ValueKind::Number(num) => Some(num),
+            ValueKind::Int(num) => Some(num as f64),
+            _ => None,
+        }
     }
     pub fn neg(&self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match self {
-            Value::Number(a) => Value::Number(-a),
+        let res = match self.kind() {
+            ValueKind::Number(a) => Value::number(-a),
+            ValueKind::Int(a) => a.checked_neg().map_or(Value::number(-(a as f64)), Value::int),
             _ => return Err(ValueError::UnSupportedOperation),
         };
         Ok(res)
     }
     pub fn add(&self, other: &Self, heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
-            (Value::Object(a), Value::Object(b)) => {
-                match (&heap.get_object(*a)?.kind, &heap.get_object(*b)?.kind) {
-                    (ObjectKind::String(a), ObjectKind::String(b)) => {
-                        let joined_string = eco_format!("{}{}", a, b);
-                        let key = heap.intern_string(joined_string);
-                        Value::Object(key)
-                    },
-                }
+        if self.as_string(heap).is_none() && other.as_string(heap).is_none() {
+            return self.arithmetic(other, |a, b| Ok(a.checked_add(b)), |a, b| a + b);
+        }
+        // A string on either side turns numbers, bools and nil into their printed form
+        match (self.concat_operand(heap), other.concat_operand(heap)) {
+            (Some(a), Some(b)) => {
+                let key = heap.try_intern_string(eco_format!("{}{}", a, b))?;
+                Ok(Value::object(key))
             }
-            _ => return Err(ValueError::UnSupportedOperation),
-        };
-        Ok(res)
+            _ => Err(ValueError::UnSupportedOperation),
+        }
+    }
+    /// Joins all `values` into a single new string, non-string operands are converted like in [`Value::add`]
+    pub fn concat(values: &[Value], heap: &mut ObjectHeap) -> Result<Value, ValueError> {
+        let mut strings = Vec::with_capacity(values.len());
+        for value in values {
+            strings.push(value.concat_operand(heap).ok_or(ValueError::UnSupportedOperation)?);
+        }
+        let mut joined_string = EcoString::with_capacity(strings.iter().map(|s| s.len()).sum());
+        for string in strings {
+            joined_string.push_str(&string);
+        }
+        Ok(Value::object(heap.try_intern_string(joined_string)?))
     }
     pub fn sub(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
-            _ => return Err(ValueError::UnSupportedOperation),
-        };
-        Ok(res)
+        self.arithmetic(other, |a, b| Ok(a.checked_sub(b)), |a, b| a - b)
     }
     pub fn mul(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
-            _ => return Err(ValueError::UnSupportedOperation),
-        };
-        Ok(res)
+        self.arithmetic(other, |a, b| Ok(a.checked_mul(b)), |a, b| a * b)
     }
+    /// Integer division truncates toward zero, dividing an integer by zero is an error
     pub fn div(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
-            _ => return Err(ValueError::UnSupportedOperation),
-        };
-        Ok(res)
+        self.arithmetic(
+            other,
+            |a, b| match b {
+                0 => Err(ValueError::DivisionByZero),
+                b => Ok(a.checked_div(b)),
+            },
+            |a, b| a / b,
+        )
+    }
+    /// Remainder of the truncating division, so it takes the sign of the dividend
+    pub fn rem(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
+        self.arithmetic(
+            other,
+            |a, b| match b {
+                0 => Err(ValueError::DivisionByZero),
+                b => Ok(a.checked_rem(b)),
+            },
+            |a, b| a % b,
+        )
+    }
+    /// `Int`s stay `Int`s for exponents that fit in a `u32`, negative exponents or results that overflow give a `Number`
+    pub fn pow(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
+        match (self.kind(), other.kind()) {
+            (ValueKind::Int(a), ValueKind::Int(b)) if u32::try_from(b).is_ok() => match a.checked_pow(b as u32) {
+                Some(res) => Ok(Value::int(res)),
+                None => Ok(Value::number((a as f64).powf(b as f64))),
+            },
+            _ => match (self.as_float(), other.as_float()) {
+                (Some(a), Some(b)) => Ok(Value::number(a.powf(b))),
+                _ => Err(ValueError::UnSupportedOperation),
+            },
+        }
+    }
+    /// Applies `int_op` when both operands are `Int`, otherwise promotes both to floats.
+    /// When `int_op` overflows, the `Int`s are promoted to floats as well.
+    fn arithmetic(
+        &self,
+        other: &Self,
+        int_op: impl FnOnce(i64, i64) -> Result<Option<i64>, ValueError>,
+        float_op: impl FnOnce(f64, f64) -> f64,
+    ) -> Result<Value, ValueError> {
+        match (self.kind(), other.kind()) {
+            (ValueKind::Int(a), ValueKind::Int(b)) => match int_op(a, b)? {
+                Some(res) => Ok(Value::int(res)),
+                None => Ok(Value::number(float_op(a as f64, b as f64))),
+            },
+            _ => match (self.as_float(), other.as_float()) {
+                (Some(a), Some(b)) => Ok(Value::number(float_op(a, b))),
+                _ => Err(ValueError::UnSupportedOperation),
+            },
+        }
     }
+    /// Logical negation based on truthiness: `!x` is `true` exactly when `x` is falsey.
+    ///
+    /// Only `nil` and `false` are falsey, so `!0` and `!"x"` are both `false`.
     pub fn not(&self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match self {
-            Value::Bool(val) => !val,
-            Value::Nil => true,
-            _ => return Err(ValueError::UnSupportedOperation),
-        };
-        Ok(Value::Bool(res))
+        Ok(Value::bool(self.is_falsey()))
     }
     pub fn and(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Bool(a), Value::Bool(b)) => *a && *b,
+        let res = match (self.kind(), other.kind()) {
+            (ValueKind::Bool(a), ValueKind::Bool(b)) => a && b,
             _ => return Err(ValueError::UnSupportedOperation),
         };
-        Ok(Value::Bool(res))
+        Ok(Value::bool(res))
     }
     pub fn or(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Bool(a), Value::Bool(b)) => *a || *b,
+        let res = match (self.kind(), other.kind()) {
+            (ValueKind::Bool(a), ValueKind::Bool(b)) => a || b,
             _ => return Err(ValueError::UnSupportedOperation),
         };
-        Ok(Value::Bool(res))
-    }
-    pub fn equal(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Number(a), Value::Number(b)) => a == b,
-            (Value::Bool(a), Value::Bool(b)) => a == b,
-            (Value::Nil, Value::Nil) => true,
-            (Value::Object(a), Value::Object(b)) => a == b,
+        Ok(Value::bool(res))
+    }
+    /// Strings are equal by content, other objects only when they are the same object
+    pub fn equal(&self, other: &Self, heap: &mut ObjectHeap) -> Result<Value, ValueError> {
+        let res = match (self.kind(), other.kind()) {
+            (ValueKind::Int(a), ValueKind::Int(b)) => a == b,
+            // IEEE comparison, so NaN isn't equal to anything, not even itself
+            (ValueKind::Number(_) | ValueKind::Int(_), ValueKind::Number(_) | ValueKind::Int(_)) => {
+                matches!((self.as_float(), other.as_float()), (Some(a), Some(b)) if a == b)
+            }
+            (ValueKind::Bool(a), ValueKind::Bool(b)) => a == b,
+            (ValueKind::Nil, ValueKind::Nil) => true,
+            (ValueKind::Object(a), ValueKind::Object(b)) => {
+                match (self.as_string(heap), other.as_string(heap)) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => a == b,
+                }
+            }
             _ => return Err(ValueError::UnSupportedOperation),
         };
-        Ok(Value::Bool(res))
+        Ok(Value::bool(res))
     }
-    pub fn greater(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Number(a), Value::Number(b)) => a > b,
-            _ => return Err(ValueError::UnSupportedOperation),
+    /// Hashes the value consistently with [`Value::equal`], so values that are `==` hash the same.
+    ///
+    /// Numbers are hashed by the bits of their float value, which makes `1` and `1.0` or `0.0` and `-0.0` collide.
+    /// Strings are hashed by content, other objects by identity.
+    pub fn hash_with_heap<H: Hasher>(&self, heap: &ObjectHeap, state: &mut H) {
+        match self.kind() {
+            ValueKind::Nil => 0u8.hash(state),
+            ValueKind::Bool(val) => {
+                1u8.hash(state);
+                val.hash(state);
+            }
+            ValueKind::Int(_) | ValueKind::Number(_) => {
+                2u8.hash(state);
+                let num = self.as_float().unwrap_or_default();
+                // `-0.0 == 0.0` and every NaN is unequal to everything, so any bits do for them
+                let num = if num == 0.0 { 0.0 } else { num };
+                num.to_bits().hash(state);
+            }
+            ValueKind::Object(key) => match self.as_string(heap) {
+                Some(string) => {
+                    3u8.hash(state);
+                    string.hash(state);
+                }
+                None => {
+                    4u8.hash(state);
+                    key.hash(state);
+                }
+            },
+        }
+    }
+    pub fn greater(&self, other: &Self, heap: &mut ObjectHeap) -> Result<Value, ValueError> {
+        let res = match (self.kind(), other.kind()) {
+            (ValueKind::Int(a), ValueKind::Int(b)) => a > b,
+            _ => match (self.as_float(), other.as_float()) {
+                (Some(a), Some(b)) => a > b,
+                _ => match (self.as_string(heap), other.as_string(heap)) {
+                    (Some(a), Some(b)) => a > b,
+                    _ => return Err(ValueError::UnSupportedOperation),
+                },
+            },
         };
-        Ok(Value::Bool(res))
+        Ok(Value::bool(res))
     }
-    pub fn less(&self, other: &Self, _heap: &mut ObjectHeap) -> Result<Value, ValueError> {
-        let res = match (self, other) {
-            (Value::Number(a), Value::Number(b)) => a < b,
-            _ => return Err(ValueError::UnSupportedOperation),
+    /// Numbers are ordered by value and strings lexicographically
+    pub fn less(&self, other: &Self, heap: &mut ObjectHeap) -> Result<Value, ValueError> {
+        let res = match (self.kind(), other.kind()) {
+            (ValueKind::Int(a), ValueKind::Int(b)) => a < b,
+            _ => match (self.as_float(), other.as_float()) {
+                (Some(a), Some(b)) => a < b,
+                _ => match (self.as_string(heap), other.as_string(heap)) {
+                    (Some(a), Some(b)) => a < b,
+                    _ => return Err(ValueError::UnSupportedOperation),
+                },
+            },
+        };
+        Ok(Value::bool(res))
+    }
+    /// Order of map keys and set elements when they're printed: `nil`, bools, numbers by value,
+    /// strings by content and then other objects in the order they were made
+    pub fn print_order(&self, other: &Self, heap: &ObjectHeap) -> Ordering {
+        let rank = |value: &Value| match value.kind() {
+            ValueKind::Nil => 0,
+            ValueKind::Bool(_) => 1,
+            ValueKind::Int(_) | ValueKind::Number(_) => 2,
+            ValueKind::Object(_) if value.as_string(heap).is_some() => 3,
+            ValueKind::Object(_) => 4,
         };
-        Ok(Value::Bool(res))
+        match (self.kind(), other.kind()) {
+            (ValueKind::Bool(a), ValueKind::Bool(b)) => a.cmp(&b),
+            (ValueKind::Int(a), ValueKind::Int(b)) => a.cmp(&b),
+            (ValueKind::Int(_) | ValueKind::Number(_), ValueKind::Int(_) | ValueKind::Number(_)) => {
+                let (a, b) = (self.as_float().unwrap_or_default(), other.as_float().unwrap_or_default());
+                a.total_cmp(&b)
+            }
+            (ValueKind::Object(a), ValueKind::Object(b)) => match (self.as_string(heap), other.as_string(heap)) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (None, None) => a.cmp(&b),
+                _ => rank(self).cmp(&rank(other)),
+            },
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+}
+
+impl Display for ValueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueError::UnSupportedOperation => write!(f, "Operation not supported for these types"),
+            ValueError::DivisionByZero => write!(f, "Division by zero"),
+            ValueError::HeapError(err) => write!(f, "{err}"),
+        }
     }
 }
 
@@ -122,19 +514,43 @@ impl From<HeapError> for ValueError {
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Nil => write!(f, "nil"),
-            Value::Number(num) => write!(f, "{num}"),
-            Value::Bool(val) => write!(f, "{val}"),
-            Value::Object(id) => write!(f, "Object${id:?}"),
+        match self.kind() {
+            ValueKind::Nil => write!(f, "nil"),
+            ValueKind::Number(num) => write!(f, "{}", NumberDisplay(num)),
+            ValueKind::Int(num) => write!(f, "{num}"),
+            ValueKind::Bool(val) => write!(f, "{val}"),
+            // Without the heap only the slot can be shown, the version is left out as noise
+            ValueKind::Object(id) => write!(f, "<obj#{}>", id.data().as_ffi() as u32),
+        }
+    }
+}
+
+/// The one way numbers are shown, by `print`, `str` and the disassembler.
+///
+/// The shortest representation that reads back as the same float. Integral floats keep their `.0`,
+/// so they can be told apart from `Int`s, very large and small ones use an exponent, like `1e21`.
+#[derive(Debug, Clone, Copy)]
+pub struct NumberDisplay(pub f64);
+
+impl Display for NumberDisplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            num if num.is_nan() => write!(f, "nan"),
+            // Debug is already shortest-roundtrip and prints infinities as `inf` and `-inf`
+            num => write!(f, "{num:?}"),
         }
     }
 }
 
+/// Collections nested deeper than this are elided, which also stops collections containing themselves
+const MAX_PRINT_DEPTH: usize = 8;
+
 #[derive(Debug)]
 pub struct ValueHeapDisplay<'value, 'heap> {
     value: &'value Value,
     heap: &'heap ObjectHeap,
+    depth: usize,
+    quoted: bool,
 }
 
 impl Value {
@@ -142,21 +558,358 @@ impl Value {
         &'value self,
         heap: &'heap ObjectHeap,
     ) -> ValueHeapDisplay<'value, 'heap> {
-        ValueHeapDisplay { value: self, heap }
+        ValueHeapDisplay { value: self, heap, depth: 0, quoted: false }
+    }
+}
+
+impl<'value, 'heap> ValueHeapDisplay<'value, 'heap> {
+    /// Puts strings in quotes, like they are shown inside collections
+    pub fn quoted(mut self) -> Self {
+        self.quoted = true;
+        self
+    }
+
+    fn nested<'inner>(&self, value: &'inner Value) -> ValueHeapDisplay<'inner, 'heap> {
+        ValueHeapDisplay { value, heap: self.heap, depth: self.depth + 1, quoted: true }
     }
 }
 
 impl<'value, 'heap> Display for ValueHeapDisplay<'value, 'heap> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.value {
-            Value::Object(key) => {
+        match self.value.kind() {
+            ValueKind::Object(key) => {
                 let obj = self
                     .heap
-                    .get_object(*key);
-                obj.unwrap().fmt(f)
+                    .get_object(key);
+                match &obj.unwrap().kind {
+                    ObjectKind::String(string) if self.quoted => write!(f, "{string:?}"),
+                    ObjectKind::List(_) if self.depth >= MAX_PRINT_DEPTH => write!(f, "[...]"),
+                    ObjectKind::Map(_) | ObjectKind::Set(_) if self.depth >= MAX_PRINT_DEPTH => write!(f, "{{...}}"),
+                    ObjectKind::BoundMethod { function, .. } => write!(f, "<bound {}>", self.nested(function)),
+                    ObjectKind::Instance(instance) => match self.heap.get_object(instance.class).map(|class| &class.kind) {
+                        Ok(ObjectKind::Class(class)) => write!(f, "<{} instance>", class.name),
+                        _ => write!(f, "<instance>"),
+                    },
+                    ObjectKind::List(list) => {
+                        write!(f, "[")?;
+                        for (idx, element) in list.iter().enumerate() {
+                            if idx > 0 {
+                                write!(f, ", ")?;
+                            }
+                            write!(f, "{}", self.nested(element))?;
+                        }
+                        write!(f, "]")
+                    }
+                    ObjectKind::Map(map) => {
+                        // Sorted so the output doesn't depend on the hasher
+                        let mut entries: Vec<_> = map.iter().map(|(key, value)| (Value::from(*key), *value)).collect();
+                        entries.sort_by(|(a, _), (b, _)| a.print_order(b, self.heap));
+                        write!(f, "{{")?;
+                        for (idx, (key, value)) in entries.into_iter().enumerate() {
+                            if idx > 0 {
+                                write!(f, ", ")?;
+                            }
+                            write!(f, "{}: {}", self.nested(&key), self.nested(&value))?;
+                        }
+                        write!(f, "}}")
+                    }
+                    ObjectKind::Set(set) => {
+                        let mut elements: Vec<_> = set.iter().map(|element| Value::from(*element)).collect();
+                        elements.sort_by(|a, b| a.print_order(b, self.heap));
+                        write!(f, "{{")?;
+                        for (idx, element) in elements.into_iter().enumerate() {
+                            if idx > 0 {
+                                write!(f, ", ")?;
+                            }
+                            write!(f, "{}", self.nested(&element))?;
+                        }
+                        write!(f, "}}")
+                    }
+                    kind => kind.fmt(f),
+                }
             }
-            val => val.fmt(f),
+            _ => self.value.fmt(f),
         }
     }
 }
 
+/// What makes two keys the same entry, numbers with an integer value are stored as `Int`
+/// so `1` and `1.0` are the same key, like they are equal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyKind {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    /// Bits of the float
+    Number(u64),
+    /// Strings are interned, so the key is enough to compare them by content
+    Object(ObjectKey),
+}
+
+/// A [`Value`] used as a map key or a set element.
+///
+/// It's hashed with [`Value::hash_with_heap`] when it's made, so keys that are `==` land in the same entry.
+#[derive(Debug, Clone, Copy)]
+pub struct MapKey {
+    kind: KeyKind,
+    hash: u64,
+}
+
+impl MapKey {
+    pub fn new(value: Value, heap: &ObjectHeap) -> Self {
+        let kind = match value.kind() {
+            ValueKind::Nil => KeyKind::Nil,
+            ValueKind::Bool(val) => KeyKind::Bool(val),
+            ValueKind::Int(num) => KeyKind::Int(num),
+            ValueKind::Number(num) if num.fract() == 0.0 && Value::in_int_range(num) => KeyKind::Int(num as i64),
+            ValueKind::Number(num) => KeyKind::Number(num.to_bits()),
+            ValueKind::Object(key) => KeyKind::Object(key),
+        };
+        let mut hasher = ahash::AHasher::default();
+        value.hash_with_heap(heap, &mut hasher);
+        Self { kind, hash: hasher.finish() }
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl From<MapKey> for Value {
+    fn from(key: MapKey) -> Self {
+        match key.kind {
+            KeyKind::Nil => Value::NIL,
+            KeyKind::Bool(val) => Value::bool(val),
+            KeyKind::Int(num) => Value::int(num),
+            KeyKind::Number(bits) => Value::number(f64::from_bits(bits)),
+            KeyKind::Object(key) => Value::object(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::object::Object;
+
+    use super::*;
+
+    #[test]
+    fn not_uses_truthiness() {
+        let mut heap = ObjectHeap::new();
+        let string = Value::object(heap.intern_string("x".into()));
+
+        assert!(matches!(Value::number(0.0).not(&mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(false))));
+        assert!(matches!(string.not(&mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(false))));
+        assert!(matches!(Value::NIL.not(&mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(true))));
+        assert!(matches!(Value::bool(false).not(&mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(true))));
+        assert!(matches!(Value::bool(true).not(&mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(false))));
+    }
+
+    fn hash_of(value: Value, heap: &ObjectHeap) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        value.hash_with_heap(heap, &mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn equal_values_hash_equal() {
+        let mut heap = ObjectHeap::new();
+        let interned = Value::object(heap.intern_string("hp".into()));
+        let concatenated = Value::concat(&[Value::object(heap.intern_string("h".into())), interned], &mut heap).unwrap();
+        let concatenated_again = Value::concat(&[Value::object(heap.intern_string("h".into())), interned], &mut heap).unwrap();
+        let list = Value::object(heap.alloc_object(Object::new(ObjectKind::List(vec![]))));
+        let other_list = Value::object(heap.alloc_object(Object::new(ObjectKind::List(vec![]))));
+
+        let equal_pairs = [
+            (Value::int(1), Value::number(1.0)),
+            (Value::number(0.0), Value::number(-0.0)),
+            (Value::int(0), Value::number(-0.0)),
+            (Value::number(2.5), Value::number(2.5)),
+            (Value::NIL, Value::NIL),
+            (Value::bool(true), Value::bool(true)),
+            (concatenated, concatenated_again),
+            (list, list),
+        ];
+        for (a, b) in equal_pairs {
+            assert!(matches!(a.equal(&b, &mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(true))), "{a} == {b}");
+            assert_eq!(hash_of(a, &heap), hash_of(b, &heap), "{a} and {b}");
+            assert_eq!(MapKey::new(a, &heap), MapKey::new(b, &heap), "{a} and {b}");
+        }
+
+        let distinct = [
+            Value::NIL,
+            Value::bool(false),
+            Value::bool(true),
+            Value::int(0),
+            Value::int(1),
+            Value::number(1.5),
+            interned,
+            concatenated,
+            list,
+            other_list,
+        ];
+        for (idx, a) in distinct.iter().enumerate() {
+            for b in &distinct[idx + 1..] {
+                assert_ne!(hash_of(*a, &heap), hash_of(*b, &heap), "{a} and {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn number_formatting() {
+        let number = |num: f64| Value::number(num).to_string();
+
+        assert_eq!(number(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(number(1e21), "1e21");
+        assert_eq!(number(1e-7), "1e-7");
+        assert_eq!(number(-0.0), "-0.0");
+        assert_eq!(number(4.0), "4.0");
+        assert_eq!(number(1.0 / 0.0), "inf");
+        assert_eq!(number(-1.0 / 0.0), "-inf");
+        assert_eq!(number(f64::NAN), "nan");
+        assert_eq!(
+            crate::run_source("print 0.1 + 0.2; print 10.0 ** 21; print -0.0; print 1.0 / 0; print str(0.0 / 0);").unwrap(),
+            "0.30000000000000004\n1e21\n-0.0\ninf\nnan\n"
+        );
+    }
+
+    #[test]
+    fn displays_objects_without_heap() {
+        let mut heap = ObjectHeap::new();
+        let first = Value::object(heap.intern_string("first".into()));
+        let second = Value::object(heap.intern_string("second".into()));
+
+        let first = first.to_string();
+        assert!(first.starts_with("<obj#") && first.ends_with('>'), "{first}");
+        assert_ne!(first, second.to_string());
+        assert_eq!(Value::int(3).to_string(), "3");
+    }
+
+    #[test]
+    fn strings_compare_lexicographically() {
+        let mut heap = ObjectHeap::new();
+        let apple = Value::object(heap.intern_string("apple".into()));
+        let banana = Value::object(heap.intern_string("banana".into()));
+        let a = Value::object(heap.intern_string("a".into()));
+        let b = Value::object(heap.intern_string("b".into()));
+
+        assert!(matches!(apple.less(&banana, &mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(true))));
+        assert!(matches!(banana.greater(&apple, &mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(true))));
+        assert!(matches!(b.less(&a, &mut heap).map(|value| value.kind()), Ok(ValueKind::Bool(false))));
+        assert!(matches!(a.less(&Value::int(1), &mut heap), Err(ValueError::UnSupportedOperation)));
+        assert!(matches!(Value::int(1).greater(&a, &mut heap), Err(ValueError::UnSupportedOperation)));
+    }
+
+    #[test]
+    fn add_stringifies_next_to_strings() {
+        let mut heap = ObjectHeap::new();
+        let x = Value::object(heap.intern_string("x=".into()));
+        let bang = Value::object(heap.intern_string("!".into()));
+
+        let joined = x.add(&Value::int(3), &mut heap).unwrap();
+        assert_eq!(joined.as_string(&heap).unwrap(), "x=3");
+        let joined = Value::bool(true).add(&bang, &mut heap).unwrap();
+        assert_eq!(joined.as_string(&heap).unwrap(), "true!");
+        assert!(matches!(Value::int(1).add(&Value::number(0.5), &mut heap).map(|value| value.kind()), Ok(ValueKind::Number(n)) if n == 1.5));
+    }
+
+    #[test]
+    fn int_arithmetic() {
+        let mut heap = ObjectHeap::new();
+
+        assert!(matches!(Value::int(7).div(&Value::int(2), &mut heap).map(|value| value.kind()), Ok(ValueKind::Int(3))));
+        assert!(matches!(Value::int(-7).div(&Value::int(2), &mut heap).map(|value| value.kind()), Ok(ValueKind::Int(-3))));
+        assert!(matches!(Value::int(7).rem(&Value::int(2), &mut heap).map(|value| value.kind()), Ok(ValueKind::Int(1))));
+        assert!(matches!(Value::int(-7).rem(&Value::int(2), &mut heap).map(|value| value.kind()), Ok(ValueKind::Int(-1))));
+        assert!(matches!(
+            Value::int(7).div(&Value::int(0), &mut heap),
+            Err(ValueError::DivisionByZero)
+        ));
+        assert!(matches!(
+            Value::int(7).rem(&Value::int(0), &mut heap),
+            Err(ValueError::DivisionByZero)
+        ));
+        // Overflowing results become floats instead of wrapping around
+        assert!(matches!(Value::int(i64::MAX).add(&Value::int(1), &mut heap).map(|value| value.kind()), Ok(ValueKind::Number(n)) if n == 2f64.powi(63)));
+        assert!(matches!(Value::int(i64::MIN).sub(&Value::int(1), &mut heap).map(|value| value.kind()), Ok(ValueKind::Number(n)) if n == -(2f64.powi(63))));
+        assert!(matches!(Value::int(i64::MIN).neg(&mut heap).map(|value| value.kind()), Ok(ValueKind::Number(n)) if n == 2f64.powi(63)));
+    }
+
+    #[test]
+    fn mixed_arithmetic_promotes() {
+        let mut heap = ObjectHeap::new();
+
+        assert!(matches!(
+            Value::number(7.0).div(&Value::int(2), &mut heap).map(|value| value.kind()),
+            Ok(ValueKind::Number(n)) if n == 3.5
+        ));
+        assert!(matches!(
+            Value::int(1).add(&Value::number(0.5), &mut heap).map(|value| value.kind()),
+            Ok(ValueKind::Number(n)) if n == 1.5
+        ));
+        assert!(matches!(
+            Value::int(1).equal(&Value::number(1.0), &mut heap).map(|value| value.kind()),
+            Ok(ValueKind::Bool(true))
+        ));
+        assert!(matches!(
+            Value::int(2).less(&Value::number(2.5), &mut heap).map(|value| value.kind()),
+            Ok(ValueKind::Bool(true))
+        ));
+    }
+
+    #[test]
+    fn kinds_roundtrip() {
+        let mut heap = ObjectHeap::new();
+        let key = heap.intern_string("x".into());
+        let other_key = heap.alloc_object(Object::new(ObjectKind::List(vec![])));
+
+        assert!(matches!(Value::NIL.kind(), ValueKind::Nil));
+        assert!(matches!(Value::bool(true).kind(), ValueKind::Bool(true)));
+        assert!(matches!(Value::bool(false).kind(), ValueKind::Bool(false)));
+        for num in [0, 1, -1, 42, Value::INT_MIN, Value::INT_MAX] {
+            assert_eq!(Value::int(num).as_int(), Some(num));
+        }
+        for num in [0.0, -0.0, 1.5, -2.25, f64::INFINITY, f64::NEG_INFINITY, f64::MIN_POSITIVE, f64::MAX] {
+            assert_eq!(Value::number(num).as_number().map(f64::to_bits), Some(num.to_bits()));
+        }
+        assert!(Value::number(f64::NAN).as_number().is_some_and(f64::is_nan));
+        assert!(Value::number(-f64::NAN).as_number().is_some_and(f64::is_nan));
+        assert_eq!(Value::object(key).as_object(), Some(key));
+        assert_eq!(Value::object(other_key).as_object(), Some(other_key));
+        assert_eq!(Value::object(key).as_number(), None);
+        assert_eq!(Value::int(1).as_number(), None);
+        assert_eq!(Value::NIL.as_bool(), None);
+    }
+
+    #[test]
+    fn int_range() {
+        assert!(Value::in_int_range(Value::INT_MIN as f64));
+        assert!(Value::in_int_range(-(Value::INT_MIN as f64) - 1024.0));
+        assert!(!Value::in_int_range(-(Value::INT_MIN as f64)));
+        assert!(!Value::in_int_range(f64::NAN));
+        assert!(matches!(
+            MapKey::new(Value::number(-(Value::INT_MIN as f64)), &ObjectHeap::new()).kind,
+            KeyKind::Number(_)
+        ));
+        #[cfg(feature = "nan-boxing")]
+        {
+            assert_eq!(std::mem::size_of::<Value>(), 8);
+            // Ints past 48 bits are stored aside, with every bit kept
+            for num in [1 << 47, -(1 << 47) - 1, (1 << 53) + 1, i64::MIN] {
+                assert_eq!(Value::int(num).as_int(), Some(num));
+                // The same value reuses its slot
+                assert_eq!(Value::int(num).0, Value::int(num).0);
+            }
+        }
+    }
+}
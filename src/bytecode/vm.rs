@@ -1,25 +1,172 @@
 use std::fmt::Display;
+use std::io::Write;
+use std::mem;
 use std::ops::Range;
+use std::rc::Rc;
 
+use ahash::AHashMap;
+use ecow::{eco_format, EcoString};
 use log::trace;
 
 use super::chunk::CodeChunk;
-use super::object::{HeapError, ObjectHeap};
+use super::natives;
+use super::object::{
+    Class, Function, HeapError, Instance, NativeBody, NativeFunction, Object, ObjectHeap, ObjectKey, ObjectKind,
+};
 use super::opcodes::*;
-use super::value::{Value, ValueError};
+use super::value::{MapKey, NumberDisplay, Value, ValueError, ValueKind};
 
-#[derive(Debug)]
 pub struct VM<'code, 'heap> {
     code: &'code CodeChunk,
     heap: &'heap mut ObjectHeap,
     stack: Stack,
+    frames: Vec<CallFrame>,
+    /// Function of the innermost frame, so reading its code doesn't go through `frames`
+    function: Option<Rc<Function>>,
+    pc: usize,
+    /// Where `print` writes to
+    output: Box<dyn Write + 'heap>,
+    /// Stopped at a `yield`, waiting for [`VM::resume`]
+    suspended: bool,
+    config: VmConfig,
+    /// Source spans to stop at, see [`VM::set_breakpoints`]
+    breakpoints: Vec<Range<usize>>,
+    /// The breakpoint the VM last stopped at, it doesn't stop there again until an instruction outside of it runs
+    current_breakpoint: Option<usize>,
+    /// Host calls running inside of the VM, like a `sort_by` comparator. Breakpoints only stop the outermost run,
+    /// a host call has to run until its callee returns
+    host_calls: usize,
+    /// Coroutines running inside of each other, see [`NESTED_RESUMES_MAX`]
+    resumes: usize,
+    trace: TraceState,
+}
+
+/// Only updated while instructions are traced
+#[derive(Debug, Clone, Copy, Default)]
+struct TraceState {
+    /// Instructions traced so far
+    instructions: u64,
+    /// Where the last traced instruction was
+    last_pc: usize,
+    /// Whether the trace log gets colors, see [`VM::with_trace_color`]
+    color: bool,
+}
+
+/// Behavior that differs between embedders, the default follows IEEE floats
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VmConfig {
+    /// Arithmetic on finite numbers that gives NaN or an infinity fails with [`RuntimeError::MathError`]
+    pub strict_math: bool,
+    /// Values the stack can hold before failing with [`RuntimeError::StackOverflow`], [`STACK_MAX`] if `None`
+    pub max_stack: Option<usize>,
+    /// Calling a native that isn't deterministic, like `now`, fails with [`RuntimeError::NondeterministicCall`].
+    ///
+    /// Runs of the same code with the same inputs then always end the same, as replays and lockstep games need.
+    pub deterministic: bool,
+}
+
+/// Maximum depth of nested function calls
+pub const FRAMES_MAX: usize = 1024;
+/// Room for 256 slots per frame on average. A single frame can address 65535 locals through the long
+/// instructions, but only this many values fit across all frames, a deeper stack means values are pushed and never popped
+pub const STACK_MAX: usize = FRAMES_MAX * 256;
+/// Coroutines resumed from inside of each other, every one of them runs in a nested loop
+pub const NESTED_RESUMES_MAX: usize = 64;
+
+#[derive(Debug, Clone)]
+struct CallFrame {
+    /// `None` for the top-level code
+    function: Option<Rc<Function>>,
+    /// Where to continue once the frame above returns
     pc: usize,
+    /// Stack index of local slot 0
+    base: usize,
+    /// Frames entered from [`VM::call_function`] halt the VM when they return
+    host_call: bool,
+}
+
+/// One call of a [`VM::capture_stack_trace`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// `None` for the top-level code
+    pub function: Option<EcoString>,
+    /// The instruction being executed, for outer frames the call that hasn't returned yet
+    pub span: Range<usize>,
+}
+
+/// What a name on an instance refers to
+enum Member {
+    Field(Value),
+    Method(Value),
 }
 
 #[derive(Debug, Clone)]
 pub enum RuntimeStep {
     KeepGoing,
     Halt,
+    Yield(Value),
+    /// The next instruction is in a breakpoint, it wasn't executed yet
+    Breakpoint,
+}
+
+/// A call that stops at every `yield` and continues from there when it's resumed, see [`VM::resume_coroutine`].
+///
+/// It has frames and a stack of its own, so any number of them can be suspended at once.
+#[derive(Debug)]
+pub struct Coroutine {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    /// Where the innermost frame continues
+    pc: usize,
+    status: CoroutineStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    /// Not resumed yet or waiting at a `yield`
+    Suspended,
+    /// Resumed and didn't yield yet
+    Running,
+    /// Its function returned or failed
+    Finished,
+}
+
+impl Coroutine {
+    /// Calls `callee` without arguments when it's resumed the first time
+    pub fn new(callee: Value) -> Self {
+        Self {
+            stack: vec![callee],
+            frames: Vec::new(),
+            pc: 0,
+            status: CoroutineStatus::Suspended,
+        }
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.status
+    }
+
+    /// Values it holds on to while suspended
+    pub fn stack_len(&self) -> usize {
+        self.stack.len()
+    }
+}
+
+/// What a coroutine did when it was resumed
+#[derive(Debug, Clone, Copy)]
+pub enum Resumed {
+    Yielded(Value),
+    /// Its function returned, it can't be resumed again
+    Returned(Value),
+}
+
+/// Where running stopped, see [`VM::run_to_yield`]
+#[derive(Debug, Clone, Copy)]
+pub enum RunState {
+    Finished,
+    Yielded(Value),
+    /// Stopped before an instruction in a breakpoint, running again continues from it
+    Breakpoint,
 }
 
 #[derive(Debug, Clone)]
@@ -30,112 +177,1007 @@ pub enum RuntimeError {
     ConstantNotFound,
     ConstantNotIdentifier,
     EmptyStack,
+    /// What [`RuntimeError::EmptyStack`] becomes in debug builds, compiled code never pops more than it pushed
+    UnbalancedStack,
+    StackOverflow,
+    /// Running code went over the heap's memory limit
+    OutOfMemory,
+    /// Strict math forbids the NaN or infinity `op` gave
+    MathError {
+        op: &'static str,
+        result: f64,
+    },
+    /// The instruction limit of [`VM::run_with_limit`] ran out
+    FuelExhausted,
+    OutputError,
+    NotSuspended,
+    /// Only a [`CoroutineStatus::Suspended`] coroutine can be resumed
+    CoroutineNotSuspended(CoroutineStatus),
+    UndefinedGlobal(EcoString),
+    UndefinedMethod {
+        receiver: &'static str,
+        name: EcoString,
+    },
+    /// An operation applied to values of the wrong types, `rhs` is `None` for unary operations
+    TypeError {
+        op: &'static str,
+        lhs: &'static str,
+        rhs: Option<&'static str>,
+    },
+    WrongArgumentCount {
+        expected: u8,
+        got: usize,
+    },
+    /// `kind` is the type name of the indexed list or string
+    IndexOutOfBounds {
+        kind: &'static str,
+        index: i64,
+        len: usize,
+    },
+    /// Lists and strings can only be indexed by `Int`s, `index` is the type that was used instead
+    InvalidIndex {
+        kind: &'static str,
+        index: &'static str,
+    },
+    /// Holds the missing key as it would be printed
+    KeyNotFound(EcoString),
+    /// An instance has neither a field nor a method `name`
+    UndefinedField {
+        class: EcoString,
+        name: EcoString,
+    },
+    /// An `assert` got a falsey value, holds its message if it had one
+    AssertionFailed(Option<EcoString>),
+    /// `assert_type` got a value of another type
+    TypeAssertionFailed {
+        expected: &'static str,
+        actual: &'static str,
+    },
+    /// A native was called with arguments it can't work with, holds the whole message
+    InvalidArgument(EcoString),
+    /// [`VmConfig::deterministic`] forbids calling the native with this name
+    NondeterministicCall(&'static str),
     HeapError(HeapError),
     ValueError(ValueError),
 }
 
 impl<'code, 'heap> VM<'code, 'heap> {
     pub fn init(code: &'code CodeChunk, heap: &'heap mut ObjectHeap) -> Self {
+        Self::init_with_output(code, heap, std::io::stdout())
+    }
+
+    /// Like [`VM::init`], but `print` writes to `output` instead of stdout
+    pub fn init_with_output(
+        code: &'code CodeChunk,
+        heap: &'heap mut ObjectHeap,
+        output: impl Write + 'heap,
+    ) -> Self {
         Self {
             code,
             stack: Stack::with_capacity(256),
             heap,
+            frames: vec![CallFrame {
+                function: None,
+                pc: 0,
+                base: 0,
+                host_call: false,
+            }],
+            function: None,
             pc: 0,
+            output: Box::new(output),
+            suspended: false,
+            config: VmConfig::default(),
+            breakpoints: Vec::new(),
+            current_breakpoint: None,
+            host_calls: 0,
+            resumes: 0,
+            trace: TraceState::default(),
         }
     }
 
+    pub fn with_config(mut self, config: VmConfig) -> Self {
+        self.config = config;
+        self.stack.max = config.max_stack.unwrap_or(STACK_MAX);
+        self
+    }
+
+    /// Colors the instructions and stacks in the trace log, which is plain by default
+    pub fn with_trace_color(mut self, color: bool) -> Self {
+        self.trace.color = color;
+        self
+    }
+
+    /// Stops before every instruction whose source span overlaps one of `spans`, replacing the previous breakpoints.
+    ///
+    /// Once stopped, running again executes the instruction and doesn't stop in the same breakpoint until the code leaves it.
+    /// Functions called by natives, like the comparator of `sort_by`, run to the end without stopping.
+    pub fn set_breakpoints(&mut self, spans: &[Range<usize>]) {
+        self.breakpoints = spans.to_vec();
+        self.current_breakpoint = None;
+    }
+
+    /// Runs to the end or the next breakpoint, every `yield` is resumed right away with `nil`
     pub fn run(&mut self) -> Result<(), RuntimeError> {
+        let mut state = self.run_to_yield()?;
+        while let RunState::Yielded(_) = state {
+            state = self.resume(Value::NIL)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`VM::run`], but returns the value the code leaves behind, `nil` if there is none.
+    ///
+    /// Code compiled with [`ParserConfig::expression_mode`] leaves the value of its last expression.
+    ///
+    /// [`ParserConfig::expression_mode`]: crate::compiler::parser::ParserConfig::expression_mode
+    pub fn run_to_value(&mut self) -> Result<Value, RuntimeError> {
+        self.run()?;
+        Ok(self.stack.pop().unwrap_or(Value::NIL))
+    }
+
+    /// Runs until the code finishes or yields.
+    ///
+    /// A yield suspends all of the frames, the VM keeps them until [`VM::resume`] is called.
+    pub fn run_to_yield(&mut self) -> Result<RunState, RuntimeError> {
         loop {
-            match self.step() {
-                Ok(RuntimeStep::Halt) => return Ok(()),
+            match self.step_instruction() {
+                Ok(RuntimeStep::Halt) => return Ok(RunState::Finished),
+                Ok(RuntimeStep::Breakpoint) => return Ok(RunState::Breakpoint),
+                Ok(RuntimeStep::Yield(value)) => {
+                    self.suspended = true;
+                    return Ok(RunState::Yielded(value));
+                }
                 Err(err) => return Err(err),
                 Ok(RuntimeStep::KeepGoing) => {}
             }
         }
     }
 
+    /// Continues after a `yield`, which evaluates to `value`
+    pub fn resume(&mut self, value: Value) -> Result<RunState, RuntimeError> {
+        if !self.suspended {
+            return Err(RuntimeError::NotSuspended);
+        }
+        self.suspended = false;
+        self.stack.push(value)?;
+        self.run_to_yield()
+    }
+
+    /// Like [`VM::run`], but fails with [`RuntimeError::FuelExhausted`] after `max_instructions`.
+    ///
+    /// Only this entry point counts instructions, so [`VM::run`] doesn't pay for it.
+    pub fn run_with_limit(&mut self, max_instructions: u64) -> Result<(), RuntimeError> {
+        for _ in 0..max_instructions {
+            if let RuntimeStep::Halt | RuntimeStep::Breakpoint = self.step()? {
+                return Ok(());
+            }
+        }
+        Err(RuntimeError::FuelExhausted)
+    }
+
+    /// Calls the global function `name` with `args` and runs it until it returns
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        self.call_function_with(name, args, Self::run)
+    }
+
+    /// Like [`VM::call_function`], with the instruction limit of [`VM::run_with_limit`]
+    pub fn call_function_with_limit(&mut self, name: &str, args: &[Value], max_instructions: u64) -> Result<Value, RuntimeError> {
+        self.call_function_with(name, args, |vm| vm.run_with_limit(max_instructions))
+    }
+
+    fn call_function_with(
+        &mut self,
+        name: &str,
+        args: &[Value],
+        run: impl FnOnce(&mut Self) -> Result<(), RuntimeError>,
+    ) -> Result<Value, RuntimeError> {
+        let callee = self.global(name)?;
+        self.call_host(callee, args, run)
+    }
+
+    /// Calls `callee` from a native, like `sort_by` calling its comparator, and runs it until it returns
+    pub(super) fn call_from_native(&mut self, callee: Value, args: &[Value]) -> Result<Value, RuntimeError> {
+        self.call_host(callee, args, Self::run)
+    }
+
+    fn call_host(
+        &mut self,
+        callee: Value,
+        args: &[Value],
+        run: impl FnOnce(&mut Self) -> Result<(), RuntimeError>,
+    ) -> Result<Value, RuntimeError> {
+        self.stack.push(callee)?;
+        for arg in args {
+            self.stack.push(*arg)?;
+        }
+        let depth = self.frames.len();
+        self.call_value(callee, args.len(), true)?;
+        if self.frames.len() > depth {
+            self.host_calls += 1;
+            let res = run(self);
+            self.host_calls -= 1;
+            res?;
+        }
+        self.stack.pop()
+    }
+
+    /// Makes a [`Coroutine`] on the heap that calls `callee` when it's first resumed
+    pub fn new_coroutine(&mut self, callee: Value) -> Result<Value, RuntimeError> {
+        let coroutine = ObjectKind::Coroutine(Coroutine::new(callee));
+        Ok(Value::object(self.heap.try_alloc_object(Object::new(coroutine))?))
+    }
+
+    /// Runs `coroutine` until it yields or its function returns, on the frames and stack it left off with.
+    ///
+    /// The `yield` it stopped at evaluates to `value`, the first resume calls the function and ignores `value`.
+    /// Like host calls, coroutines don't stop at breakpoints.
+    pub fn resume_coroutine(&mut self, coroutine: Value, value: Value) -> Result<Resumed, RuntimeError> {
+        let not_coroutine = RuntimeError::TypeError {
+            op: "resume",
+            lhs: coroutine.type_name(self.heap),
+            rhs: None,
+        };
+        let Some(key) = coroutine.as_object() else {
+            return Err(not_coroutine);
+        };
+        let ObjectKind::Coroutine(suspended) = &mut self.heap.get_object_mut(key)?.kind else {
+            return Err(not_coroutine);
+        };
+        if suspended.status != CoroutineStatus::Suspended {
+            return Err(RuntimeError::CoroutineNotSuspended(suspended.status));
+        }
+        if self.resumes >= NESTED_RESUMES_MAX {
+            return Err(RuntimeError::StackOverflow);
+        }
+        // Taken out of the heap while it runs, resuming it from inside of itself sees it running
+        let mut running = mem::replace(suspended, Coroutine::new(Value::NIL));
+        suspended.status = CoroutineStatus::Running;
+
+        self.resumes += 1;
+        let res = self.run_coroutine(&mut running, value);
+        self.resumes -= 1;
+
+        running.status = match res {
+            Ok(Resumed::Yielded(_)) => CoroutineStatus::Suspended,
+            _ => CoroutineStatus::Finished,
+        };
+        if running.status == CoroutineStatus::Finished {
+            running.stack = Vec::new();
+            running.frames = Vec::new();
+        }
+        if let ObjectKind::Coroutine(slot) = &mut self.heap.get_object_mut(key)?.kind {
+            *slot = running;
+        }
+        res
+    }
+
+    /// Swaps the frames and stack of `coroutine` in for the ones of the caller while it runs
+    fn run_coroutine(&mut self, coroutine: &mut Coroutine, value: Value) -> Result<Resumed, RuntimeError> {
+        mem::swap(&mut self.stack.stack, &mut coroutine.stack);
+        mem::swap(&mut self.frames, &mut coroutine.frames);
+        let caller_pc = mem::replace(&mut self.pc, coroutine.pc);
+        let innermost = self.frames.last().and_then(|frame| frame.function.clone());
+        let caller_function = mem::replace(&mut self.function, innermost);
+        self.host_calls += 1;
+
+        let res = self.run_coroutine_steps(value);
+
+        self.host_calls -= 1;
+        self.function = caller_function;
+        coroutine.pc = mem::replace(&mut self.pc, caller_pc);
+        mem::swap(&mut self.frames, &mut coroutine.frames);
+        mem::swap(&mut self.stack.stack, &mut coroutine.stack);
+        res
+    }
+
+    fn run_coroutine_steps(&mut self, value: Value) -> Result<Resumed, RuntimeError> {
+        if self.frames.is_empty() {
+            let callee = *self.stack.peek(0)?;
+            self.call_value(callee, 0, true)?;
+            // Natives return right away, without a frame
+            if self.frames.is_empty() {
+                return Ok(Resumed::Returned(self.stack.pop()?));
+            }
+        } else {
+            self.stack.push(value)?;
+        }
+        loop {
+            match self.step_instruction()? {
+                RuntimeStep::Yield(value) => return Ok(Resumed::Yielded(value)),
+                // The function's frame is the only one, so returning from it halts
+                RuntimeStep::Halt => return Ok(Resumed::Returned(self.stack.pop()?)),
+                RuntimeStep::KeepGoing | RuntimeStep::Breakpoint => {}
+            }
+        }
+    }
+
+    /// Executes a single instruction, a `yield` is resumed with `nil` right away like in [`VM::run`]
+    pub fn step(&mut self) -> Result<RuntimeStep, RuntimeError> {
+        let step = self.step_instruction()?;
+        if let RuntimeStep::Yield(_) = step {
+            self.stack.push(Value::NIL)?;
+        }
+        Ok(step)
+    }
+
+    /// Offset of the next instruction in [`VM::chunk`]
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The whole value stack as it is between instructions, the top is last
+    pub fn stack_snapshot(&self) -> &[Value] {
+        &self.stack.stack
+    }
+
+    pub fn heap(&self) -> &ObjectHeap {
+        self.heap
+    }
+
+    pub(super) fn heap_mut(&mut self) -> &mut ObjectHeap {
+        self.heap
+    }
+
+    pub fn print_stack(&self) -> StackPrinter<'_, '_> {
+        self.stack.print_stack_with_heap(self.heap)
+    }
+
+    /// Looks up the global variable `name`
+    pub fn global(&mut self, name: &str) -> Result<Value, RuntimeError> {
+        let identifier = self.heap.intern_string(EcoString::from(name));
+        self.get_global(identifier)
+    }
+
     pub fn current_span(&self) -> Range<usize> {
-        self.code.find_span_of(self.pc - 1).1.clone()
+        self.chunk().find_span_of(self.pc.saturating_sub(1)).1.clone()
     }
 
-    fn step(&mut self) -> Result<RuntimeStep, RuntimeError> {
+    /// Logs the state of the VM and the instruction about to run, with how far the pc moved since the last one
+    #[cold]
+    fn trace_instruction(&mut self) {
+        use owo_colors::{OwoColorize, Style};
+
+        let pc_delta = self.pc as isize - self.trace.last_pc as isize;
+        self.trace.instructions += 1;
+        self.trace.last_pc = self.pc;
+        let style = if self.trace.color { Style::new().blue().bold() } else { Style::new() };
+        trace!(
+            "{:12} #{} pc{:+} L:{} M:{}{} S:{}",
+            "",
+            self.trace.instructions.style(style),
+            pc_delta.style(style),
+            self.heap.live_count().style(style),
+            self.heap.dynamic_memory_used().style(style),
+            "B".style(style),
+            self.stack.print_stack_with_heap(self.heap).with_color(self.trace.color)
+        );
+        trace!(
+            "{}",
+            self.chunk().dissasemble().at(self.pc).with_heap(self.heap).with_color(self.trace.color)
+        );
+    }
+
+    /// Whether the next instruction enters a breakpoint, which then becomes the current one
+    fn at_new_breakpoint(&mut self) -> bool {
+        let span = self.chunk().find_span_of(self.pc).1.clone();
+        let hit = self
+            .breakpoints
+            .iter()
+            .position(|breakpoint| breakpoint.start < span.end && span.start < breakpoint.end);
+        let entered = hit.is_some() && hit != self.current_breakpoint;
+        self.current_breakpoint = hit;
+        entered
+    }
+
+    /// Every active call, innermost first
+    pub fn capture_stack_trace(&self) -> Vec<StackFrame> {
+        let innermost = self.frames.len().saturating_sub(1);
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(idx, frame)| {
+                let pc = if idx == innermost { self.pc } else { frame.pc };
+                let chunk = frame.function.as_ref().map_or(self.code, |function| &function.chunk);
+                StackFrame {
+                    function: frame.function.as_ref().map(|function| function.name.clone()),
+                    span: chunk.find_span_of(pc.saturating_sub(1)).1.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// The chunk of the currently executing function
+    pub fn chunk(&self) -> &CodeChunk {
+        match &self.function {
+            Some(function) => &function.chunk,
+            None => self.code,
+        }
+    }
+
+    fn frame_base(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.base)
+    }
+
+    fn get_global(&self, identifier: ObjectKey) -> Result<Value, RuntimeError> {
+        self.heap.get_global(identifier).map_err(|err| self.global_error(err, Some(identifier)))
+    }
+
+    /// Names the global in [`RuntimeError::UndefinedGlobal`], `identifier` is `None` for slots nothing was declared in
+    fn global_error(&self, err: HeapError, identifier: Option<ObjectKey>) -> RuntimeError {
+        match (err, identifier.map(|identifier| self.heap.get_object(identifier))) {
+            (HeapError::GlobalVariableNotFound, Some(Ok(Object { kind: ObjectKind::String(name), .. }))) => {
+                RuntimeError::UndefinedGlobal(name.clone())
+            }
+            (err, _) => err.into(),
+        }
+    }
+
+    /// Attaches the operand types to type errors coming from [`Value`] operations
+    fn operation_error(&self, err: ValueError, op: &'static str, lhs: &Value, rhs: Option<&Value>) -> RuntimeError {
+        match err {
+            ValueError::UnSupportedOperation => RuntimeError::TypeError {
+                op,
+                lhs: lhs.type_name(self.heap),
+                rhs: rhs.map(|rhs| rhs.type_name(self.heap)),
+            },
+            err => err.into(),
+        }
+    }
+
+    /// With [`VmConfig::strict_math`], finite operands can't give NaN or an infinity
+    fn check_math(op: &'static str, a: &Value, b: &Value, result: &Value) -> Result<(), RuntimeError> {
+        let finite = |value: &Value| value.as_float().is_none_or(f64::is_finite);
+        match result.kind() {
+            ValueKind::Number(num) if !num.is_finite() && finite(a) && finite(b) => {
+                Err(RuntimeError::MathError { op, result: num })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn not_indexable(&self, target: Value) -> RuntimeError {
+        RuntimeError::TypeError {
+            op: "index",
+            lhs: target.type_name(self.heap),
+            rhs: None,
+        }
+    }
+
+    /// Checks that `index` is an `Int` within a list or string of length `len`
+    fn sequence_index(&self, kind: &'static str, len: usize, index: Value) -> Result<usize, RuntimeError> {
+        let ValueKind::Int(index) = index.kind() else {
+            return Err(RuntimeError::InvalidIndex {
+                kind,
+                index: index.type_name(self.heap),
+            });
+        };
+        usize::try_from(index)
+            .ok()
+            .filter(|idx| *idx < len)
+            .ok_or(RuntimeError::IndexOutOfBounds { kind, index, len })
+    }
+
+    /// Reads `target[index]` from a list, a map or a string.
+    ///
+    /// Strings are indexed by characters, not bytes, and give a one character string. A missing map key gives `nil`.
+    fn index_get(&mut self, target: Value, index: Value) -> Result<Value, RuntimeError> {
+        let ValueKind::Object(key) = target.kind() else {
+            return Err(self.not_indexable(target));
+        };
+        let character = match &self.heap.get_object(key)?.kind {
+            ObjectKind::List(list) => return Ok(list[self.sequence_index("List", list.len(), index)?]),
+            ObjectKind::Map(map) => return Ok(map.get(&MapKey::new(index, self.heap)).copied().unwrap_or(Value::NIL)),
+            ObjectKind::String(string) => {
+                let idx = self.sequence_index("String", string.chars().count(), index)?;
+                string.chars().nth(idx).ok_or(RuntimeError::UnknownError)?
+            }
+            _ => return Err(self.not_indexable(target)),
+        };
+        Ok(Value::object(self.heap.try_intern_string(EcoString::from(character))?))
+    }
+
+    /// Writes `target[index]`, maps get a new entry if the key is missing
+    fn index_set(&mut self, target: Value, index: Value, value: Value) -> Result<(), RuntimeError> {
+        let ValueKind::Object(key) = target.kind() else {
+            return Err(self.not_indexable(target));
+        };
+        let list_idx = match &self.heap.get_object(key)?.kind {
+            ObjectKind::List(list) => Some(self.sequence_index("List", list.len(), index)?),
+            ObjectKind::Map(_) => {
+                // Checked before inserting, so a failed assignment leaves the map as it was
+                self.heap.reserve(std::mem::size_of::<(MapKey, Value)>())?;
+                None
+            }
+            ObjectKind::String(_) => {
+                return Err(RuntimeError::TypeError {
+                    op: "assign to an index of",
+                    lhs: "String",
+                    rhs: None,
+                })
+            }
+            _ => return Err(self.not_indexable(target)),
+        };
+        let map_key = MapKey::new(index, self.heap);
+        match (&mut self.heap.get_object_mut(key)?.kind, list_idx) {
+            (ObjectKind::List(list), Some(idx)) => list[idx] = value,
+            (ObjectKind::Map(map), None) => {
+                if map.insert(map_key, value).is_none() {
+                    self.heap.grow(std::mem::size_of::<(MapKey, Value)>())?;
+                }
+            }
+            _ => return Err(RuntimeError::UnknownError),
+        }
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, argc: usize, host_call: bool) -> Result<(), RuntimeError> {
+        let not_callable = || RuntimeError::TypeError {
+            op: "call",
+            lhs: callee.type_name(self.heap),
+            rhs: None,
+        };
+        let ValueKind::Object(key) = callee.kind() else {
+            return Err(not_callable());
+        };
+        let function = match &self.heap.get_object(key)?.kind {
+            ObjectKind::Function(function) => function.clone(),
+            ObjectKind::Native(native) => return self.call_native(*native, argc),
+            &ObjectKind::BoundMethod { receiver, function, is_method: true } => {
+                // The receiver takes the callee's slot, where the method finds `this`
+                let callee_slot = self.stack.len() - argc - 1;
+                self.stack.set_at(callee_slot, receiver)?;
+                return self.call_value(function, argc, host_call);
+            }
+            &ObjectKind::BoundMethod { receiver, function, is_method: false } => {
+                // The function takes the callee's slot and the receiver goes before the arguments
+                self.stack.insert(argc, receiver)?;
+                let callee_slot = self.stack.len() - argc - 2;
+                self.stack.set_at(callee_slot, function)?;
+                return self.call_value(function, argc + 1, host_call);
+            }
+            ObjectKind::Class(_) => return self.construct(key, argc, host_call),
+            _ => return Err(not_callable()),
+        };
+        if function.arity as usize != argc {
+            return Err(RuntimeError::WrongArgumentCount {
+                expected: function.arity,
+                got: argc,
+            });
+        }
+        if self.frames.len() >= FRAMES_MAX {
+            return Err(RuntimeError::StackOverflow);
+        }
+
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pc = self.pc;
+        }
+        self.function = Some(function.clone());
+        self.frames.push(CallFrame {
+            function: Some(function),
+            pc: 0,
+            base: self.stack.len() - argc - 1,
+            host_call,
+        });
+        self.pc = 0;
+        Ok(())
+    }
+
+    /// Calling a class makes an instance and passes the arguments to its `init` method
+    fn construct(&mut self, class: ObjectKey, argc: usize, host_call: bool) -> Result<(), RuntimeError> {
+        let init = self.heap.intern_string(EcoString::from("init"));
+        let ObjectKind::Class(class_object) = &self.heap.get_object(class)?.kind else {
+            return Err(RuntimeError::UnknownError);
+        };
+        let init = class_object.methods.get(&init).copied();
+        let instance = Instance { class, fields: AHashMap::new() };
+        let instance = Value::object(self.heap.try_alloc_object(Object::new(ObjectKind::Instance(instance)))?);
+        // The instance replaces the class, as `this` of `init` or as the result if there's no `init`
+        let callee_slot = self.stack.len() - argc - 1;
+        self.stack.set_at(callee_slot, instance)?;
+        match init {
+            Some(init) => self.call_value(init, argc, host_call),
+            None if argc == 0 => Ok(()),
+            None => Err(RuntimeError::WrongArgumentCount { expected: 0, got: argc }),
+        }
+    }
+
+    /// Natives run to completion right away, their result replaces the callee and the arguments
+    fn call_native(&mut self, native: NativeFunction, argc: usize) -> Result<(), RuntimeError> {
+        if !native.accepts(argc) {
+            return Err(RuntimeError::WrongArgumentCount {
+                expected: native.arity,
+                got: argc,
+            });
+        }
+        let mut args = self.stack.top(argc)?.to_vec();
+        args.resize(native.arity as usize, Value::NIL);
+        let result = self.run_native(native, &args)?;
+        self.stack.truncate(self.stack.len() - argc - 1);
+        self.stack.push(result)?;
+        Ok(())
+    }
+
+    fn run_native(&mut self, native: NativeFunction, args: &[Value]) -> Result<Value, RuntimeError> {
+        if self.config.deterministic && !native.deterministic {
+            return Err(RuntimeError::NondeterministicCall(native.name));
+        }
+        match native.function {
+            NativeBody::Heap(function) => function(self.heap, args),
+            NativeBody::Vm(function) => function(self, args),
+        }
+    }
+
+    /// `receiver.name`, an entry of a map with the name as its key, otherwise the method `name` bound to `receiver`.
+    ///
+    /// Instances look up their fields and then the methods of their class.
+    fn get_field(&mut self, receiver: Value, name: ObjectKey) -> Result<Value, RuntimeError> {
+        let ValueKind::Object(key) = receiver.kind() else {
+            return self.bind_method(receiver, name);
+        };
+        if let ObjectKind::Instance(_) = &self.heap.get_object(key)?.kind {
+            return match self.instance_member(key, name)? {
+                Member::Field(value) => Ok(value),
+                Member::Method(function) => {
+                    let bound = ObjectKind::BoundMethod { receiver, function, is_method: true };
+                    Ok(Value::object(self.heap.try_alloc_object(Object::new(bound))?))
+                }
+            };
+        }
+        let ObjectKind::Map(map) = &self.heap.get_object(key)?.kind else {
+            return self.bind_method(receiver, name);
+        };
+        if let Some(value) = map.get(&MapKey::new(Value::object(name), self.heap)) {
+            return Ok(*value);
+        }
+        // Methods are only looked up once there's no entry, so entries shadow them
+        self.bind_method(receiver, name).map_err(|err| match err {
+            RuntimeError::UndefinedMethod { .. } => {
+                RuntimeError::KeyNotFound(eco_format!("{}", Value::object(name).print_with_heap(self.heap).quoted()))
+            }
+            err => err,
+        })
+    }
+
+    /// `receiver.name = value`, instances get a new field if they don't have one, maps a new entry
+    fn set_field(&mut self, receiver: Value, name: Value, value: Value) -> Result<(), RuntimeError> {
+        if let (ValueKind::Object(key), ValueKind::Object(name)) = (receiver.kind(), name.kind()) {
+            if let ObjectKind::Instance(instance) = &mut self.heap.get_object_mut(key)?.kind {
+                if instance.fields.insert(name, value).is_none() {
+                    self.heap.grow(std::mem::size_of::<(ObjectKey, Value)>())?;
+                }
+                return Ok(());
+            }
+        }
+        if receiver.type_name(self.heap) != "Map" {
+            return Err(RuntimeError::TypeError {
+                op: "set a field of",
+                lhs: receiver.type_name(self.heap),
+                rhs: None,
+            });
+        }
+        self.index_set(receiver, name, value)
+    }
+
+    /// Whether `value` is an instance of exactly the class `class`, not just of one with the same name
+    fn is_instance_of(&self, value: Value, class: Value) -> Result<bool, RuntimeError> {
+        let class = match class.kind() {
+            ValueKind::Object(key) if matches!(self.heap.get_object(key)?.kind, ObjectKind::Class(_)) => key,
+            _ => {
+                return Err(RuntimeError::InvalidArgument(eco_format!(
+                    "Right side of 'is' must be a class or a type, not {}",
+                    class.type_name(self.heap)
+                )))
+            }
+        };
+        let ValueKind::Object(key) = value.kind() else { return Ok(false) };
+        Ok(matches!(&self.heap.get_object(key)?.kind, ObjectKind::Instance(instance) if instance.class == class))
+    }
+
+    /// The field `name` of an instance, or the method of its class if it has no such field
+    fn instance_member(&self, instance: ObjectKey, name: ObjectKey) -> Result<Member, RuntimeError> {
+        let ObjectKind::Instance(instance) = &self.heap.get_object(instance)?.kind else {
+            return Err(RuntimeError::UnknownError);
+        };
+        if let Some(value) = instance.fields.get(&name) {
+            return Ok(Member::Field(*value));
+        }
+        let ObjectKind::Class(class) = &self.heap.get_object(instance.class)?.kind else {
+            return Err(RuntimeError::UnknownError);
+        };
+        if let Some(method) = class.methods.get(&name) {
+            return Ok(Member::Method(*method));
+        }
+        Err(RuntimeError::UndefinedField {
+            class: class.name.clone(),
+            name: Value::object(name).as_string(self.heap).cloned().unwrap_or_default(),
+        })
+    }
+
+    /// The built-in method `name` of `receiver`, bound to it so it can be called later
+    fn bind_method(&mut self, receiver: Value, name: ObjectKey) -> Result<Value, RuntimeError> {
+        let receiver_type = receiver.type_name(self.heap);
+        let ObjectKind::String(name) = &self.heap.get_object(name)?.kind else {
+            return Err(RuntimeError::ConstantNotIdentifier);
+        };
+        let method = natives::find_method(receiver_type, name).ok_or_else(|| RuntimeError::UndefinedMethod {
+            receiver: receiver_type,
+            name: name.clone(),
+        })?;
+        // Called as a plain function, the receiver is one more argument
+        let method = NativeFunction { arity: method.arity + 1, ..method };
+        let function = Value::object(self.heap.try_alloc_object(Object::new(ObjectKind::Native(method)))?);
+        let bound = self.heap.try_alloc_object(Object::new(ObjectKind::BoundMethod { receiver, function, is_method: false }))?;
+        Ok(Value::object(bound))
+    }
+
+    /// Calls the built-in method `name` of the value below the arguments, the receiver is passed first.
+    ///
+    /// On instances it calls the field `name` or the class method, which gets the receiver as `this`.
+    fn invoke(&mut self, name: ObjectKey, argc: usize) -> Result<(), RuntimeError> {
+        if let ValueKind::Object(key) = self.stack.peek(argc)?.kind() {
+            if let ObjectKind::Instance(_) = &self.heap.get_object(key)?.kind {
+                return match self.instance_member(key, name)? {
+                    Member::Method(method) => self.call_value(method, argc, false),
+                    Member::Field(value) => {
+                        let callee_slot = self.stack.len() - argc - 1;
+                        self.stack.set_at(callee_slot, value)?;
+                        self.call_value(value, argc, false)
+                    }
+                };
+            }
+        }
+        let receiver = self.stack.peek(argc)?.type_name(self.heap);
+        let ObjectKind::String(name) = &self.heap.get_object(name)?.kind else {
+            return Err(RuntimeError::ConstantNotIdentifier);
+        };
+        let method = natives::find_method(receiver, name).ok_or_else(|| RuntimeError::UndefinedMethod {
+            receiver,
+            name: name.clone(),
+        })?;
+        if !method.accepts(argc) {
+            return Err(RuntimeError::WrongArgumentCount {
+                expected: method.arity,
+                got: argc,
+            });
+        }
+        let mut args = self.stack.top(argc + 1)?.to_vec();
+        args.resize(method.arity as usize + 1, Value::NIL);
+        let result = self.run_native(method, &args)?;
+        self.stack.truncate(self.stack.len() - argc - 1);
+        self.stack.push(result)?;
+        Ok(())
+    }
+
+    fn step_instruction(&mut self) -> Result<RuntimeStep, RuntimeError> {
+        let res = self.execute_instruction();
+        if cfg!(debug_assertions) {
+            if let Err(RuntimeError::EmptyStack) = res {
+                return Err(RuntimeError::UnbalancedStack);
+            }
+        }
+        res
+    }
+
+    fn execute_instruction(&mut self) -> Result<RuntimeStep, RuntimeError> {
         macro_rules! bin_op {
-            ($op:ident) => {{
+            ($op:ident, $name:literal) => {{
                 let b = self.stack.pop()?;
                 let a = self.stack.pop()?;
-                let value = a.$op(&b, self.heap)?;
-                self.stack.push(value);
+                let value = a
+                    .$op(&b, self.heap)
+                    .map_err(|err| self.operation_error(err, $name, &a, Some(&b)))?;
+                if self.config.strict_math {
+                    Self::check_math($name, &a, &b, &value)?;
+                }
+                self.stack.push(value)?;
             }};
         }
 
         macro_rules! un_op {
-            ($op:ident) => {{
-                let value = self.stack.pop()?.$op(self.heap)?;
-                self.stack.push(value);
+            ($op:ident, $name:literal) => {{
+                let a = self.stack.pop()?;
+                let value = a
+                    .$op(self.heap)
+                    .map_err(|err| self.operation_error(err, $name, &a, None))?;
+                self.stack.push(value)?;
             }};
         }
-        
-        {
-            use owo_colors::OwoColorize;
 
-            trace!(
-                "{:12} L:{} M:{}{} S:{}",
-                "",
-                self.heap.live_count().blue().bold(),
-                self.heap.dynamic_memory_used().blue().bold(),
-                "B".blue().bold(),
-                self.stack.print_stack_with_heap(self.heap)
-            );
-            trace!(
-                "{}",
-                self.code.dissasemble().at(self.pc).with_heap(self.heap)
-            );
+        if !self.breakpoints.is_empty() && self.host_calls == 0 && self.at_new_breakpoint() {
+            return Ok(RuntimeStep::Breakpoint);
+        }
+
+        // A single check per instruction, everything else only happens when tracing
+        if cfg!(feature = "trace") && log::log_enabled!(log::Level::Trace) {
+            self.trace_instruction();
         }
 
         let op = self.read_u8()?;
 
         match op {
             OP_RETURN => {
-                return Ok(RuntimeStep::Halt);
+                if self.frames.len() <= 1 {
+                    return Ok(RuntimeStep::Halt);
+                }
+                let result = self.stack.pop()?;
+                let frame = self.frames.pop().ok_or_else(|| RuntimeError::UnknownError)?;
+                self.stack.truncate(frame.base);
+                self.stack.push(result)?;
+                if let Some(caller) = self.frames.last() {
+                    self.pc = caller.pc;
+                    self.function = caller.function.clone();
+                }
+                if frame.host_call {
+                    return Ok(RuntimeStep::Halt);
+                }
+            }
+            OP_CALL => {
+                let argc = self.read_u8()?;
+                let callee = *self.stack.peek(argc as usize)?;
+                self.call_value(callee, argc as usize, false)?;
+            }
+            OP_IS => {
+                let name = self.read_constant()?;
+                let value = self.stack.pop()?;
+                let Some(name) = name.as_string(self.heap) else {
+                    return Err(RuntimeError::ConstantNotIdentifier);
+                };
+                let matches = value.type_name(self.heap) == name.as_str();
+                self.stack.push(Value::bool(matches))?;
+            }
+            OP_IS_CLASS => {
+                let class = self.stack.pop()?;
+                let value = self.stack.pop()?;
+                let matches = self.is_instance_of(value, class)?;
+                self.stack.push(Value::bool(matches))?;
+            }
+            OP_INVOKE => {
+                let name = self.read_constant()?;
+                let ValueKind::Object(name) = name.kind() else { return Err(RuntimeError::ConstantNotIdentifier) };
+                let argc = self.read_u8()?;
+                self.invoke(name, argc as usize)?;
+            }
+            OP_GET_FIELD => {
+                let name = self.read_constant()?;
+                let ValueKind::Object(name) = name.kind() else { return Err(RuntimeError::ConstantNotIdentifier) };
+                let receiver = self.stack.pop()?;
+                let field = self.get_field(receiver, name)?;
+                self.stack.push(field)?;
+            }
+            OP_SET_FIELD => {
+                let name = self.read_constant()?;
+                let value = self.stack.pop()?;
+                let receiver = self.stack.pop()?;
+                self.set_field(receiver, name, value)?;
+                self.stack.push(value)?;
+            }
+            OP_CLASS => {
+                let name = self.read_constant()?;
+                let Some(name) = name.as_string(self.heap) else { return Err(RuntimeError::ConstantNotIdentifier) };
+                let class = Class { name: name.clone(), methods: AHashMap::new() };
+                let key = self.heap.try_alloc_object(Object::new(ObjectKind::Class(class)))?;
+                self.stack.push(Value::object(key))?;
+            }
+            OP_METHOD => {
+                let name = self.read_constant()?;
+                let ValueKind::Object(name) = name.kind() else { return Err(RuntimeError::ConstantNotIdentifier) };
+                let method = self.stack.pop()?;
+                let ValueKind::Object(class) = self.stack.peek(0)?.kind() else { return Err(RuntimeError::UnknownError) };
+                let ObjectKind::Class(class) = &mut self.heap.get_object_mut(class)?.kind else {
+                    return Err(RuntimeError::UnknownError);
+                };
+                class.methods.insert(name, method);
+            }
+            OP_ASSERT => {
+                let message = match self.read_u8()? {
+                    0 => None,
+                    _ => Some(self.stack.pop()?),
+                };
+                if self.stack.pop()?.is_falsey() {
+                    let message = message.map(|message| eco_format!("{}", message.print_with_heap(self.heap)));
+                    return Err(RuntimeError::AssertionFailed(message));
+                }
             }
             OP_PRINT => {
                 let value = self.stack.pop()?;
-                println!("{}", value.print_with_heap(self.heap));
+                writeln!(self.output, "{}", value.print_with_heap(self.heap))
+                    .map_err(|_| RuntimeError::OutputError)?;
+            }
+            OP_WRITE => {
+                let value = self.stack.pop()?;
+                // Flushed right away, stdout would hold the text back until the line ends
+                write!(self.output, "{}", value.print_with_heap(self.heap))
+                    .and_then(|_| self.output.flush())
+                    .map_err(|_| RuntimeError::OutputError)?;
             }
             OP_CONSTANT => {
                 let value = self.read_constant()?;
-                self.stack.push(value);
+                self.stack.push(value)?;
             }
             OP_POP => {
                 self.stack.pop()?;
             }
             OP_DEF_GLOBAL => {
                 let ident_value = self.read_constant()?;
-                let Value::Object(ident) = ident_value else { return Err(RuntimeError::ConstantNotIdentifier) };
+                let ValueKind::Object(ident) = ident_value.kind() else { return Err(RuntimeError::ConstantNotIdentifier) };
                 let variable = self.stack.pop()?;
                 self.heap.put_as_global(ident, variable);
             }
             OP_GET_GLOBAL => {
                 let ident_value = self.read_constant()?;
-                let Value::Object(ident) = ident_value else { return Err(RuntimeError::ConstantNotIdentifier) };
-                let val = self.heap.get_global(ident)?;
-                self.stack.push(val);
+                let ValueKind::Object(ident) = ident_value.kind() else { return Err(RuntimeError::ConstantNotIdentifier) };
+                let val = self.get_global(ident)?;
+                self.stack.push(val)?;
             }
             OP_SET_GLOBAL => {
                 let ident_value = self.read_constant()?;
-                let Value::Object(ident) = ident_value else { return Err(RuntimeError::ConstantNotIdentifier) };
-                self.heap.get_global(ident)?;
-                self.heap.put_as_global(ident, *self.stack.peek(0)?);
+                let ValueKind::Object(ident) = ident_value.kind() else { return Err(RuntimeError::ConstantNotIdentifier) };
+                let value = *self.stack.peek(0)?;
+                self.heap
+                    .set_global(ident, value)
+                    .map_err(|err| self.global_error(err, Some(ident)))?;
+            }
+            OP_GET_GLOBAL_SLOT => {
+                let slot = self.read_u16()? as usize;
+                let value = self
+                    .heap
+                    .get_global_at(slot)
+                    .map_err(|err| self.global_error(err, self.heap.global_name(slot)))?;
+                self.stack.push(value)?;
+            }
+            OP_SET_GLOBAL_SLOT => {
+                let slot = self.read_u16()? as usize;
+                let value = *self.stack.peek(0)?;
+                self.heap
+                    .set_global_at(slot, value)
+                    .map_err(|err| self.global_error(err, self.heap.global_name(slot)))?;
             }
             OP_GET_LOCAL => {
                 let idx = self.read_u8()?;
-                let local = self.stack.get_at(idx as usize)?;
-                self.stack.push(*local);
+                let local = self.stack.get_at(self.frame_base() + idx as usize)?;
+                self.stack.push(*local)?;
+            }
+            OP_GET_LOCAL_LONG => {
+                let idx = self.read_u16()?;
+                let local = self.stack.get_at(self.frame_base() + idx as usize)?;
+                self.stack.push(*local)?;
+            }
+            OP_POPN => {
+                let count = self.read_u8()? as usize;
+                self.stack.top(count)?;
+                self.stack.truncate(self.stack.len() - count);
+            }
+            OP_NOT_EQUAL => {
+                let b = self.stack.pop()?;
+                let a = self.stack.pop()?;
+                let equal = a
+                    .equal(&b, self.heap)
+                    .map_err(|err| self.operation_error(err, "compare", &a, Some(&b)))?;
+                self.stack.push(Value::bool(equal.is_falsey()))?;
+            }
+            OP_INC_LOCAL => {
+                let idx = self.frame_base() + self.read_u8()? as usize;
+                let amount = self.read_constant()?;
+                let local = *self.stack.get_at(idx)?;
+                let value = local
+                    .add(&amount, self.heap)
+                    .map_err(|err| self.operation_error(err, "add", &local, Some(&amount)))?;
+                if self.config.strict_math {
+                    Self::check_math("add", &local, &amount, &value)?;
+                }
+                self.stack.set_at(idx, value)?;
+                self.stack.push(value)?;
             }
             OP_SET_LOCAL => {
                 let idx = self.read_u8()?;
                 let set = self.stack.peek(0)?;
-                self.stack.set_at(idx as usize, *set)?;
+                self.stack.set_at(self.frame_base() + idx as usize, *set)?;
+            }
+            OP_SET_LOCAL_LONG => {
+                let idx = self.read_u16()?;
+                let set = self.stack.peek(0)?;
+                self.stack.set_at(self.frame_base() + idx as usize, *set)?;
             }
             OP_JUMP => {
                 let pos = self.read_i16()?;
@@ -148,49 +1190,142 @@ impl<'code, 'heap> VM<'code, 'heap> {
                     self.pc = (self.pc as isize + pos as isize) as usize;
                 }
             }
-            OP_TRUE => self.stack.push(Value::Bool(true)),
-            OP_FALSE => self.stack.push(Value::Bool(false)),
-            OP_NIL => self.stack.push(Value::Nil),
-            OP_NEG => un_op!(neg),
-            OP_NOT => un_op!(not),
-            OP_AND => bin_op!(and),
-            OP_OR => bin_op!(or),
-            OP_ADD => bin_op!(add),
-            OP_SUB => bin_op!(sub),
-            OP_MUL => bin_op!(mul),
-            OP_DIV => bin_op!(div),
-            OP_EQUAL => bin_op!(equal),
-            OP_LESS => bin_op!(less),
-            OP_GREATER => bin_op!(greater),
+            OP_JUMP_NIL => {
+                let pos = self.read_i16()?;
+                if self.stack.peek(0)?.is_nil() {
+                    self.pc = (self.pc as isize + pos as isize) as usize;
+                }
+            }
+            OP_CONCAT_N => {
+                let count = self.read_u8()? as usize;
+                let operands = self.stack.top(count)?;
+                let value = Value::concat(operands, self.heap).map_err(|err| {
+                    // Everything before the first operand that can't be joined is already a string
+                    let rhs = operands
+                        .iter()
+                        .find(|value| matches!(value.kind(), ValueKind::Object(_)) && value.as_string(self.heap).is_none())
+                        .unwrap_or(&Value::NIL);
+                    self.operation_error(err, "add", &operands[0], Some(rhs))
+                })?;
+                self.stack.truncate(self.stack.len() - count);
+                self.stack.push(value)?;
+            }
+            OP_BUILD_LIST => {
+                let count = self.read_u8()? as usize;
+                let list = self.stack.top(count)?.to_vec();
+                self.stack.truncate(self.stack.len() - count);
+                let key = self.heap.try_alloc_object(Object::new(ObjectKind::List(list)))?;
+                self.stack.push(Value::object(key))?;
+            }
+            OP_ARRAY_REPEAT => {
+                let count = self.stack.pop()?;
+                let value = self.stack.pop()?;
+                let count = match count.kind() {
+                    ValueKind::Int(count) => usize::try_from(count).map_err(|_| {
+                        RuntimeError::InvalidArgument(eco_format!("List repeat count can't be negative, got {count}"))
+                    })?,
+                    _ => {
+                        return Err(RuntimeError::InvalidArgument(eco_format!(
+                            "List repeat count must be Int, not {}",
+                            count.type_name(self.heap)
+                        )))
+                    }
+                };
+                // Checked up front, so a huge count fails before the list is built
+                let bytes = count
+                    .checked_mul(std::mem::size_of::<Value>())
+                    .filter(|bytes| *bytes <= isize::MAX as usize)
+                    .ok_or(RuntimeError::OutOfMemory)?;
+                self.heap.reserve(bytes)?;
+                let mut list = Vec::new();
+                list.try_reserve_exact(count).map_err(|_| RuntimeError::OutOfMemory)?;
+                list.resize(count, value);
+                let key = self.heap.try_alloc_object(Object::new(ObjectKind::List(list)))?;
+                self.stack.push(Value::object(key))?;
+            }
+            OP_INDEX_GET => {
+                let index = self.stack.pop()?;
+                let target = self.stack.pop()?;
+                let element = self.index_get(target, index)?;
+                self.stack.push(element)?;
+            }
+            OP_INDEX_SET => {
+                let value = self.stack.pop()?;
+                let index = self.stack.pop()?;
+                let target = self.stack.pop()?;
+                self.index_set(target, index, value)?;
+                self.stack.push(value)?;
+            }
+            OP_BUILD_MAP => {
+                let count = self.read_u8()? as usize;
+                let entries = self.stack.top(count * 2)?;
+                let map = entries
+                    .chunks_exact(2)
+                    .map(|entry| (MapKey::new(entry[0], self.heap), entry[1]))
+                    .collect();
+                self.stack.truncate(self.stack.len() - count * 2);
+                let key = self.heap.try_alloc_object(Object::new(ObjectKind::Map(map)))?;
+                self.stack.push(Value::object(key))?;
+            }
+            OP_BUILD_SET => {
+                let count = self.read_u8()? as usize;
+                self.heap.reserve(count * std::mem::size_of::<MapKey>())?;
+                let set = self.stack.top(count)?.iter().map(|element| MapKey::new(*element, self.heap)).collect();
+                self.stack.truncate(self.stack.len() - count);
+                let key = self.heap.try_alloc_object(Object::new(ObjectKind::Set(set)))?;
+                self.stack.push(Value::object(key))?;
+            }
+            OP_YIELD => {
+                let value = self.stack.pop()?;
+                return Ok(RuntimeStep::Yield(value));
+            }
+            OP_TRUE => self.stack.push(Value::bool(true))?,
+            OP_FALSE => self.stack.push(Value::bool(false))?,
+            OP_NIL => self.stack.push(Value::NIL)?,
+            OP_NEG => un_op!(neg, "negate"),
+            OP_NOT => un_op!(not, "negate"),
+            OP_AND => bin_op!(and, "apply 'and' to"),
+            OP_OR => bin_op!(or, "apply 'or' to"),
+            OP_ADD => bin_op!(add, "add"),
+            OP_SUB => bin_op!(sub, "subtract"),
+            OP_MUL => bin_op!(mul, "multiply"),
+            OP_DIV => bin_op!(div, "divide"),
+            OP_REM => bin_op!(rem, "take the remainder of"),
+            OP_POW => bin_op!(pow, "exponentiate"),
+            OP_EQUAL => bin_op!(equal, "compare"),
+            OP_LESS => bin_op!(less, "compare"),
+            OP_GREATER => bin_op!(greater, "compare"),
             _ => return Err(RuntimeError::UnknownCode),
         }
 
         Ok(RuntimeStep::KeepGoing)
     }
 
+    /// The next `N` bytes of the current chunk, every operand is decoded from a single bounds check
+    fn read<const N: usize>(&mut self) -> Result<[u8; N], RuntimeError> {
+        self.pc += N;
+        // Errors here and in `Stack` are built lazily, an eager one is dropped on every instruction
+        let bytes = self.chunk().code.get(self.pc - N..self.pc).ok_or_else(|| RuntimeError::UnexpectedEnd)?;
+        Ok(bytes.try_into().expect("the slice has N bytes"))
+    }
+
     fn read_u8(&mut self) -> Result<u8, RuntimeError> {
-        self.pc += 1;
-        self.code
-            .get_byte(self.pc - 1)
-            .ok_or(RuntimeError::UnexpectedEnd)
+        self.read().map(u8::from_be_bytes)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, RuntimeError> {
+        self.read().map(u16::from_be_bytes)
     }
 
     fn read_i16(&mut self) -> Result<i16, RuntimeError> {
-        self.pc += 2;
-        let big = self.code
-            .get_byte(self.pc - 2)
-            .ok_or(RuntimeError::UnexpectedEnd)?;
-        let little = self.code
-            .get_byte(self.pc - 1)
-            .ok_or(RuntimeError::UnexpectedEnd)?;
-        Ok(i16::from_be_bytes([big, little]))
+        self.read().map(i16::from_be_bytes)
     }
 
     fn read_constant(&mut self) -> Result<Value, RuntimeError> {
         let constant_offset = self.read_u8()?;
-        self.code
+        self.chunk()
             .get_constant(constant_offset as usize)
-            .ok_or(RuntimeError::ConstantNotFound)
+            .ok_or_else(|| RuntimeError::ConstantNotFound)
             .copied()
     }
 }
@@ -198,78 +1333,203 @@ impl<'code, 'heap> VM<'code, 'heap> {
 #[derive(Debug, Clone)]
 struct Stack {
     stack: Vec<Value>,
+    max: usize,
 }
 
 impl Stack {
     fn with_capacity(capacity: usize) -> Self {
         Self {
             stack: Vec::with_capacity(capacity),
+            max: STACK_MAX,
         }
     }
 
     fn pop(&mut self) -> Result<Value, RuntimeError> {
-        self.stack.pop().ok_or(RuntimeError::EmptyStack)
+        self.stack.pop().ok_or_else(|| RuntimeError::EmptyStack)
     }
 
     fn peek(&self, dist: usize) -> Result<&Value, RuntimeError> {
-        self.stack
-            .get(self.stack.len() - 1 - dist)
-            .ok_or(RuntimeError::EmptyStack)
+        let idx = self
+            .stack
+            .len()
+            .checked_sub(dist + 1)
+            .ok_or_else(|| RuntimeError::EmptyStack)?;
+        Ok(&self.stack[idx])
     }
 
     fn get_at(&self, idx: usize) -> Result<&Value, RuntimeError> {
-        self.stack.get(idx).ok_or(RuntimeError::EmptyStack)
+        self.stack.get(idx).ok_or_else(|| RuntimeError::EmptyStack)
     }
 
     fn set_at(&mut self, idx: usize, value: Value) -> Result<(), RuntimeError> {
-        *self.stack.get_mut(idx).ok_or(RuntimeError::EmptyStack)? = value;
+        *self.stack.get_mut(idx).ok_or_else(|| RuntimeError::EmptyStack)? = value;
+        Ok(())
+    }
+
+    /// Inserts `value` below the topmost `depth` values
+    fn insert(&mut self, depth: usize, value: Value) -> Result<(), RuntimeError> {
+        if self.stack.len() >= self.max {
+            return Err(RuntimeError::StackOverflow);
+        }
+        let idx = self.stack.len().checked_sub(depth).ok_or_else(|| RuntimeError::EmptyStack)?;
+        self.stack.insert(idx, value);
         Ok(())
     }
 
-    fn push(&mut self, value: Value) {
+    fn push(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.stack.len() >= self.max {
+            return Err(RuntimeError::StackOverflow);
+        }
         self.stack.push(value);
+        Ok(())
+    }
+
+    /// The topmost `count` values, deepest first
+    fn top(&self, count: usize) -> Result<&[Value], RuntimeError> {
+        let start = self
+            .stack
+            .len()
+            .checked_sub(count)
+            .ok_or_else(|| RuntimeError::EmptyStack)?;
+        Ok(&self.stack[start..])
+    }
+
+    fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.stack.truncate(len);
     }
 
     fn print_stack_with_heap<'stack, 'heap>(
         &'stack self,
         heap: &'heap ObjectHeap,
     ) -> StackPrinter<'stack, 'heap> {
-        StackPrinter { stack: self, heap }
+        StackPrinter { stack: self, heap, color: false }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::UndefinedGlobal(name) => write!(f, "Undefined global variable '{name}'"),
+            RuntimeError::UndefinedMethod { receiver, name } => write!(f, "{receiver} has no method '{name}'"),
+            RuntimeError::TypeError { op, lhs, rhs: Some(rhs) } => {
+                write!(f, "Cannot {op} {lhs} and {rhs}")
+            }
+            RuntimeError::TypeError { op, lhs, rhs: None } => write!(f, "Cannot {op} {lhs}"),
+            RuntimeError::WrongArgumentCount { expected, got } => {
+                write!(f, "Expected {expected} arguments but got {got}")
+            }
+            RuntimeError::IndexOutOfBounds { kind, index, len } => {
+                let kind = kind.to_lowercase();
+                write!(f, "Index {index} is out of bounds for a {kind} of length {len}")
+            }
+            RuntimeError::InvalidIndex { kind, index } => {
+                write!(f, "{kind} indices must be Int, not {index}")
+            }
+            RuntimeError::KeyNotFound(key) => write!(f, "Key {key} not found in the map"),
+            RuntimeError::UndefinedField { class, name } => write!(f, "{class} has no field or method '{name}'"),
+            RuntimeError::AssertionFailed(None) => write!(f, "Assertion failed"),
+            RuntimeError::AssertionFailed(Some(msg)) => write!(f, "Assertion failed: {msg}"),
+            RuntimeError::TypeAssertionFailed { expected, actual } => {
+                write!(f, "Expected a value of type {expected} but got {actual}")
+            }
+            RuntimeError::InvalidArgument(msg) => write!(f, "{msg}"),
+            RuntimeError::NondeterministicCall(name) => write!(f, "Can't call '{name}' in deterministic mode"),
+            RuntimeError::StackOverflow => write!(f, "Stack overflow"),
+            RuntimeError::OutOfMemory => write!(f, "Out of memory"),
+            RuntimeError::MathError { op, result } => {
+                write!(f, "Cannot {op} these numbers, the result would be {}", NumberDisplay(*result))
+            }
+            RuntimeError::FuelExhausted => write!(f, "Instruction limit reached, the program may be stuck in a loop"),
+            RuntimeError::OutputError => write!(f, "Couldn't write the output"),
+            RuntimeError::NotSuspended => write!(f, "Nothing to resume, the code didn't yield"),
+            RuntimeError::CoroutineNotSuspended(CoroutineStatus::Running) => {
+                write!(f, "Can't resume a coroutine that is already running")
+            }
+            RuntimeError::CoroutineNotSuspended(_) => write!(f, "Can't resume a coroutine that has finished"),
+            RuntimeError::ValueError(err) => write!(f, "{err}"),
+            RuntimeError::HeapError(err) => write!(f, "{err}"),
+            RuntimeError::UnbalancedStack => {
+                write!(f, "Internal error: the compiler produced an unbalanced stack, please report this")
+            }
+            err => write!(f, "Internal error: {err:?}"),
+        }
+    }
+}
+
+impl RuntimeError {
+    /// Errors caused by broken bytecode rather than by the script, their span doesn't point at the cause
+    pub fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            RuntimeError::UnknownError
+                | RuntimeError::UnexpectedEnd
+                | RuntimeError::UnknownCode
+                | RuntimeError::ConstantNotFound
+                | RuntimeError::ConstantNotIdentifier
+                | RuntimeError::EmptyStack
+                | RuntimeError::UnbalancedStack
+        )
     }
 }
 
 impl From<HeapError> for RuntimeError {
     fn from(value: HeapError) -> Self {
-        Self::HeapError(value)
+        match value {
+            HeapError::OutOfMemory => Self::OutOfMemory,
+            h => Self::HeapError(h),
+        }
     }
 }
 
 impl From<ValueError> for RuntimeError {
     fn from(value: ValueError) -> Self {
         match value {
-            ValueError::HeapError(h) => Self::HeapError(h),
+            ValueError::HeapError(h) => h.into(),
             v => Self::ValueError(v)
         }
     }
 }
 
+impl std::fmt::Debug for VM<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VM")
+            .field("code", &self.code)
+            .field("heap", &self.heap)
+            .field("stack", &self.stack)
+            .field("frames", &self.frames)
+            .field("pc", &self.pc)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Debug)]
 pub struct StackPrinter<'stack, 'heap> {
     stack: &'stack Stack,
     heap: &'heap ObjectHeap,
+    color: bool,
 }
 
 impl<'stack, 'heap> StackPrinter<'stack, 'heap> {
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
     fn write_stack(&self, f: &mut impl std::fmt::Write) -> std::fmt::Result {
-        use owo_colors::OwoColorize;
+        use owo_colors::{OwoColorize, Style};
 
+        let style = if self.color { Style::new().blue() } else { Style::new() };
         write!(f, "[")?;
         let mut stack_iter = self.stack.stack.iter();
         if let Some(first_val) = stack_iter.next() {
-            write!(f, "'{}'", first_val.print_with_heap(self.heap).blue())?;
+            write!(f, "'{}'", first_val.print_with_heap(self.heap).style(style))?;
         }
         for val in stack_iter {
-            write!(f, ", '{}'", val.print_with_heap(self.heap).blue())?;
+            write!(f, ", '{}'", val.print_with_heap(self.heap).style(style))?;
         }
         write!(f, "]")?;
         Ok(())
@@ -284,6 +1544,8 @@ impl<'stack, 'heap> Display for StackPrinter<'stack, 'heap> {
 
 #[cfg(test)]
 mod tests {
+    use crate::compiler::parser::{Parser, ParserConfig};
+
     use super::*;
 
     fn init_logger() {
@@ -300,11 +1562,11 @@ mod tests {
 
         let mut chunk = CodeChunk::new();
         chunk.push_span_info(0..10);
-        let constant = chunk.push_constant(Value::Number(1.2));
+        let constant = chunk.push_constant(Value::number(1.2)).unwrap();
         chunk.push_code(OP_CONSTANT);
         chunk.push_code(constant);
 
-        let constant = chunk.push_constant(Value::Number(3.4));
+        let constant = chunk.push_constant(Value::number(3.4)).unwrap();
         chunk.push_code(OP_CONSTANT);
         chunk.push_code(constant);
 
@@ -312,7 +1574,7 @@ mod tests {
 
         chunk.push_span_info(10..20);
 
-        let constant = chunk.push_constant(Value::Number(5.6));
+        let constant = chunk.push_constant(Value::number(5.6)).unwrap();
         chunk.push_code(OP_CONSTANT);
         chunk.push_code(constant);
 
@@ -327,4 +1589,509 @@ mod tests {
         let res = vm.run();
         eprintln!("{:?}", res);
     }
+
+    #[test]
+    fn string_comparison_test() {
+        let source = r#"
+            let a = "ab";
+            print a + "c" == "abc";
+            print "abc" < "abd";
+            print "ab" < "abc";
+            print "b" > "abc";
+            print "abc" >= "abc";
+            print "abd" <= "abc";
+        "#;
+        assert_eq!(
+            crate::run_source(source).unwrap(),
+            "true\ntrue\ntrue\ntrue\ntrue\nfalse\n"
+        );
+
+        let res = crate::run_source(r#"let a = "a"; print a < 1;"#);
+        assert!(matches!(
+            res,
+            Err(crate::RunError::Runtime { error: RuntimeError::TypeError { op: "compare", .. }, .. })
+        ));
+    }
+
+    #[test]
+    fn undefined_global_test() {
+        for source in ["print missing;", "fn f() { return missing + 1; } f();", "missing = 2;"] {
+            match crate::run_source(source) {
+                Err(crate::RunError::Runtime { error: RuntimeError::UndefinedGlobal(name), span, .. }) => {
+                    assert_eq!(name, "missing");
+                    assert_eq!(&source[span], "missing");
+                }
+                res => panic!("Expected an undefined global, got {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn global_slot_test() {
+        let mut heap = ObjectHeap::new();
+        let mut run = |source: &str| {
+            let mut code = CodeChunk::new();
+            Parser::parse_source(source, &mut code, &mut heap).unwrap();
+            let res = VM::init(&code, &mut heap).run();
+            (code, res)
+        };
+
+        // The failed definition still declared a slot, which stays undefined
+        assert!(run("let x = missing;").1.is_err());
+        let (code, res) = run("print x; x = 1;");
+        assert!(code.dissasemble().with_color(false).to_string().contains("GET GLOBAL SLOT"));
+        assert!(matches!(res, Err(RuntimeError::UndefinedGlobal(name)) if name == "x"));
+        let (_, res) = run("x = 1;");
+        assert!(matches!(res, Err(RuntimeError::UndefinedGlobal(name)) if name == "x"));
+
+        let (code, res) = run("let x = 1; fn add(n) { x += n; return later; } let later = 2; print add(2) + x;");
+        res.unwrap();
+        let dump = code.dissasemble().with_heap(&heap).with_color(false).to_string();
+        assert!(dump.contains("SET GLOBAL SLOT"), "{dump}");
+        // `later` wasn't declared yet when `add` was compiled
+        assert!(dump.contains("GET GLOBAL   0 var 'later'"), "{dump}");
+        let x = heap.intern_string(EcoString::from("x"));
+        assert!(matches!(heap.get_global(x).map(|value| value.kind()), Ok(ValueKind::Int(3))));
+    }
+
+    #[test]
+    fn type_error_message_test() {
+        let message = |source| match crate::run_source(source) {
+            Err(crate::RunError::Runtime { error, .. }) => error.to_string(),
+            res => panic!("Expected a runtime error, got {res:?}"),
+        };
+
+        assert_eq!(message("print [1] + 2;"), "Cannot add List and Int");
+        assert_eq!(message("print nil + true;"), "Cannot add Nil and Bool");
+        assert_eq!(message("let a = \"a\"; print -a;"), "Cannot negate String");
+        assert_eq!(message("print 1 / 0;"), "Division by zero");
+        // Each bad combination names both operand types
+        let messages = [
+            message("let n = nil; print n + 1;"),
+            message("let t = true; print t - 2;"),
+            message("let x = \"x\"; print x * 3;"),
+        ];
+        assert_eq!(messages, ["Cannot add Nil and Int", "Cannot subtract Bool and Int", "Cannot multiply String and Int"]);
+        assert_eq!(ValueError::UnSupportedOperation.to_string(), "Operation not supported for these types");
+        assert_eq!(HeapError::GlobalVariableNotFound.to_string(), "Undefined variable");
+    }
+
+    #[test]
+    fn index_error_span_test() {
+        for (source, spanned) in [
+            ("let xs = [1, [2]]; print xs[1 + 1];", "[1 + 1]"),
+            ("let xs = [1, [2]]; xs[1][3] = 0;", "[3]"),
+            ("let x = 1; print x[0];", "[0]"),
+        ] {
+            match crate::run_source(source) {
+                Err(crate::RunError::Runtime { span, .. }) => assert_eq!(&source[span], spanned),
+                res => panic!("Expected a runtime error, got {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn assert_span_test() {
+        let source = r#"let x = 1;
+            assert x + 1 == 2;
+            assert x  >  1, "x is " + x;"#;
+        match crate::run_source(source) {
+            Err(crate::RunError::Runtime { error, span, .. }) => {
+                assert_eq!(error.to_string(), "Assertion failed: x is 1");
+                assert_eq!(&source[span], "x  >  1");
+            }
+            res => panic!("Expected a failed assertion, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn memory_limit_test() {
+        for source in [
+            r#"let s = "ab"; while true { s = s + s; }"#,
+            r#"let s = "ab"; while true { s = "x" + s + "y"; }"#,
+            "let l = []; while true { l.push(l); }",
+            "let m = {}; let i = 0; while true { m[i] = i; i = i + 1; }",
+        ] {
+            let mut code = CodeChunk::new();
+            let mut heap = ObjectHeap::new();
+            crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+            let limit = heap.dynamic_memory_used() + (1 << 16);
+            heap.set_max_bytes(Some(limit));
+            let res = VM::init(&code, &mut heap).run();
+            assert!(matches!(res, Err(RuntimeError::OutOfMemory)), "{source}: {res:?}");
+            assert!(heap.dynamic_memory_used() <= limit);
+        }
+    }
+
+    #[test]
+    fn map_out_of_memory_test() {
+        let source = "let m = {}; let i = 0; while true { m[i] = i; i = i + 1; }";
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        heap.set_max_bytes(Some(heap.dynamic_memory_used() + (1 << 12)));
+        let mut vm = VM::init(&code, &mut heap);
+        assert!(matches!(vm.run(), Err(RuntimeError::OutOfMemory)));
+
+        // The entry that didn't fit isn't in the map
+        let (m, i) = (vm.global("m").unwrap(), vm.global("i").unwrap());
+        let Ok(ObjectKind::Map(map)) = vm.heap.get_object(m.as_object().unwrap()).map(|object| &object.kind) else {
+            panic!("m is a map");
+        };
+        assert_eq!(Some(map.len() as i64), i.as_int());
+    }
+
+    #[test]
+    fn set_memory_test() {
+        // Removed elements give their memory back, so the set never holds more than two
+        let source = "let s = {0}; let i = 1; while i < 10000 { set_add(s, i); set_remove(s, i); i = i + 1; }";
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        let limit = heap.dynamic_memory_used() + (1 << 12);
+        heap.set_max_bytes(Some(limit));
+        VM::init(&code, &mut heap).run().unwrap();
+        assert!(heap.dynamic_memory_used() <= limit);
+    }
+
+    #[test]
+    fn strict_math_test() {
+        let run = |source: &str, config: VmConfig| {
+            let mut code = CodeChunk::new();
+            let mut heap = ObjectHeap::new();
+            crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+            let mut output = Vec::new();
+            let mut vm = VM::init_with_output(&code, &mut heap, &mut output).with_config(config);
+            let res = vm.run().map_err(|err| (err, vm.current_span()));
+            drop(vm);
+            res.map(|_| String::from_utf8(output).unwrap())
+        };
+        let strict = VmConfig { strict_math: true, ..VmConfig::default() };
+        let source = "let zero = 0.0; let nan = zero / zero; print 1 / zero; print nan == nan; print nan != nan;";
+
+        assert_eq!(run(source, VmConfig::default()).unwrap(), "inf\nfalse\ntrue\n");
+        let (err, span) = run(source, strict).unwrap_err();
+        assert!(matches!(err, RuntimeError::MathError { op: "divide", result } if result.is_nan()), "{err:?}");
+        assert_eq!(&source[span], "/");
+        assert_eq!(err.to_string(), "Cannot divide these numbers, the result would be nan");
+
+        assert!(matches!(run("print 10.0 ** 400;", strict), Err((RuntimeError::MathError { op: "exponentiate", .. }, _))));
+        assert_eq!(run("print 1.5 / 0.5;", strict).unwrap(), "3.0\n");
+    }
+
+    #[test]
+    fn deterministic_test() {
+        let run = |source: &str, config: VmConfig| {
+            let (code, mut heap) = crate::compile_source(source, ParserConfig::default()).unwrap();
+            let mut output = Vec::new();
+            let res = VM::init_with_output(&code, &mut heap, &mut output).with_config(config).run();
+            res.map(|_| String::from_utf8(output).unwrap())
+        };
+        let deterministic = VmConfig { deterministic: true, ..VmConfig::default() };
+
+        assert!(run("let t = now(); print t >= 0;", VmConfig::default()).is_ok());
+        let err = run("let t = now();", deterministic).unwrap_err();
+        assert!(matches!(err, RuntimeError::NondeterministicCall("now")), "{err:?}");
+        assert_eq!(err.to_string(), "Can't call 'now' in deterministic mode");
+        assert_eq!(run("print clamp(5, 0, 3); print [1, 2].len();", deterministic).unwrap(), "3\n2\n");
+    }
+
+    #[test]
+    fn instruction_limit_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let source = "let i = 0;\nwhile true { i = i + 1; }";
+        crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        let mut vm = VM::init(&code, &mut heap);
+        assert!(matches!(vm.run_with_limit(1000), Err(RuntimeError::FuelExhausted)));
+        assert!(!source[vm.current_span()].is_empty());
+        assert!(source.find("while").unwrap() <= vm.current_span().start);
+        drop(vm);
+
+        let mut code = CodeChunk::new();
+        crate::compiler::parser::Parser::parse_source("fn f() { return 2; } let x = f();", &mut code, &mut heap).unwrap();
+        let mut vm = VM::init(&code, &mut heap);
+        assert!(vm.run_with_limit(1000).is_ok());
+        assert!(matches!(vm.call_function_with_limit("f", &[], 1000).map(|value| value.kind()), Ok(ValueKind::Int(2))));
+    }
+
+    #[test]
+    fn pow_test() {
+        let source = "
+            print 2 ** 10;
+            print 2 ** 3 ** 2;
+            print -2 ** 2;
+            print 2 ** -1;
+            print 4.0 ** 0.5;
+        ";
+        assert_eq!(crate::run_source(source).unwrap(), "1024\n512\n-4\n0.5\n2.0\n");
+
+        let res = crate::run_source(r#"let a = "a"; print 2 ** a;"#);
+        assert!(matches!(
+            res,
+            Err(crate::RunError::Runtime { error: RuntimeError::TypeError { op: "exponentiate", .. }, .. })
+        ));
+    }
+
+    #[test]
+    fn run_to_value_test() {
+        let eval = |source: &str| {
+            let mut code = CodeChunk::new();
+            let mut heap = ObjectHeap::new();
+            let config = ParserConfig { expression_mode: true, ..ParserConfig::default() };
+            Parser::parse_source_with_config(source, &mut code, &mut heap, config).unwrap();
+            let value = VM::init(&code, &mut heap).run_to_value().unwrap();
+            (value, value.print_with_heap(&heap).to_string())
+        };
+        assert!(matches!(eval("3 + 4").0.kind(), ValueKind::Int(7)));
+        assert!(matches!(eval("3.5 + 4").0.kind(), ValueKind::Number(n) if n == 7.5));
+        assert_eq!(eval(r#"let name = "hp"; name + ": " + 3"#).1, "hp: 3");
+        assert_eq!(eval("fn sq(x) { return x * x; } [sq(2), sq(3)]").1, "[4, 9]");
+        // Statements leave nothing behind
+        assert!(matches!(eval("let x = 1;").0.kind(), ValueKind::Nil));
+        assert!(matches!(eval("1;").0.kind(), ValueKind::Nil));
+        assert!(matches!(eval("").0.kind(), ValueKind::Nil));
+
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        assert!(Parser::parse_source("3 + 4", &mut code, &mut heap).is_err());
+    }
+
+    #[test]
+    fn step_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        Parser::parse_source("{ let a = 2; let b = a * 3; print b; }", &mut code, &mut heap).unwrap();
+        let mut output = Vec::new();
+        let mut vm = VM::init_with_output(&code, &mut heap, &mut output);
+
+        let mut stacks = Vec::new();
+        let mut pcs = vec![vm.pc()];
+        while let RuntimeStep::KeepGoing = vm.step().unwrap() {
+            stacks.push(vm.stack_snapshot().iter().map(ToString::to_string).collect::<Vec<_>>().join(" "));
+            pcs.push(vm.pc());
+        }
+        assert_eq!(
+            stacks,
+            [
+                "2",     // CONSTANT 2
+                "2 2",   // GET LOCAL a
+                "2 2 3", // CONSTANT 3
+                "2 6",   // MUL
+                "2 6 6", // GET LOCAL b
+                "2 6",   // PRINT
+                "2",     // POP
+                "",      // POP
+            ]
+        );
+        assert!(pcs.windows(2).all(|pair| pair[0] < pair[1]));
+        drop(vm);
+        assert_eq!(output, b"6\n");
+    }
+
+    #[test]
+    fn breakpoint_test() {
+        let source = "fn twice(x) {\n    return x * 2;\n}\nlet a = twice(1);\nlet b = twice(a);\nprint b;";
+        let body = source.find("return").unwrap()..source.find(";\n}").unwrap();
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        let mut output = Vec::new();
+        let mut vm = VM::init_with_output(&code, &mut heap, &mut output);
+        vm.set_breakpoints(std::slice::from_ref(&body));
+
+        // Stops before the body runs, once for every call
+        assert!(matches!(vm.run_to_yield(), Ok(RunState::Breakpoint)));
+        assert!(body.contains(&vm.chunk().find_span_of(vm.pc()).1.start));
+        assert!(matches!(vm.stack_snapshot(), [_, value] if value.as_int() == Some(1)));
+        assert!(vm.global("a").is_err());
+
+        assert!(matches!(vm.run_to_yield(), Ok(RunState::Breakpoint)));
+        assert!(matches!(vm.global("a").map(|value| value.kind()), Ok(ValueKind::Int(2))));
+        assert!(matches!(vm.stack_snapshot(), [_, value] if value.as_int() == Some(2)));
+
+        assert!(matches!(vm.run_to_yield(), Ok(RunState::Finished)));
+        drop(vm);
+        assert_eq!(output, b"4\n");
+    }
+
+    #[test]
+    fn breakpoint_in_host_call_test() {
+        let source = "fn less(a, b) {\n    return a < b;\n}\nprint sort_by([3, 1, 2], less);";
+        let body = source.find("return").unwrap()..source.find(";\n}").unwrap();
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        let mut output = Vec::new();
+        let mut vm = VM::init_with_output(&code, &mut heap, &mut output);
+        vm.set_breakpoints(std::slice::from_ref(&body));
+
+        // The comparator runs inside of `sort_by`, which can't stop halfway
+        assert!(matches!(vm.run_to_yield(), Ok(RunState::Finished)));
+        drop(vm);
+        assert_eq!(output, b"[1, 2, 3]\n");
+    }
+
+    #[test]
+    fn write_test() {
+        let output = crate::run_source(r#"write "hp: "; write 3; print "/5"; write [1]; print "";"#).unwrap();
+        assert_eq!(output, "hp: 3/5\n[1]\n");
+    }
+
+    #[test]
+    fn yield_test() {
+        let source = "
+            fn wait(frames) { return yield frames; }
+            let i = 0;
+            while i < 3 {
+                i = i + wait(i);
+            }
+            print i;
+        ";
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        let mut output = Vec::new();
+        let mut vm = VM::init_with_output(&code, &mut heap, &mut output);
+
+        let mut yielded = Vec::new();
+        let mut state = vm.run_to_yield().unwrap();
+        while let RunState::Yielded(value) = state {
+            let frames = value.as_int().unwrap();
+            yielded.push(frames);
+            state = vm.resume(Value::int(frames + 1)).unwrap();
+        }
+        assert!(matches!(state, RunState::Finished));
+        assert!(matches!(vm.resume(Value::NIL), Err(RuntimeError::NotSuspended)));
+        drop(vm);
+
+        // i goes 0 -> 1 -> 3
+        assert_eq!(yielded, [0, 1]);
+        assert_eq!(String::from_utf8(output).unwrap(), "3\n");
+    }
+
+    #[test]
+    fn coroutine_test() {
+        let source = "
+            fn count(from) {
+                let i = from;
+                while true {
+                    i = i + yield i;
+                }
+            }
+            fn from_zero() { return count(0); }
+            fn from_ten() { return count(10); }
+            fn once() { return 1; }
+        ";
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+        let mut vm = VM::init(&code, &mut heap);
+        vm.run().unwrap();
+        let frames = vm.frames.len();
+
+        // Each one keeps its own frames and stack while the other runs
+        let from_zero = vm.global("from_zero").unwrap();
+        let from_ten = vm.global("from_ten").unwrap();
+        let a = vm.new_coroutine(from_zero).unwrap();
+        let b = vm.new_coroutine(from_ten).unwrap();
+        let mut counted = Vec::new();
+        for step in 0..3 {
+            for co in [a, b] {
+                let Resumed::Yielded(value) = vm.resume_coroutine(co, Value::int(step)).unwrap() else {
+                    panic!("count never returns");
+                };
+                counted.push(value.as_int().unwrap());
+            }
+        }
+        assert_eq!(counted, [0, 10, 1, 11, 3, 13]);
+        assert_eq!(vm.stack.len(), 0);
+        assert_eq!(vm.frames.len(), frames);
+
+        let once = vm.global("once").unwrap();
+        let co = vm.new_coroutine(once).unwrap();
+        assert!(matches!(vm.resume_coroutine(co, Value::NIL), Ok(Resumed::Returned(value)) if value.as_int() == Some(1)));
+        assert!(matches!(
+            vm.resume_coroutine(co, Value::NIL),
+            Err(RuntimeError::CoroutineNotSuspended(CoroutineStatus::Finished))
+        ));
+        assert!(matches!(
+            vm.resume_coroutine(Value::int(1), Value::NIL),
+            Err(RuntimeError::TypeError { op: "resume", .. })
+        ));
+    }
+
+    #[test]
+    fn call_native_test() {
+        let code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let mut vm = VM::init(&code, &mut heap);
+
+        let res = vm.call_function("str", &[Value::number(1.5)]).unwrap();
+        assert_eq!(res.as_string(vm.heap).unwrap(), "1.5");
+        assert_eq!(vm.stack.len(), 0);
+        assert!(matches!(
+            vm.call_function("str", &[]),
+            Err(RuntimeError::WrongArgumentCount { expected: 1, got: 0 })
+        ));
+    }
+
+    #[test]
+    fn runaway_stack_test() {
+        // Pushes nil forever
+        let mut chunk = CodeChunk::new();
+        chunk.push_code(OP_NIL);
+        chunk.push_code(OP_JUMP);
+        for byte in (-4i16).to_be_bytes() {
+            chunk.push_code(byte);
+        }
+
+        let mut heap = ObjectHeap::new();
+        let res = VM::init(&chunk, &mut heap).run();
+        assert!(matches!(res, Err(RuntimeError::StackOverflow)));
+    }
+
+    #[test]
+    fn max_stack_test() {
+        // Every level keeps `x` on the stack until the innermost addition
+        let nested = |depth| format!("let x = 1; print {}x{};", "x + (".repeat(depth), ")".repeat(depth));
+        let run = |source: &str| {
+            let mut code = CodeChunk::new();
+            let mut heap = ObjectHeap::new();
+            crate::compiler::parser::Parser::parse_source(source, &mut code, &mut heap).unwrap();
+            let config = VmConfig { max_stack: Some(64), ..VmConfig::default() };
+            let mut vm = VM::init_with_output(&code, &mut heap, std::io::sink()).with_config(config);
+            vm.run().map_err(|err| (err, vm.capture_stack_trace()))
+        };
+
+        assert!(run(&nested(50)).is_ok());
+        let source = nested(100);
+        let Err((err, trace)) = run(&source) else {
+            panic!("Expected a stack overflow");
+        };
+        assert!(matches!(err, RuntimeError::StackOverflow));
+        assert_eq!(&source[trace[0].span.clone()], "x");
+    }
+
+    #[test]
+    fn empty_stack_test() {
+        let mut chunk = CodeChunk::new();
+        chunk.push_code(OP_JUMP_F);
+        chunk.push_code(0);
+        chunk.push_code(0);
+        chunk.push_code(OP_RETURN);
+
+        let mut heap = ObjectHeap::new();
+        let res = VM::init(&chunk, &mut heap).run();
+        // Debug builds report the underflow as a compiler bug
+        if cfg!(debug_assertions) {
+            let err = res.unwrap_err();
+            assert!(matches!(err, RuntimeError::UnbalancedStack));
+            assert!(err.is_internal());
+            assert!(err.to_string().contains("compiler produced an unbalanced stack"));
+        } else {
+            assert!(matches!(res, Err(RuntimeError::EmptyStack)));
+        }
+        assert!(!RuntimeError::StackOverflow.is_internal());
+    }
 }
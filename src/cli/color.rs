@@ -0,0 +1,106 @@
+use std::{
+    io::IsTerminal,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use owo_colors::Style;
+
+/// The policy set with [`ColorPolicy::set_global`], `UNSET` until then
+static GLOBAL: AtomicU8 = AtomicU8::new(UNSET);
+const UNSET: u8 = 0;
+const ALWAYS: u8 = 1;
+const NEVER: u8 = 2;
+
+/// Decides whether terminal output should be colored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorPolicy {
+    Always,
+    Never,
+}
+
+impl ColorPolicy {
+    /// Colors are enabled unless the `NO_COLOR` environment variable is set to a non-empty value
+    pub fn from_env() -> Self {
+        match std::env::var_os("NO_COLOR") {
+            Some(value) if !value.is_empty() => ColorPolicy::Never,
+            _ => ColorPolicy::Always,
+        }
+    }
+
+    /// Like [`ColorPolicy::from_env`], but colors are also disabled when `stream` isn't a terminal
+    pub fn for_stream(stream: &impl IsTerminal) -> Self {
+        match stream.is_terminal() {
+            true => Self::from_env(),
+            false => ColorPolicy::Never,
+        }
+    }
+
+    /// Makes this the policy of output that isn't given one explicitly, like error reports,
+    /// the trace log and new disassemblers
+    pub fn set_global(self) {
+        let policy = match self {
+            ColorPolicy::Always => ALWAYS,
+            ColorPolicy::Never => NEVER,
+        };
+        GLOBAL.store(policy, Ordering::Relaxed);
+    }
+
+    /// The policy from [`ColorPolicy::set_global`], [`ColorPolicy::from_env`] if none was set
+    pub fn global() -> Self {
+        match GLOBAL.load(Ordering::Relaxed) {
+            ALWAYS => ColorPolicy::Always,
+            NEVER => ColorPolicy::Never,
+            _ => Self::from_env(),
+        }
+    }
+
+    pub fn enabled(self) -> bool {
+        self == ColorPolicy::Always
+    }
+
+    /// Returns `style` if colors are enabled and a plain style otherwise
+    pub fn style(self, style: Style) -> Style {
+        if self.enabled() {
+            style
+        } else {
+            Style::new()
+        }
+    }
+}
+
+impl Default for ColorPolicy {
+    fn default() -> Self {
+        Self::from_env()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_not_colored() {
+        let path = std::env::temp_dir().join(format!("game_lang_{}_color", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+
+        assert_eq!(ColorPolicy::for_stream(&file), ColorPolicy::Never);
+        assert_eq!(ColorPolicy::Never.style(Style::new().red()), Style::new());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn disassembly_follows_policy() {
+        use crate::bytecode::{chunk::CodeChunk, object::ObjectHeap};
+
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        crate::compiler::parser::Parser::parse_source("print 1 + 2;", &mut code, &mut heap).unwrap();
+        let dump = |policy: ColorPolicy| code.dissasemble().with_heap(&heap).with_color(policy.enabled()).to_string();
+
+        assert!(dump(ColorPolicy::Always).contains('\x1b'));
+        assert!(!dump(ColorPolicy::Never).contains('\x1b'));
+        // Whatever the global policy is, the bytecode layer is plain unless asked for colors
+        assert_eq!(code.dissasemble().with_heap(&heap).to_string(), dump(ColorPolicy::Never));
+    }
+}
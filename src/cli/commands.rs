@@ -0,0 +1,126 @@
+use std::io::{self, Write};
+
+use crate::{
+    bytecode::{chunk::CodeChunk, object::ObjectKind},
+    compiler::parser::Parser,
+};
+
+use super::{reporter::report_parsing_error, session::Session};
+
+/// Printed for unknown commands
+pub const HELP: &str = "Commands:
+  :dump <code>  show the bytecode of <code> without running it
+  :globals      list the global variables
+  :mem          show how many objects are alive and the memory they use
+  :quit         exit the REPL";
+
+/// A line the REPL handles itself instead of running it, written as `:name`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'line> {
+    Dump(&'line str),
+    Globals,
+    Mem,
+    Quit,
+    Unknown(&'line str),
+}
+
+impl<'line> Command<'line> {
+    /// Returns `None` for lines of code
+    pub fn parse(line: &'line str) -> Option<Self> {
+        let rest = line.trim().strip_prefix(':')?;
+        let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let command = match name {
+            "dump" => Command::Dump(args.trim()),
+            "globals" => Command::Globals,
+            "mem" => Command::Mem,
+            "quit" | "q" => Command::Quit,
+            _ => Command::Unknown(name),
+        };
+        Some(command)
+    }
+}
+
+impl Session {
+    /// Runs a REPL command writing its output to `out`, returns `false` once the REPL should exit
+    pub fn command(&mut self, command: Command, out: &mut impl Write) -> io::Result<bool> {
+        match command {
+            Command::Dump(source) => {
+                let mut code = CodeChunk::new();
+                match Parser::parse_source(source, &mut code, self.heap_mut()) {
+                    Ok(()) => write!(out, "{}", code.dissasemble().with_heap(self.heap()).with_color(self.color()))?,
+                    Err(errors) => {
+                        for err in errors {
+                            report_parsing_error("REPL", source, err);
+                        }
+                    }
+                }
+            }
+            Command::Globals => {
+                let heap = self.heap();
+                let mut globals: Vec<_> = heap
+                    .globals()
+                    .filter_map(|(key, value)| match &heap.get_object(key).ok()?.kind {
+                        ObjectKind::String(name) => Some((name, value)),
+                        _ => None,
+                    })
+                    .collect();
+                globals.sort_unstable_by_key(|(name, _)| *name);
+                for (name, value) in globals {
+                    writeln!(out, "{name} = {}", value.print_with_heap(heap).quoted())?;
+                }
+            }
+            Command::Mem => {
+                let heap = self.heap();
+                writeln!(out, "{} live objects", heap.live_count())?;
+                writeln!(out, "{} bytes of dynamic memory", heap.dynamic_memory_used())?;
+            }
+            Command::Quit => return Ok(false),
+            Command::Unknown(name) => writeln!(out, "Unknown command :{name}\n{HELP}")?,
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_command(session: &mut Session, line: &str) -> (bool, String) {
+        let mut out = Vec::new();
+        let command = Command::parse(line).unwrap();
+        let keep_going = session.command(command, &mut out).unwrap();
+        (keep_going, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(Command::parse("print 1;"), None);
+        assert_eq!(Command::parse(" :dump  1 + 2; "), Some(Command::Dump("1 + 2;")));
+        assert_eq!(Command::parse(":globals"), Some(Command::Globals));
+        assert_eq!(Command::parse(":quit"), Some(Command::Quit));
+        assert_eq!(Command::parse(":nope x"), Some(Command::Unknown("nope")));
+    }
+
+    #[test]
+    fn commands_test() {
+        let mut session = Session::new();
+        assert!(session.eval("REPL", r#"let name = "game"; let count = 2;"#));
+
+        let (_, globals) = run_command(&mut session, ":globals");
+        assert!(globals.contains("count = 2\n"), "{globals}");
+        assert!(globals.contains("name = \"game\"\n"), "{globals}");
+
+        let (_, dump) = run_command(&mut session, ":dump let unused = 1;");
+        assert!(dump.contains("DEF GLOBAL"), "{dump}");
+        let (_, globals) = run_command(&mut session, ":globals");
+        assert!(!globals.contains("unused"), "dumped code was run");
+
+        let (_, mem) = run_command(&mut session, ":mem");
+        assert!(mem.contains("live objects"));
+
+        let (keep_going, help) = run_command(&mut session, ":help");
+        assert!(keep_going);
+        assert!(help.contains(HELP));
+        assert_eq!(run_command(&mut session, ":quit"), (false, String::new()));
+    }
+}
@@ -0,0 +1,176 @@
+use std::io::{BufRead, Write};
+
+use crate::bytecode::vm::{RuntimeError, RuntimeStep, VM};
+
+/// Printed for unknown commands
+pub const HELP: &str = "Commands:
+  s, <enter>  execute the next instruction
+  c           continue until a breakpoint or the end
+  b <offset>  break before the instruction at <offset>
+  p <global>  print a global variable
+  q           stop debugging";
+
+/// A line typed at the debugger prompt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugCommand<'line> {
+    Step,
+    Continue,
+    Break(usize),
+    Print(&'line str),
+    Quit,
+    Unknown(&'line str),
+}
+
+impl<'line> DebugCommand<'line> {
+    pub fn parse(line: &'line str) -> Self {
+        let line = line.trim();
+        let (name, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let arg = arg.trim();
+        match name {
+            "" | "s" => DebugCommand::Step,
+            "c" => DebugCommand::Continue,
+            "b" => arg.parse().map_or(DebugCommand::Unknown(line), DebugCommand::Break),
+            "p" if !arg.is_empty() => DebugCommand::Print(arg),
+            "q" => DebugCommand::Quit,
+            _ => DebugCommand::Unknown(line),
+        }
+    }
+}
+
+/// Runs the VM one instruction at a time, reading commands from `input`
+#[derive(Debug)]
+pub struct Debugger {
+    /// Offsets into the chunk that is executing when they are reached
+    breakpoints: Vec<usize>,
+    color: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            color: true,
+        }
+    }
+
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Debugs `vm` until its code finishes, `input` runs out or the user quits
+    pub fn run(&mut self, vm: &mut VM, input: impl BufRead, out: &mut impl Write) -> Result<(), RuntimeError> {
+        let mut lines = input.lines();
+        self.show(vm, out).map_err(|_| RuntimeError::OutputError)?;
+        loop {
+            let Some(Ok(line)) = lines.next() else {
+                return Ok(());
+            };
+            match DebugCommand::parse(&line) {
+                DebugCommand::Step => {
+                    if let RuntimeStep::Halt = vm.step()? {
+                        return Ok(());
+                    }
+                    self.show(vm, out).map_err(|_| RuntimeError::OutputError)?;
+                }
+                DebugCommand::Continue => {
+                    loop {
+                        if let RuntimeStep::Halt = vm.step()? {
+                            return Ok(());
+                        }
+                        if self.breakpoints.contains(&vm.pc()) {
+                            break;
+                        }
+                    }
+                    self.show(vm, out).map_err(|_| RuntimeError::OutputError)?;
+                }
+                DebugCommand::Break(offset) => {
+                    if !self.breakpoints.contains(&offset) {
+                        self.breakpoints.push(offset);
+                    }
+                }
+                DebugCommand::Print(name) => {
+                    // A typo shouldn't end the session, so lookup errors are only shown
+                    let res = match vm.global(name) {
+                        Ok(value) => writeln!(out, "{name} = {}", value.print_with_heap(vm.heap()).quoted()),
+                        Err(err) => writeln!(out, "{err}"),
+                    };
+                    res.map_err(|_| RuntimeError::OutputError)?;
+                }
+                DebugCommand::Quit => return Ok(()),
+                DebugCommand::Unknown(line) => {
+                    writeln!(out, "Unknown command '{line}'\n{HELP}").map_err(|_| RuntimeError::OutputError)?
+                }
+            }
+        }
+    }
+
+    /// The instruction about to run and the stack
+    fn show(&self, vm: &VM, out: &mut impl Write) -> std::io::Result<()> {
+        let instruction = vm.chunk().dissasemble().at(vm.pc()).with_heap(vm.heap()).with_color(self.color);
+        writeln!(out, "{instruction}{:12} {}", "", vm.print_stack().with_color(self.color))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bytecode::{chunk::CodeChunk, object::ObjectHeap},
+        compiler::parser::Parser,
+    };
+
+    use super::*;
+
+    const SOURCE: &str = "let a = 1; let b = a + 2; print b;";
+
+    /// Debugs `SOURCE` with the given commands, returns what the program printed and the debugger output
+    fn debug(commands: &str) -> (String, String) {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        Parser::parse_source(SOURCE, &mut code, &mut heap).unwrap();
+
+        let mut printed = Vec::new();
+        let mut out = Vec::new();
+        let mut vm = VM::init_with_output(&code, &mut heap, &mut printed);
+        Debugger::new().with_color(false).run(&mut vm, commands.as_bytes(), &mut out).unwrap();
+        drop(vm);
+        (String::from_utf8(printed).unwrap(), String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(DebugCommand::parse(""), DebugCommand::Step);
+        assert_eq!(DebugCommand::parse("s"), DebugCommand::Step);
+        assert_eq!(DebugCommand::parse(" c "), DebugCommand::Continue);
+        assert_eq!(DebugCommand::parse("b 12"), DebugCommand::Break(12));
+        assert_eq!(DebugCommand::parse("b x"), DebugCommand::Unknown("b x"));
+        assert_eq!(DebugCommand::parse("p score"), DebugCommand::Print("score"));
+        assert_eq!(DebugCommand::parse("p"), DebugCommand::Unknown("p"));
+    }
+
+    #[test]
+    fn steps_and_prints_globals() {
+        // CONSTANT and DEF GLOBAL define `a`, the program stops there when the input runs out
+        let (printed, out) = debug("s\n\np a\np missing\n");
+        assert_eq!(printed, "");
+        assert!(out.contains("a = 1"), "{out}");
+        assert!(out.contains("Undefined global variable 'missing'"), "{out}");
+        assert!(out.contains("DEF GLOBAL"), "{out}");
+    }
+
+    #[test]
+    fn continues_to_breakpoints() {
+        let (printed, out) = debug("b 4\nc\np a\nc\n");
+        assert_eq!(printed, "3\n");
+        assert!(out.contains("a = 1"), "{out}");
+        // Stopped once at the breakpoint before running to the end
+        assert_eq!(out.matches("0004").count(), 1, "{out}");
+        assert!(!out.contains('\x1b'), "{out}");
+    }
+}
@@ -0,0 +1,216 @@
+use std::{borrow::Cow, fmt::Write, ops::Range};
+
+use owo_colors::{OwoColorize, Style};
+use rustyline::{
+    completion::Completer, highlight::Highlighter, hint::Hinter, validate::Validator, Helper,
+};
+
+use crate::compiler::lexer::{Lexer, Token};
+
+use super::color::ColorPolicy;
+
+/// Lines longer than this are left as they are, so pasting something huge doesn't lex on every keystroke
+pub const MAX_HIGHLIGHT_LEN: usize = 4096;
+
+/// Syntax highlighting for the REPL, driven by the compiler lexer
+#[derive(Debug, Clone, Copy)]
+pub struct ReplHighlighter {
+    color: ColorPolicy,
+}
+
+impl ReplHighlighter {
+    pub fn new(color: ColorPolicy) -> Self {
+        Self { color }
+    }
+
+    fn token_style(token: Token, slice: &str) -> Style {
+        match token {
+            Token::Let
+            | Token::Mut
+            | Token::If
+            | Token::Else
+            | Token::Elif
+            | Token::For
+            | Token::While
+            | Token::Break
+            | Token::Continue
+            | Token::Return
+            | Token::Fn
+            | Token::Defer
+            | Token::Yield
+            | Token::Class
+            | Token::Super
+            | Token::This
+            | Token::Print
+            | Token::Write
+            | Token::Assert
+            | Token::Is => Style::new().magenta().bold(),
+            Token::And | Token::Or | Token::Not if slice.chars().all(char::is_alphabetic) => {
+                Style::new().magenta().bold()
+            }
+            Token::True | Token::False | Token::Nil => Style::new().yellow(),
+            Token::Number | Token::Integer => Style::new().cyan(),
+            Token::String => Style::new().green(),
+            Token::Error => Style::new().red(),
+            _ => Style::new(),
+        }
+    }
+
+    fn write_gap(&self, out: &mut String, gap: &str) {
+        let comment_style = self.color.style(Style::new().bright_black().italic());
+        let mut rest = gap;
+        while let Some(start) = rest.find("//") {
+            let end = rest[start..].find('\n').map_or(rest.len(), |end| start + end);
+            out.push_str(&rest[..start]);
+            let _ = write!(out, "{}", (&rest[start..end]).style(comment_style));
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+    }
+
+    /// Finds the bracket under or right before the cursor together with its matching pair
+    fn matching_brackets(tokens: &[(Token, Range<usize>)], pos: usize) -> Option<(usize, usize)> {
+        let brackets = || {
+            tokens
+                .iter()
+                .enumerate()
+                .filter(|(_, (t, _))| Self::bracket_direction(*t).is_some())
+        };
+        let (idx, &(token, _)) = brackets()
+            .find(|(_, (_, span))| span.start == pos)
+            .or_else(|| brackets().find(|(_, (_, span))| span.end == pos))?;
+        let (open, close, forward) = Self::bracket_direction(token)?;
+
+        let mut depth = 0usize;
+        let candidates: Box<dyn Iterator<Item = usize>> = if forward {
+            Box::new(idx + 1..tokens.len())
+        } else {
+            Box::new((0..idx).rev())
+        };
+        for other in candidates {
+            let other_token = tokens[other].0;
+            if other_token == token {
+                depth += 1;
+            } else if (forward && other_token == close) || (!forward && other_token == open) {
+                if depth == 0 {
+                    return Some((idx, other));
+                }
+                depth -= 1;
+            }
+        }
+        None
+    }
+
+    /// Returns the opening and closing token of a bracket pair and whether `token` opens it
+    fn bracket_direction(token: Token) -> Option<(Token, Token, bool)> {
+        match token {
+            Token::ParenOpen => Some((Token::ParenOpen, Token::ParenClose, true)),
+            Token::ParenClose => Some((Token::ParenOpen, Token::ParenClose, false)),
+            Token::BraceOpen => Some((Token::BraceOpen, Token::BraceClose, true)),
+            Token::BraceClose => Some((Token::BraceOpen, Token::BraceClose, false)),
+            Token::BracketOpen => Some((Token::BracketOpen, Token::BracketClose, true)),
+            Token::BracketClose => Some((Token::BracketOpen, Token::BracketClose, false)),
+            _ => None,
+        }
+    }
+}
+
+impl Highlighter for ReplHighlighter {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        if !self.color.enabled() || line.len() > MAX_HIGHLIGHT_LEN {
+            return Cow::Borrowed(line);
+        }
+
+        let mut tokens = Vec::new();
+        let mut lexer = Lexer::lex(line);
+        while let Some(token) = lexer.next() {
+            tokens.push((token, lexer.span()));
+        }
+        let brackets = Self::matching_brackets(&tokens, pos);
+
+        let mut out = String::with_capacity(line.len() * 2);
+        let mut last_end = 0;
+        for (idx, (token, span)) in tokens.iter().enumerate() {
+            self.write_gap(&mut out, &line[last_end..span.start]);
+            let slice = &line[span.clone()];
+            let style = match brackets {
+                Some((a, b)) if idx == a || idx == b => Style::new().bold().underline(),
+                _ => Self::token_style(*token, slice),
+            };
+            let _ = write!(out, "{}", slice.style(style));
+            last_end = span.end;
+        }
+        self.write_gap(&mut out, &line[last_end..]);
+
+        Cow::Owned(out)
+    }
+
+    fn highlight_char(&self, line: &str, _pos: usize, _forced: bool) -> bool {
+        self.color.enabled() && line.len() <= MAX_HIGHLIGHT_LEN
+    }
+}
+
+impl Completer for ReplHighlighter {
+    type Candidate = String;
+}
+
+impl Hinter for ReplHighlighter {
+    type Hint = String;
+}
+
+impl Validator for ReplHighlighter {}
+
+impl Helper for ReplHighlighter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ESC: char = '\x1b';
+
+    #[test]
+    fn highlights_with_colors() {
+        let highlighter = ReplHighlighter::new(ColorPolicy::Always);
+        let line = r#"let x = 1; print "a" + x; // note"#;
+        let highlighted = highlighter.highlight(line, 0);
+
+        assert!(highlighted.contains(ESC));
+        assert!(highlighted.contains("// note"));
+        assert_ne!(highlighted, line);
+    }
+
+    #[test]
+    fn plain_without_colors() {
+        let highlighter = ReplHighlighter::new(ColorPolicy::Never);
+        let line = r#"let x = 1; print "a" + x; // note"#;
+        let highlighted = highlighter.highlight(line, 0);
+
+        assert!(!highlighted.contains(ESC));
+        assert_eq!(highlighted, line);
+    }
+
+    #[test]
+    fn skips_long_lines() {
+        let highlighter = ReplHighlighter::new(ColorPolicy::Always);
+        let line = "1 + ".repeat(MAX_HIGHLIGHT_LEN) + "1;";
+        let highlighted = highlighter.highlight(&line, 0);
+
+        assert!(!highlighted.contains(ESC));
+        assert!(!highlighter.highlight_char(&line, 0, false));
+    }
+
+    #[test]
+    fn matches_brackets() {
+        let line = "((1) + 2)";
+        let mut tokens = Vec::new();
+        let mut lexer = Lexer::lex(line);
+        while let Some(token) = lexer.next() {
+            tokens.push((token, lexer.span()));
+        }
+
+        assert_eq!(ReplHighlighter::matching_brackets(&tokens, 0), Some((0, 6)));
+        assert_eq!(ReplHighlighter::matching_brackets(&tokens, 9), Some((6, 0)));
+        assert_eq!(ReplHighlighter::matching_brackets(&tokens, 1), Some((1, 3)));
+        assert_eq!(ReplHighlighter::matching_brackets(&tokens, 5), None);
+    }
+}
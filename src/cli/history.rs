@@ -0,0 +1,61 @@
+use std::path::{Path, PathBuf};
+
+use rustyline::history::History;
+
+/// Name of the REPL history file looked up in the home directory
+pub const HISTORY_FILE_NAME: &str = ".gamelang_history";
+
+/// `~/.gamelang_history`, if the home directory is known
+pub fn default_history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE_NAME))
+}
+
+/// Loads the history saved at `path`, there is nothing to load on the first run so a missing file is fine
+pub fn load_history(history: &mut impl History, path: &Path) {
+    if !path.exists() {
+        return;
+    }
+    if let Err(err) = history.load(path) {
+        log::warn!("Can't load REPL history from {}: {}", path.display(), err);
+    }
+}
+
+/// Writes the whole history to `path`, creating the file if it's missing
+pub fn save_history(history: &mut impl History, path: &Path) {
+    if let Err(err) = history.save(path) {
+        log::warn!("Can't save REPL history to {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustyline::history::{DefaultHistory, SearchDirection};
+
+    use super::*;
+
+    fn entries(history: &DefaultHistory) -> Vec<String> {
+        (0..history.len())
+            .filter_map(|idx| history.get(idx, SearchDirection::Forward).ok().flatten())
+            .map(|result| result.entry.into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn history_survives_sessions() {
+        let path = std::env::temp_dir().join(format!("game_lang_{}_history", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut first = DefaultHistory::new();
+        load_history(&mut first, &path);
+        assert_eq!(first.len(), 0);
+        first.add("let x = 1;").unwrap();
+        first.add("print x;").unwrap();
+        save_history(&mut first, &path);
+
+        let mut second = DefaultHistory::new();
+        load_history(&mut second, &path);
+        assert_eq!(entries(&second), ["let x = 1;", "print x;"]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+/// Maps byte offsets of a source to lines and columns
+#[derive(Debug, Clone)]
+pub struct LineMap<'source> {
+    source: &'source str,
+    /// Byte offset where every line starts, the first line starts at 0
+    line_starts: Vec<usize>,
+}
+
+impl<'source> LineMap<'source> {
+    pub fn new(source: &'source str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        Self { source, line_starts }
+    }
+
+    /// 1-based line and column of `offset`, columns count characters, not bytes.
+    ///
+    /// Offsets past the end are clamped to the end of the source.
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.source.len());
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let column = self.source[line_start..]
+            .char_indices()
+            .take_while(|(idx, _)| line_start + idx < offset)
+            .count();
+        (line + 1, column + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_offsets() {
+        let source = "let a = 1;\nprint a;\n\nprint \"é\" + b;";
+        let map = LineMap::new(source);
+
+        assert_eq!(map.location(0), (1, 1));
+        assert_eq!(map.location(4), (1, 5));
+        // The newline still belongs to the line it ends
+        assert_eq!(map.location(10), (1, 11));
+        assert_eq!(map.location(11), (2, 1));
+        assert_eq!(map.location(20), (3, 1));
+        assert_eq!(map.location(source.find('b').unwrap()), (4, 13));
+        assert_eq!(map.location(source.len() + 5), (4, 15));
+    }
+}
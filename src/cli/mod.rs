@@ -1 +1,8 @@
-pub mod reporter;
\ No newline at end of file
+pub mod color;
+pub mod commands;
+pub mod debugger;
+pub mod highlighter;
+pub mod history;
+pub mod line_map;
+pub mod reporter;
+pub mod session;
\ No newline at end of file
@@ -1,34 +1,181 @@
-use std::ops::Range;
+use std::{
+    fmt::Write,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use ariadne::{Color, Config, Label, Report, ReportKind, Source};
-use owo_colors::OwoColorize;
+use owo_colors::{OwoColorize, Style};
 
-use crate::{bytecode::vm::RuntimeError, compiler::parser::ParsingError};
+use crate::{
+    bytecode::vm::{RuntimeError, StackFrame},
+    compiler::parser::ParsingError,
+};
+
+use super::{color::ColorPolicy, line_map::LineMap};
+
+/// Tab stops used when none is set with [`set_tab_width`]
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
+static TAB_WIDTH: AtomicUsize = AtomicUsize::new(DEFAULT_TAB_WIDTH);
+
+/// Columns between the tab stops of reported source lines, so carets line up under tab-indented code
+pub fn set_tab_width(width: usize) {
+    TAB_WIDTH.store(width.max(1), Ordering::Relaxed);
+}
+
+/// Reports are written to stderr, so they follow the global color policy
+fn report_config() -> Config {
+    Config::default()
+        .with_compact(true)
+        .with_color(ColorPolicy::global().enabled())
+        .with_tab_width(TAB_WIDTH.load(Ordering::Relaxed))
+}
+
+fn styled(style: Style) -> Style {
+    ColorPolicy::global().style(style)
+}
 
 pub fn report_parsing_error(name: &str, src: &str, err: ParsingError) {
+    parsing_error_report(name, src, err, report_config())
+        .eprint((name, Source::from(src)))
+        .unwrap()
+}
+
+fn parsing_error_report<'name>(
+    name: &'name str,
+    src: &str,
+    err: ParsingError,
+    config: Config,
+) -> Report<'name, (&'name str, Range<usize>)> {
+    let message = with_location(src, err.span.start, &err.msg);
     Report::build(ReportKind::Error, name, err.span.start)
-        .with_config(Config::default().with_compact(true))
-        .with_message(err.msg)
+        .with_config(config)
+        .with_message(message)
         .with_label(
             Label::new((name, err.span))
-                .with_message("Here".red())
+                .with_message("Here".style(styled(Style::new().red())))
                 .with_color(Color::Red),
         )
         .finish()
-        .print((name, Source::from(src)))
-        .unwrap()
 }
 
-pub fn report_runtime_error(name: &str, src: &str, err: RuntimeError, span: Range<usize>) {
-    Report::build(ReportKind::Error, name, span.start)
-        .with_config(Config::default().with_compact(true))
-        .with_message(format!("{:?}", err))
+/// Reports every error and ends with a summary line counting them
+pub fn report_parsing_errors(name: &str, src: &str, errors: Vec<ParsingError>) {
+    let count = errors.len();
+    for err in errors {
+        report_parsing_error(name, src, err);
+    }
+    eprintln!("{}", aborting_summary(count));
+}
+
+pub fn aborting_summary(count: usize) -> String {
+    let error = "error".style(styled(Style::new().red().bold()));
+    match count {
+        1 => format!("{error}: aborting due to 1 previous error"),
+        _ => format!("{error}: aborting due to {count} previous errors"),
+    }
+}
+
+/// Reports `err` at the innermost frame of `trace`, the calls leading to it get their own labels
+pub fn report_runtime_error(name: &str, src: &str, err: RuntimeError, trace: &[StackFrame]) {
+    // The span of an internal error would point at a script that isn't at fault
+    let Some((failed, callers)) = trace.split_first().filter(|_| !err.is_internal()) else {
+        eprintln!("Error: {err}");
+        return;
+    };
+    let mut report = Report::build(ReportKind::Error, name, failed.span.start)
+        .with_config(report_config())
+        .with_message(with_location(src, failed.span.start, &err.to_string()))
         .with_label(
-            Label::new((name, span))
-                .with_message("Here".red())
+            Label::new((name, failed.span.clone()))
+                .with_message("Here".style(styled(Style::new().red())))
                 .with_color(Color::Red),
-        )
-        .finish()
-        .print((name, Source::from(src)))
-        .unwrap()
+        );
+    for (callee, caller) in trace.iter().zip(callers) {
+        report = report.with_label(
+            Label::new((name, caller.span.clone()))
+                .with_message(format!("{} called here", frame_name(callee)).style(styled(Style::new().yellow())))
+                .with_color(Color::Yellow),
+        );
+    }
+    if !callers.is_empty() {
+        report = report.with_note(backtrace(src, trace));
+    }
+    report.finish().eprint((name, Source::from(src))).unwrap()
+}
+
+/// `message at line L, column C`
+pub fn with_location(src: &str, offset: usize, message: &str) -> String {
+    let (line, column) = LineMap::new(src).location(offset);
+    format!("{message} at line {line}, column {column}")
+}
+
+fn frame_name(frame: &StackFrame) -> &str {
+    frame.function.as_deref().unwrap_or("<script>")
+}
+
+/// One line per frame, innermost first, located in `src` like the error message
+pub fn backtrace(src: &str, trace: &[StackFrame]) -> String {
+    let lines = LineMap::new(src);
+    let mut out = String::from("Stack trace:");
+    for frame in trace {
+        let (line, column) = lines.location(frame.span.start);
+        let _ = write!(out, "\n  at {} (line {line}, column {column})", frame_name(frame));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bytecode::{chunk::CodeChunk, object::ObjectHeap},
+        compiler::parser::Parser,
+    };
+
+    use super::*;
+
+    /// The plain report of the first parsing error in `src`
+    fn parsing_report(src: &str, tab_width: usize) -> String {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let err = Parser::parse_source(src, &mut code, &mut heap).unwrap_err().remove(0);
+        let config = report_config().with_color(false).with_tab_width(tab_width);
+        let mut out = Vec::new();
+        parsing_error_report("test", src, err, config).write(("test", Source::from(src)), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Display column of `pattern` in the first line of `report` containing it
+    fn column_of(report: &str, pattern: &str) -> usize {
+        let line = report.lines().find(|line| line.contains(pattern)).unwrap();
+        line[..line.find(pattern).unwrap()].chars().count()
+    }
+
+    #[test]
+    fn tab_indented_caret_test() {
+        let src = "{\n\t\tlet x = \"a\" - 1;\n}";
+        let mut indents = Vec::new();
+        for tab_width in [2, 4, 8] {
+            let report = parsing_report(src, tab_width);
+            // The tabs are expanded, so the source line and the caret use the same columns
+            assert!(!report.contains('\t'), "{report}");
+            assert_eq!(column_of(&report, "- 1"), column_of(&report, "╰"), "{report}");
+            indents.push(column_of(&report, "let"));
+        }
+        assert!(indents.is_sorted() && indents[0] < indents[2], "{indents:?}");
+    }
+
+    #[test]
+    fn backtrace_test() {
+        let src = "fn init() {\n  return 1 / nil;\n}\ninit();";
+        let trace = [
+            StackFrame { function: Some("init".into()), span: 21..28 },
+            StackFrame { function: None, span: 32..38 },
+        ];
+        assert_eq!(
+            backtrace(src, &trace),
+            "Stack trace:\n  at init (line 2, column 10)\n  at <script> (line 4, column 1)"
+        );
+    }
 }
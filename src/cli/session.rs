@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    bytecode::{chunk::CodeChunk, object::ObjectHeap, vm::VM},
+    compiler::parser::Parser,
+};
+
+use super::{
+    color::ColorPolicy,
+    reporter::{report_parsing_error, report_runtime_error},
+};
+
+/// Name of the startup script looked up in the home directory
+pub const RC_FILE_NAME: &str = ".gamelangrc.gl";
+
+/// State shared by everything evaluated in one interactive session
+#[derive(Debug, Default)]
+pub struct Session {
+    heap: ObjectHeap,
+    /// Instruction limit for every evaluation, so a stuck loop doesn't hang the REPL
+    max_steps: Option<u64>,
+    /// Whether command output like `:dump` gets colors, the log follows [`ColorPolicy::global`]
+    color: bool,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            heap: ObjectHeap::new(),
+            max_steps: None,
+            color: false,
+        }
+    }
+
+    pub fn with_max_steps(mut self, max_steps: Option<u64>) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Compiles and runs `source` in the session, reporting errors against `name`.
+    ///
+    /// Returns `false` if there were any errors.
+    pub fn eval(&mut self, name: &str, source: &str) -> bool {
+        let mut code = CodeChunk::new();
+        if let Err(errors) = Parser::parse_source(source, &mut code, &mut self.heap) {
+            for err in errors {
+                report_parsing_error(name, source, err);
+            }
+            return false;
+        }
+
+        let log_color = ColorPolicy::global().enabled();
+        log::info!("\n{}", code.dissasemble().with_heap(&self.heap).with_color(log_color));
+
+        let mut vm = VM::init(&code, &mut self.heap).with_trace_color(log_color);
+        let res = match self.max_steps {
+            Some(max_steps) => vm.run_with_limit(max_steps),
+            None => vm.run(),
+        };
+        if let Err(err) = res {
+            report_runtime_error(name, source, err, &vm.capture_stack_trace());
+            return false;
+        }
+        true
+    }
+
+    /// Runs a whole file in the session, errors are reported against its path
+    pub fn eval_file(&mut self, path: &Path) -> bool {
+        match std::fs::read_to_string(path) {
+            Ok(source) => self.eval(&path.to_string_lossy(), &source),
+            Err(err) => {
+                eprintln!("Can't read {}: {}", path.display(), err);
+                false
+            }
+        }
+    }
+
+    pub fn heap(&self) -> &ObjectHeap {
+        &self.heap
+    }
+
+    pub fn color(&self) -> bool {
+        self.color
+    }
+
+    pub fn heap_mut(&mut self) -> &mut ObjectHeap {
+        &mut self.heap
+    }
+}
+
+/// `~/.gamelangrc.gl`, if the home directory is known
+pub fn default_rc_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(RC_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use ecow::EcoString;
+
+    use crate::bytecode::value::{Value, ValueKind};
+
+    use super::*;
+
+    fn temp_rc(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("game_lang_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn global(session: &mut Session, name: &str) -> Option<Value> {
+        let key = session.heap_mut().intern_string(EcoString::from(name));
+        session.heap().get_global(key).ok()
+    }
+
+    #[test]
+    fn rc_defines_helpers() {
+        let rc = temp_rc("helpers.gl", "fn double(x) { return x * 2; } let base = 21;");
+        let mut session = Session::new();
+
+        assert!(session.eval_file(&rc));
+        assert!(session.eval("REPL", "let answer = double(base);"));
+        assert!(matches!(global(&mut session, "answer").map(|value| value.kind()), Some(ValueKind::Int(42))));
+
+        std::fs::remove_file(rc).unwrap();
+    }
+
+    #[test]
+    fn step_limit_stops_loops() {
+        let mut session = Session::new().with_max_steps(Some(10_000));
+
+        assert!(!session.eval("REPL", "while true {}"));
+        assert!(session.eval("REPL", "let x = 1;"));
+        assert!(matches!(global(&mut session, "x").map(|value| value.kind()), Some(ValueKind::Int(1))));
+    }
+
+    #[test]
+    fn broken_rc_keeps_session_usable() {
+        let rc = temp_rc("broken.gl", "let = ;");
+        let mut session = Session::new();
+
+        assert!(!session.eval_file(&rc));
+        assert!(session.eval("REPL", "let x = 1 + 2;"));
+        assert!(matches!(global(&mut session, "x").map(|value| value.kind()), Some(ValueKind::Int(3))));
+
+        std::fs::remove_file(rc).unwrap();
+    }
+}
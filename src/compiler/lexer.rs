@@ -11,22 +11,28 @@ pub struct Lexer<'source> {
 #[rustfmt::skip]
 #[derive(Debug, Clone, Copy, PartialEq, Logos, strum_macros::Display)]
 #[logos(skip r"[ \t\n\f]+")]
+#[logos(skip r"//[^\n]*")]
 pub enum Token {
     #[token("(")] ParenOpen, #[token(")")] ParenClose,
     #[token("{")] BraceOpen, #[token("}")] BraceClose,
-    #[token("let")] Let, #[token("if")] If, #[token("else")] Else,
+    #[token("[")] BracketOpen, #[token("]")] BracketClose,
+    #[token("let")] Let, #[token("mut")] Mut, #[token("if")] If, #[token("else")] Else, #[token("elif")] Elif,
     #[token("for")] For, #[token("while")] While,
-    #[token("return")] Return, #[token("fn")] Fn, 
+    #[token("break")] Break, #[token("continue")] Continue,
+    #[token("return")] Return, #[token("fn")] Fn, #[token("defer")] Defer, #[token("yield")] Yield,
     #[token("class")] Class, #[token("super")] Super, #[token("this")] This,
-    #[token("print")] Print,
+    #[token("print")] Print, #[token("write")] Write, #[token("assert")] Assert, #[token("is")] Is,
     #[token("=")] Assign,
+    #[token("+=")] AddAssign, #[token("-=")] SubAssign,
+    #[token("*=")] MulAssign, #[token("/=")] DivAssign, #[token("%=")] RemAssign,
     #[token("+")] Add, #[token("-")] Sub,
-    #[token("*")] Mul, #[token("/")] Div, #[token("%")] Rem, 
+    #[token("*")] Mul, #[token("**")] Pow, #[token("/")] Div, #[token("%")] Rem, 
     #[token("==")] Eq, #[token("!=")] Neq,
     #[token(">")] Gr, #[token("<")] Le, #[token(">=")] Geq, #[token("<=")] Leq,
     #[token("&&")] #[token("and")] And, #[token("||")] #[token("or")] Or, #[token("!")] #[token("not")] Not,
     #[token(";")] Semicolon, #[token(".")] Dot, #[token(",")] Comma,
-    #[regex(r"[0-9]+\.?[0-9]*")] Number,
+    #[token("?")] Question, #[token(":")] Colon,
+    #[regex(r"[0-9]+\.[0-9]*")] Number, #[regex(r"[0-9]+")] Integer,
     #[regex(r"\p{Alphabetic}(\p{Alphabetic}|\d|_)*")] Identifier,
     #[regex(r#""[^"]*""#)] String,
     #[token("true")] True, #[token("false")] False,
@@ -42,6 +48,20 @@ impl<'source> Lexer<'source> {
         }
     }
 
+    /// Lexes `source` starting at the byte offset `start`, spans stay relative to the whole source
+    pub fn lex_at(source: &'source str, start: usize) -> Self {
+        let mut inner = Token::lexer(source);
+        inner.bump(start);
+        Self {
+            inner,
+            peeked: None,
+        }
+    }
+
+    pub fn source(&self) -> &'source str {
+        self.inner.source()
+    }
+
     pub fn slice(&self) -> &str {
         self.inner.slice()
     }
@@ -92,6 +112,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn operator_spacing_test() {
+        use Token::*;
+
+        let cases: &[(&str, &[Token])] = &[
+            (">=", &[Geq]),
+            ("> =", &[Gr, Assign]),
+            ("<=", &[Leq]),
+            ("< =", &[Le, Assign]),
+            ("==", &[Eq]),
+            ("= =", &[Assign, Assign]),
+            ("!=", &[Neq]),
+            ("! =", &[Not, Assign]),
+            ("===", &[Eq, Assign]),
+            ("!==", &[Neq, Assign]),
+            ("a>=b", &[Identifier, Geq, Identifier]),
+            ("**", &[Pow]),
+            ("* *", &[Mul, Mul]),
+            ("***", &[Pow, Mul]),
+        ];
+        for (source, expected) in cases {
+            let tokens: Vec<Token> = Lexer::lex(source).collect();
+            assert_eq!(&tokens, expected, "lexing {source:?}");
+        }
+    }
+
     #[test]
     fn peek_test() {
         let mut lex = Lexer::lex(r#"arg bar 70.9 % $$"#);
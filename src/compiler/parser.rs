@@ -1,9 +1,17 @@
-use std::ops::Range;
+use std::{mem, ops::Range, rc::Rc};
 
+use ahash::AHashSet;
 use ecow::EcoString;
 use log::warn;
 
-use crate::bytecode::{chunk::CodeChunk, object::ObjectHeap, opcodes::*, value::Value};
+use crate::bytecode::{
+    chunk::CodeChunk,
+    object::{Function, Object, ObjectHeap, ObjectKind},
+    opcodes::*,
+    optimize::optimize,
+    value::{Value, ValueError, ValueKind, TYPE_NAMES},
+    vm::RuntimeError,
+};
 
 use super::lexer::{Lexer, Token};
 
@@ -13,6 +21,39 @@ pub struct Parser<'source, 'code, 'heap> {
     code: &'code mut CodeChunk,
     heap: &'heap mut ObjectHeap,
     locals: Locals,
+    /// Innermost loop last
+    loops: Vec<LoopContext>,
+    /// Deferred statements of the enclosing scopes, innermost scope last
+    defers: Vec<Deferred>,
+    config: ParserConfig,
+    /// What the code being compiled belongs to
+    function_kind: FunctionKind,
+    /// Globals declared without `mut`, only tracked with [`ParserConfig::immutable_let`]
+    immutable_globals: AHashSet<EcoString>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParserConfig {
+    /// Only allow declarations at the top level, the program is then started by calling `main`
+    pub entry_main: bool,
+    /// `let` bindings can't be reassigned unless they are declared with `let mut`
+    pub immutable_let: bool,
+    /// Run [`optimize`] over the script and every function once they are compiled
+    pub optimize: bool,
+    /// The script may end with an expression without a `;`, its value is kept for [`VM::run_to_value`]
+    ///
+    /// [`VM::run_to_value`]: crate::bytecode::vm::VM::run_to_value
+    pub expression_mode: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    /// Slot 0 holds `this`
+    Method,
+    /// The `init` method, which returns `this`
+    Initializer,
 }
 
 #[derive(Debug, Clone)]
@@ -26,6 +67,15 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
         source: &'source str,
         code: &'code mut CodeChunk,
         heap: &'heap mut ObjectHeap,
+    ) -> Result<(), Vec<ParsingError>> {
+        Self::parse_source_with_config(source, code, heap, ParserConfig::default())
+    }
+
+    pub fn parse_source_with_config(
+        source: &'source str,
+        code: &'code mut CodeChunk,
+        heap: &'heap mut ObjectHeap,
+        config: ParserConfig,
     ) -> Result<(), Vec<ParsingError>> {
         let mut errors = Vec::new();
 
@@ -34,10 +84,23 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
             code,
             heap,
             locals: Locals::new(),
+            loops: Vec::new(),
+            defers: Vec::new(),
+            config,
+            function_kind: FunctionKind::Script,
+            immutable_globals: AHashSet::new(),
         };
 
-        while parser.lexer.peek().is_some() {
-            let res = parser.statement();
+        while let Some(peeked) = parser.lexer.peek() {
+            let res = if parser.config.entry_main && !matches!(peeked, Token::Fn | Token::Let | Token::Class) {
+                let err = parser.error_at_current(
+                    "Only declarations are allowed at the top level when starting from main"
+                        .to_string(),
+                );
+                parser.statement().and(Err(err))
+            } else {
+                parser.statement()
+            };
             if let Err(err) = res {
                 errors.push(err);
                 loop {
@@ -52,11 +115,16 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
                         }
                         Token::Class
                         | Token::Fn
+                        | Token::Defer
                         | Token::Let
                         | Token::For
                         | Token::If
                         | Token::While
+                        | Token::Break
+                        | Token::Continue
                         | Token::Print
+                        | Token::Write
+                        | Token::Assert
                         | Token::Return => {
                             break;
                         }
@@ -69,81 +137,218 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
         }
 
         if errors.is_empty() {
-            parser.code.push_code(OP_RETURN);
+            if let Err(err) = parser.emit_scope_defers() {
+                return Err(vec![err]);
+            }
+            parser.emit(OP_RETURN);
+            if config.optimize {
+                optimize(parser.code);
+            }
             Ok(())
         } else {
             Err(errors)
         }
     }
 
+    /// `true` if `source` only fails to parse because it ends too early, like an unclosed block.
+    ///
+    /// The REPL uses this to keep reading lines instead of reporting the error.
+    pub fn is_incomplete(source: &'source str, config: ParserConfig) -> bool {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        match Parser::parse_source_with_config(source, &mut code, &mut heap, config) {
+            Ok(()) => false,
+            Err(errors) => errors.first().is_some_and(|err| err.span.start >= source.len()),
+        }
+    }
+
     fn statement(&mut self) -> Result<(), ParsingError> {
         let Some(op) = self.lexer.peek() else {
             return Err(self.error_at_current("Expected statement".to_string()));
         };
 
         match op {
-            Token::Print => {
+            Token::Print | Token::Write => {
                 self.lexer.next();
+                let print_span = self.lexer.span();
                 self.expression()?;
-                self.code.push_code(OP_PRINT);
+                // `write` is `print` without the newline
+                let instr = if op == Token::Print { OP_PRINT } else { OP_WRITE };
+                self.code.push_code_spanned(instr, print_span);
                 self.consume_some(Token::Semicolon)?;
             }
-            Token::If => {
+            Token::Assert => {
                 self.lexer.next();
+                let start = self.code.size();
                 self.expression()?;
-                let els_jmp = self.emit_jump_partial(OP_JUMP_F);
-                self.code.push_code(OP_POP);
-                self.block()?;
-                let then_end_jmp = self.emit_jump_partial(OP_JUMP);
-
-                self.patch_jump(els_jmp, self.code.size())?;
-                self.code.push_code(OP_POP);
-
-                if self.lexer.peek() == Some(Token::Else) {
-                    self.lexer.next();
-                    self.block()?;
-                }
-                self.patch_jump(then_end_jmp, self.code.size())?;
+                let span = self.span_since(start);
+                let has_message = match self.lexer.peek() {
+                    Some(Token::Comma) => {
+                        self.lexer.next();
+                        self.expression()?;
+                        1
+                    }
+                    _ => 0,
+                };
+                self.code.push_code_spanned(OP_ASSERT, span);
+                self.code.push_code(has_message);
+                self.consume_some(Token::Semicolon)?;
+            }
+            Token::If => {
+                self.if_statement()?;
             }
             Token::While => {
                 self.lexer.next();
-                let loop_start = self.code.size(); 
+                if self.lexer.peek() == Some(Token::Let) {
+                    return self.while_let();
+                }
+                let loop_start = self.code.size();
                 self.expression()?;
                 let loop_end = self.emit_jump_partial(OP_JUMP_F);
-                self.code.push_code(OP_POP);
-                self.block()?;
+                self.emit(OP_POP);
+                self.enter_loop(loop_start);
+                let body = self.scoped_block();
+                let breaks = self.exit_loop();
+                body?;
                 self.emit_jump_full(OP_JUMP, loop_start)?;
                 self.patch_jump(loop_end, self.code.size())?;
-                self.code.push_code(OP_POP);
+                self.emit(OP_POP);
+                for jump in breaks {
+                    self.patch_jump(jump, self.code.size())?;
+                }
+            }
+            Token::Break | Token::Continue => {
+                self.lexer.next();
+                let Some(current) = self.loops.last() else {
+                    return Err(self.error_at_current(format!("Can't use '{}' outside of a loop", self.lexer.slice())));
+                };
+                let (start, locals, defers) = (current.start, current.locals, current.defers);
+                self.emit_defers(defers)?;
+                for _ in locals..self.locals.len() {
+                    self.emit(OP_POP);
+                }
+                if op == Token::Break {
+                    let jump = self.emit_jump_partial(OP_JUMP);
+                    if let Some(current) = self.loops.last_mut() {
+                        current.breaks.push(jump);
+                    }
+                } else {
+                    self.emit_jump_full(OP_JUMP, start)?;
+                }
+                self.consume_some(Token::Semicolon)?;
             }
             Token::Let => {
                 self.lexer.next();
+                let let_span = self.lexer.span();
+                let mutable = if self.lexer.peek() == Some(Token::Mut) {
+                    self.lexer.next();
+                    true
+                } else {
+                    !self.config.immutable_let
+                };
                 self.expect_some(Token::Identifier)?;
                 let identifier = self.identifier();
+                let identifier_span = self.lexer.span();
                 if self.lexer.peek() == Some(Token::Assign) {
                     self.lexer.next();
                     self.expression()?;
                 } else {
-                    self.code.push_code(OP_NIL);
+                    self.emit(OP_NIL);
                 }
                 if self.locals.depth == 0 {
-                    self.emit_global_definition(identifier);
+                    if mutable {
+                        self.immutable_globals.remove(&identifier);
+                    } else {
+                        self.immutable_globals.insert(identifier.clone());
+                    }
+                    self.emit_global_definition(identifier, identifier_span)?;
                 } else {
-                    self.locals.push_local(identifier);
+                    self.declare_local(identifier, mutable, let_span)?;
                 }
                 self.consume_some(Token::Semicolon)?;
             }
-            Token::BraceOpen => {
-                self.locals.enter_scope();
-                self.block()?;
-                let dropped_locals = self.locals.exit_scope();
-                for _ in 0..dropped_locals {
-                    self.code.push_code(OP_POP);
+            Token::Fn => {
+                self.lexer.next();
+                let fn_span = self.lexer.span();
+                self.expect_some(Token::Identifier)?;
+                let identifier = self.identifier();
+                let identifier_span = self.lexer.span();
+                let function = self.function(identifier.clone(), FunctionKind::Function)?;
+                let key = self
+                    .heap
+                    .alloc_object(Object::new(ObjectKind::Function(Rc::new(function))));
+                self.emit_constant(Value::object(key))?;
+                if self.locals.depth == 0 {
+                    self.emit_global_definition(identifier, identifier_span)?;
+                } else {
+                    self.declare_local(identifier, true, fn_span)?;
+                }
+            }
+            Token::Class => {
+                self.class()?;
+            }
+            Token::Return => {
+                self.lexer.next();
+                if self.function_kind == FunctionKind::Script {
+                    return Err(self.error_at_current("Can't return from top-level code".to_string()));
+                }
+                if self.lexer.peek() == Some(Token::Semicolon) {
+                    self.emit_implicit_return_value();
+                } else if self.function_kind == FunctionKind::Initializer {
+                    return Err(self.error_at_current("Can't return a value from 'init'".to_string()));
+                } else {
+                    self.expression()?;
+                }
+                self.emit_defers(0)?;
+                self.emit(OP_RETURN);
+                self.consume_some(Token::Semicolon)?;
+            }
+            Token::Defer => {
+                self.lexer.next();
+                let deferred = self.lexer.peek();
+                let start = self.lexer.span().start;
+                if matches!(
+                    deferred,
+                    Some(
+                        Token::Let
+                            | Token::Fn
+                            | Token::Class
+                            | Token::Defer
+                            | Token::Return
+                            | Token::Break
+                            | Token::Continue
+                            | Token::While
+                            | Token::If
+                            | Token::BraceOpen
+                    )
+                ) {
+                    return Err(self.error_at_current(
+                        "Only expressions and print statements can be deferred".to_string(),
+                    ));
                 }
+                // Compiled here only to report errors early, the code is emitted at every exit of the scope
+                let mut scratch = CodeChunk::new();
+                mem::swap(self.code, &mut scratch);
+                let res = self.statement();
+                mem::swap(self.code, &mut scratch);
+                res?;
+                self.defers.push(Deferred {
+                    depth: self.locals.depth(),
+                    start,
+                    locals: self.locals.clone(),
+                });
+            }
+            Token::BraceOpen => {
+                self.scoped_block()?;
             }
             _ => {
                 self.expression()?;
-                self.code.push_code(OP_POP);
+                // The value of a final expression at the top level is left on the stack
+                let at_top_level = self.function_kind == FunctionKind::Script && self.locals.depth == 0;
+                if self.config.expression_mode && at_top_level && self.lexer.peek().is_none() {
+                    return Ok(());
+                }
+                self.emit(OP_POP);
                 self.consume_some(Token::Semicolon)?;
             }
         }
@@ -151,6 +356,90 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
         Ok(())
     }
 
+    /// Compiles the rest of `while let x = expr { ... }`, which loops until `expr` evaluates to `nil`.
+    ///
+    /// The value of `expr` is bound to a local visible only in the body.
+    fn while_let(&mut self) -> Result<(), ParsingError> {
+        self.consume_some(Token::Let)?;
+        self.expect_some(Token::Identifier)?;
+        let identifier = self.identifier();
+        self.consume_some(Token::Assign)?;
+
+        self.locals.enter_scope();
+        let loop_start = self.code.size();
+        self.expression()?;
+        self.enter_loop(loop_start);
+        if !self.locals.push_local(identifier, true) {
+            self.exit_loop();
+            return Err(self.error_at_current("Too many local variables (65535)".to_string()));
+        }
+        let loop_end = self.emit_jump_partial(OP_JUMP_NIL);
+        let body = self.scoped_block();
+        let breaks = self.exit_loop();
+        body?;
+        self.emit(OP_POP);
+        self.emit_jump_full(OP_JUMP, loop_start)?;
+        self.patch_jump(loop_end, self.code.size())?;
+        self.emit(OP_POP);
+        self.locals.exit_scope();
+        for jump in breaks {
+            self.patch_jump(jump, self.code.size())?;
+        }
+        Ok(())
+    }
+
+    /// Starts a loop whose `continue` jumps back to `start`
+    fn enter_loop(&mut self, start: usize) {
+        self.loops.push(LoopContext {
+            start,
+            locals: self.locals.len(),
+            defers: self.defers.len(),
+            breaks: Vec::new(),
+        });
+    }
+
+    /// Emits the deferred statements starting from `first`, last deferred first.
+    ///
+    /// Every statement sees the locals of its `defer`, the ones declared after it aren't in scope even if they shadow.
+    fn emit_defers(&mut self, first: usize) -> Result<(), ParsingError> {
+        for idx in (first..self.defers.len()).rev() {
+            let source = self.lexer.source();
+            let lexer = mem::replace(&mut self.lexer, Lexer::lex_at(source, self.defers[idx].start));
+            mem::swap(&mut self.locals, &mut self.defers[idx].locals);
+            let res = self.statement();
+            mem::swap(&mut self.locals, &mut self.defers[idx].locals);
+            self.lexer = lexer;
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Emits and forgets the deferred statements of the current scope
+    fn emit_scope_defers(&mut self) -> Result<(), ParsingError> {
+        let depth = self.locals.depth();
+        let first = self.defers.partition_point(|d| d.depth < depth);
+        self.emit_defers(first)?;
+        self.defers.truncate(first);
+        Ok(())
+    }
+
+    /// Ends the innermost loop, returning its `break` jumps that still need patching
+    fn exit_loop(&mut self) -> Vec<usize> {
+        self.loops.pop().map(|current| current.breaks).unwrap_or_default()
+    }
+
+    /// A block with its own scope, its locals are popped at the end
+    fn scoped_block(&mut self) -> Result<(), ParsingError> {
+        self.locals.enter_scope();
+        self.block()?;
+        self.emit_scope_defers()?;
+        let dropped_locals = self.locals.exit_scope();
+        for _ in 0..dropped_locals {
+            self.emit(OP_POP);
+        }
+        Ok(())
+    }
+
     fn block(&mut self) -> Result<(), ParsingError> {
         self.consume_some(Token::BraceOpen)?;
         while !matches!(self.lexer.peek(), None | Some(Token::BraceClose)) {
@@ -160,6 +449,375 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
         Ok(())
     }
 
+    /// Compiles `class Name { fn method() { ... } ... }`, the class is a variable like a function.
+    ///
+    /// Methods are compiled like functions, with `this` in slot 0.
+    fn class(&mut self) -> Result<(), ParsingError> {
+        self.consume_some(Token::Class)?;
+        let class_span = self.lexer.span();
+        self.expect_some(Token::Identifier)?;
+        let identifier = self.identifier();
+        let identifier_span = self.lexer.span();
+        let constant = self.push_string_constant(identifier.clone())?;
+        self.code.push_code_spanned(OP_CLASS, identifier_span.clone());
+        self.code.push_code(constant);
+
+        self.consume_some(Token::BraceOpen)?;
+        while !matches!(self.lexer.peek(), Some(Token::BraceClose) | None) {
+            self.consume_some(Token::Fn)?;
+            self.expect_some(Token::Identifier)?;
+            let name = self.identifier();
+            let name_span = self.lexer.span();
+            let kind = if name == "init" { FunctionKind::Initializer } else { FunctionKind::Method };
+            let method = self.function(name.clone(), kind)?;
+            let key = self
+                .heap
+                .alloc_object(Object::new(ObjectKind::Function(Rc::new(method))));
+            self.emit_constant(Value::object(key))?;
+            let constant = self.push_string_constant(name)?;
+            self.code.push_code_spanned(OP_METHOD, name_span);
+            self.code.push_code(constant);
+        }
+        self.consume_some(Token::BraceClose)?;
+
+        if self.locals.depth == 0 {
+            self.emit_global_definition(identifier, identifier_span)
+        } else {
+            self.declare_local(identifier, true, class_span)
+        }
+    }
+
+    /// Compiles the parameters and body of a function into a separate chunk.
+    ///
+    /// Functions don't capture anything, they can only see their own locals and globals.
+    fn function(&mut self, name: EcoString, kind: FunctionKind) -> Result<Function, ParsingError> {
+        let mut chunk = CodeChunk::new();
+        mem::swap(self.code, &mut chunk);
+        let enclosing_locals = mem::take(&mut self.locals);
+        let enclosing_loops = mem::take(&mut self.loops);
+        let enclosing_defers = mem::take(&mut self.defers);
+        let enclosing_kind = mem::replace(&mut self.function_kind, kind);
+
+        let res = self.function_body();
+
+        mem::swap(self.code, &mut chunk);
+        self.locals = enclosing_locals;
+        self.loops = enclosing_loops;
+        self.defers = enclosing_defers;
+        self.function_kind = enclosing_kind;
+
+        let arity = res?;
+        if self.config.optimize {
+            optimize(&mut chunk);
+        }
+        Ok(Function { name, arity, chunk })
+    }
+
+    fn function_body(&mut self) -> Result<u8, ParsingError> {
+        self.locals.enter_scope();
+        // Slot 0 holds the called function itself, or `this` in methods
+        self.locals.push_local(EcoString::new(), true);
+
+        self.consume_some(Token::ParenOpen)?;
+        let mut arity: u8 = 0;
+        while self.lexer.peek() != Some(Token::ParenClose) {
+            if arity > 0 {
+                self.consume_some(Token::Comma)?;
+            }
+            self.expect_some(Token::Identifier)?;
+            let param = self.identifier();
+            arity = arity
+                .checked_add(1)
+                .ok_or_else(|| self.error_at_current("Too many parameters (255)".to_string()))?;
+            self.locals.push_local(param, true);
+        }
+        self.consume_some(Token::ParenClose)?;
+
+        self.block()?;
+        self.emit_scope_defers()?;
+        self.emit_implicit_return_value();
+        self.emit(OP_RETURN);
+        Ok(arity)
+    }
+
+    /// What a function returns without a value, `init` returns `this` so calling a class gives the instance
+    fn emit_implicit_return_value(&mut self) {
+        if self.function_kind == FunctionKind::Initializer {
+            self.emit(OP_GET_LOCAL);
+            self.code.push_code(0);
+        } else {
+            self.emit(OP_NIL);
+        }
+    }
+
+    /// Compiles `if cond { ... }` with optional `else if`, `elif` and `else` branches.
+    ///
+    /// Starts at the `if`, or at the `elif` of a chain.
+    fn if_statement(&mut self) -> Result<(), ParsingError> {
+        self.lexer.next();
+        self.expression()?;
+        let els_jmp = self.emit_jump_partial(OP_JUMP_F);
+        self.emit(OP_POP);
+        self.scoped_block()?;
+        let then_end_jmp = self.emit_jump_partial(OP_JUMP);
+
+        self.patch_jump(els_jmp, self.code.size())?;
+        self.emit(OP_POP);
+
+        match self.lexer.peek() {
+            Some(Token::Elif) => self.if_statement()?,
+            Some(Token::Else) => {
+                self.lexer.next();
+                if self.lexer.peek() == Some(Token::If) {
+                    self.if_statement()?;
+                } else {
+                    self.scoped_block()?;
+                }
+            }
+            _ => {}
+        }
+        self.patch_jump(then_end_jmp, self.code.size())
+    }
+
+    /// Compiles `if cond { a } else { b }` in expression position, leaving the value of the taken branch.
+    ///
+    /// Branches hold a single expression and `else` is required, `else if` and `elif` chains are allowed.
+    fn if_expression(&mut self) -> Result<(), ParsingError> {
+        // `elif` continues a chain like `else if`
+        if self.lexer.peek() == Some(Token::Elif) {
+            self.lexer.next();
+        } else {
+            self.consume_some(Token::If)?;
+        }
+        self.expression()?;
+        let els_jmp = self.emit_jump_partial(OP_JUMP_F);
+        self.emit(OP_POP);
+        self.branch_expression()?;
+        let then_end_jmp = self.emit_jump_partial(OP_JUMP);
+
+        self.patch_jump(els_jmp, self.code.size())?;
+        self.emit(OP_POP);
+
+        if self.lexer.peek() == Some(Token::Elif) {
+            self.if_expression()?;
+            return self.patch_jump(then_end_jmp, self.code.size());
+        }
+        if self.lexer.peek() != Some(Token::Else) {
+            return Err(self.error_at_current(
+                "Expected else, an if expression needs a value from both branches".to_string(),
+            ));
+        }
+        self.lexer.next();
+        if self.lexer.peek() == Some(Token::If) {
+            self.if_expression()?;
+        } else {
+            self.branch_expression()?;
+        }
+        self.patch_jump(then_end_jmp, self.code.size())
+    }
+
+    /// Compiles the rest of `cond ? a : b` once `cond` is on the stack, `b` is parsed with `r_bp`
+    fn ternary(&mut self, r_bp: u8) -> Result<(), ParsingError> {
+        self.consume_some(Token::Question)?;
+        let els_jmp = self.emit_jump_partial(OP_JUMP_F);
+        self.emit(OP_POP);
+        self.expression()?;
+        self.consume_some(Token::Colon)?;
+        let then_end_jmp = self.emit_jump_partial(OP_JUMP);
+
+        self.patch_jump(els_jmp, self.code.size())?;
+        self.emit(OP_POP);
+        self.expression_bp(r_bp)?;
+        self.patch_jump(then_end_jmp, self.code.size())
+    }
+
+    fn branch_expression(&mut self) -> Result<(), ParsingError> {
+        self.consume_some(Token::BraceOpen)?;
+        self.expression()?;
+        self.consume_some(Token::BraceClose)
+    }
+
+    fn call(&mut self) -> Result<(), ParsingError> {
+        let start = self.lexer.span().start;
+        let argc = self.arguments()?;
+        self.code.push_code_spanned(OP_CALL, start..self.lexer.span().end);
+        self.code.push_code(argc);
+        Ok(())
+    }
+
+    /// Compiles `is Name` after a value. Built in type names are checked by name, anything else is read
+    /// like a variable and has to be a class
+    fn type_check(&mut self) -> Result<(), ParsingError> {
+        let start = self.lexer.span().start;
+        self.consume_some(Token::Is)?;
+        self.expect_some(Token::Identifier)?;
+        let mut ahead = self.lexer.clone();
+        ahead.next();
+        if !TYPE_NAMES.contains(&ahead.slice()) {
+            // Binds nothing after the name, so `a is B == true` still compares the result
+            self.expression_bp(u8::MAX)?;
+            self.code.push_code_spanned(OP_IS_CLASS, start..self.lexer.span().end);
+            return Ok(());
+        }
+        let name = self.identifier();
+        let constant = self.push_string_constant(name)?;
+        self.code.push_code_spanned(OP_IS, start..self.lexer.span().end);
+        self.code.push_code(constant);
+        Ok(())
+    }
+
+    /// Compiles `.name(args)` after a receiver, the method is looked up on the receiver when it's called.
+    ///
+    /// Without the arguments, `.name` is a field of the receiver or its method bound to it,
+    /// and `.name = value` sets the field.
+    fn member(&mut self, min_bp: u8) -> Result<(), ParsingError> {
+        let start = self.lexer.span().start;
+        self.consume_some(Token::Dot)?;
+        self.expect_some(Token::Identifier)?;
+        let name = self.identifier();
+        let constant = self.push_string_constant(name)?;
+        let span = start..self.lexer.span().end;
+        match self.lexer.peek() {
+            Some(Token::ParenOpen) => {}
+            Some(Token::Assign) => {
+                self.lexer.next();
+                if min_bp > 0 {
+                    return Err(self.error_at_current("Invalid left side of assignment".to_string()));
+                }
+                self.expression()?;
+                self.code.push_code_spanned(OP_SET_FIELD, span);
+                self.code.push_code(constant);
+                return Ok(());
+            }
+            _ => {
+                self.code.push_code_spanned(OP_GET_FIELD, span);
+                self.code.push_code(constant);
+                return Ok(());
+            }
+        }
+        let argc = self.arguments()?;
+        self.code.push_code_spanned(OP_INVOKE, start..self.lexer.span().end);
+        self.code.push_code(constant);
+        self.code.push_code(argc);
+        Ok(())
+    }
+
+    /// Compiles a parenthesized argument list, returns how many arguments there were
+    fn arguments(&mut self) -> Result<u8, ParsingError> {
+        self.consume_some(Token::ParenOpen)?;
+        let mut argc: u8 = 0;
+        while self.lexer.peek() != Some(Token::ParenClose) {
+            if argc > 0 {
+                self.consume_some(Token::Comma)?;
+            }
+            self.expression()?;
+            argc = argc
+                .checked_add(1)
+                .ok_or_else(|| self.error_at_current("Too many arguments (255)".to_string()))?;
+        }
+        self.consume_some(Token::ParenClose)?;
+        Ok(argc)
+    }
+
+    /// Compiles a list literal like `[1, 2, 3]`, a trailing comma is allowed.
+    ///
+    /// `[value; count]` is a list of `count` copies of `value` instead.
+    fn list(&mut self) -> Result<(), ParsingError> {
+        let start = self.lexer.span().start;
+        self.consume_some(Token::BracketOpen)?;
+        let mut count: u8 = 0;
+        while self.lexer.peek() != Some(Token::BracketClose) {
+            self.expression()?;
+            count = count
+                .checked_add(1)
+                .ok_or_else(|| self.error_at_current("Too many elements in a list literal (255)".to_string()))?;
+            if count == 1 && self.lexer.peek() == Some(Token::Semicolon) {
+                self.lexer.next();
+                self.expression()?;
+                self.consume_some(Token::BracketClose)?;
+                self.code.push_code_spanned(OP_ARRAY_REPEAT, start..self.lexer.span().end);
+                return Ok(());
+            }
+            if self.lexer.peek() != Some(Token::Comma) {
+                break;
+            }
+            self.lexer.next();
+        }
+        self.consume_some(Token::BracketClose)?;
+        self.code.push_code_spanned(OP_BUILD_LIST, start..self.lexer.span().end);
+        self.code.push_code(count);
+        Ok(())
+    }
+
+    /// Compiles a map literal like `{"a": 1, "b": 2}`, keys are expressions and a trailing comma is allowed.
+    ///
+    /// Elements without keys, like `{1, 2}`, make a set instead.
+    fn map(&mut self) -> Result<(), ParsingError> {
+        let start = self.lexer.span().start;
+        self.consume_some(Token::BraceOpen)?;
+        let mut count: u8 = 0;
+        while self.lexer.peek() != Some(Token::BraceClose) {
+            self.expression()?;
+            // Without a `:` after the first element it's a set
+            if count == 0 && matches!(self.lexer.peek(), Some(Token::Comma | Token::BraceClose)) {
+                return self.set(start);
+            }
+            self.consume_some(Token::Colon)?;
+            self.expression()?;
+            count = count
+                .checked_add(1)
+                .ok_or_else(|| self.error_at_current("Too many entries in a map literal (255)".to_string()))?;
+            if self.lexer.peek() != Some(Token::Comma) {
+                break;
+            }
+            self.lexer.next();
+        }
+        self.consume_some(Token::BraceClose)?;
+        self.code.push_code_spanned(OP_BUILD_MAP, start..self.lexer.span().end);
+        self.code.push_code(count);
+        Ok(())
+    }
+
+    /// Compiles the rest of a set literal like `{1, 2, 3}`, once its first element is compiled
+    fn set(&mut self, start: usize) -> Result<(), ParsingError> {
+        let mut count: u8 = 1;
+        while self.lexer.peek() == Some(Token::Comma) {
+            self.lexer.next();
+            if self.lexer.peek() == Some(Token::BraceClose) {
+                break;
+            }
+            self.expression()?;
+            count = count
+                .checked_add(1)
+                .ok_or_else(|| self.error_at_current("Too many elements in a set literal (255)".to_string()))?;
+        }
+        self.consume_some(Token::BraceClose)?;
+        self.code.push_code_spanned(OP_BUILD_SET, start..self.lexer.span().end);
+        self.code.push_code(count);
+        Ok(())
+    }
+
+    /// Compiles `[index]` after a list or a map, or `[index] = value` when it's the target of an assignment
+    fn index(&mut self, min_bp: u8) -> Result<(), ParsingError> {
+        let start = self.lexer.span().start;
+        self.consume_some(Token::BracketOpen)?;
+        self.expression()?;
+        self.consume_some(Token::BracketClose)?;
+        let span = start..self.lexer.span().end;
+
+        if self.lexer.peek() == Some(Token::Assign) {
+            self.lexer.next();
+            if min_bp > 0 {
+                return Err(self.error_at_current("Invalid left side of assignment".to_string()));
+            }
+            self.expression()?;
+            self.code.push_code_spanned(OP_INDEX_SET, span);
+        } else {
+            self.code.push_code_spanned(OP_INDEX_GET, span);
+        }
+        Ok(())
+    }
+
     fn expression(&mut self) -> Result<(), ParsingError> {
         self.expression_bp(0)
     }
@@ -168,6 +826,10 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
         let Some(op) = self.lexer.peek() else {
             return Err(self.error_at_current("Expected expression".to_string()));
         };
+        let start = ExpressionStart {
+            code: self.code.size(),
+            constants: self.code.constant_count(),
+        };
         match op {
             Token::ParenOpen => {
                 self.lexer.next();
@@ -176,57 +838,122 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
             }
             Token::Identifier => {
                 let identifier = self.identifier();
+                let identifier_span = self.lexer.span();
                 let maybe_local = self.locals.find_local(identifier.as_str());
 
+                let mutable = match maybe_local {
+                    Some(local) => self.locals.is_mutable(local),
+                    None => !self.immutable_globals.contains(&identifier),
+                };
+                let name = identifier.clone();
+
                 let (set, get, arg) = if let Some(local) = maybe_local {
                     (OP_SET_LOCAL, OP_GET_LOCAL, local)
                 } else {
-                    let constant = self.push_string_constant(identifier);
-                    (OP_SET_GLOBAL, OP_GET_GLOBAL, constant)
+                    // Globals declared before this point have a slot, the rest are looked up by name when they run
+                    let key = self.heap.intern_string(identifier.clone());
+                    match self.heap.global_slot(key).and_then(|slot| u16::try_from(slot).ok()) {
+                        Some(slot) => (OP_SET_GLOBAL_SLOT, OP_GET_GLOBAL_SLOT, slot),
+                        None => {
+                            let constant = self.push_string_constant(identifier)?;
+                            (OP_SET_GLOBAL, OP_GET_GLOBAL, constant.into())
+                        }
+                    }
                 };
 
-                if self.lexer.peek() == Some(Token::Assign) {
+                let compound = self.lexer.peek().and_then(Self::compound_assignment_op);
+                if self.lexer.peek() == Some(Token::Assign) || compound.is_some() {
+                    let op_span = self.lexer.span();
                     self.lexer.next();
                     if min_bp > 0 {
                         return Err(
                             self.error_at_current("Invalid left side of assignment".to_string())
                         );
                     }
+                    if !mutable {
+                        return Err(self.error_at_current(format!(
+                            "Can't assign to immutable variable '{name}', declare it with 'let mut'"
+                        )));
+                    }
+                    let short_local = u8::try_from(arg).ok().filter(|_| get == OP_GET_LOCAL);
+                    if let (Some(slot), None | Some(OP_ADD)) = (short_local, compound) {
+                        if let Some((lexer, amount, amount_span)) = self.local_increment(compound.is_some(), &name) {
+                            self.lexer = lexer;
+                            let constant = self.push_constant(amount)?;
+                            self.code.push_code_spanned(OP_INC_LOCAL, identifier_span.start..amount_span.end);
+                            self.code.push_code(slot);
+                            self.code.push_code(constant);
+                            return Ok(());
+                        }
+                    }
+                    // `x += e` is `x = x + e`, reading the variable the same way
+                    if compound.is_some() {
+                        self.emit_variable(get, arg, identifier_span.clone());
+                    }
                     self.expression()?;
-                    self.code.push_code(set);
-                    self.code.push_code(arg);
+                    if let Some(op) = compound {
+                        self.code.push_code_spanned(op, op_span);
+                    }
+                    self.emit_variable(set, arg, identifier_span);
                 } else {
-                    self.code.push_code(get);
-                    self.code.push_code(arg);
+                    self.emit_variable(get, arg, identifier_span);
                 }
             }
             Token::Number => {
-                self.number();
+                self.number()?;
+            }
+            Token::Integer => {
+                self.integer()?;
             }
             Token::String => {
-                self.string();
+                self.string()?;
             }
             Token::False => {
                 self.lexer.next();
-                self.code.push_span_info(self.lexer.span());
-                self.code.push_code(OP_FALSE)
+                self.emit(OP_FALSE)
             }
             Token::True => {
                 self.lexer.next();
-                self.code.push_span_info(self.lexer.span());
-                self.code.push_code(OP_TRUE)
+                self.emit(OP_TRUE)
             }
             Token::Nil => {
                 self.lexer.next();
-                self.code.push_span_info(self.lexer.span());
-                self.code.push_code(OP_NIL)
+                self.emit(OP_NIL)
+            }
+            Token::If => {
+                self.if_expression()?;
+            }
+            Token::BracketOpen => {
+                self.list()?;
+            }
+            Token::BraceOpen => {
+                self.map()?;
+            }
+            Token::This => {
+                self.lexer.next();
+                if !matches!(self.function_kind, FunctionKind::Method | FunctionKind::Initializer) {
+                    return Err(self.error_at_current("Can't use 'this' outside of a method".to_string()));
+                }
+                self.code.push_code_spanned(OP_GET_LOCAL, self.lexer.span());
+                self.code.push_code(0);
+            }
+            Token::Yield => {
+                // Evaluates to the value the host resumes with
+                let span = self.lexer.span();
+                self.lexer.next();
+                if matches!(self.lexer.peek(), Some(Token::Semicolon | Token::ParenClose) | None) {
+                    self.emit(OP_NIL);
+                } else {
+                    self.expression()?;
+                }
+                self.code.push_code_spanned(OP_YIELD, span);
             }
             prefix_token => match Self::prefix_bp(prefix_token) {
                 Some((_, r_bp)) => {
                     let op_span = self.lexer.span();
                     self.lexer.next();
                     self.expression_bp(r_bp)?;
-                    self.code.push_span_info(op_span);
+                    self.code.push_span_info(op_span.clone());
                     match prefix_token {
                         Token::Sub => self.code.push_code(OP_NEG),
                         Token::Not => self.code.push_code(OP_NOT),
@@ -234,29 +961,76 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
                             warn!("Unsupported token parsed as prefix operator: {:?}", op)
                         }
                     }
+                    self.fold_literals(start, op_span)?;
                 }
                 None => return Err(self.error_at_current(format!("Unexpected token: {:?}", op))),
             },
         }
 
-        loop {
-            let Some(op) = self.lexer.peek() else {
-                return Ok(());
-            };
+        // `+` chains starting with a string literal are concatenations, so they are
+        // collected and joined in one go instead of allocating every intermediate string
+        let mut concat = (op == Token::String).then_some(ConcatChain {
+            operands: 1,
+            span: self.lexer.span(),
+        });
+
+        while let Some(op) = self.lexer.peek() {
+            if let Some((l_bp, ())) = Self::postfix_bp(op) {
+                if l_bp < min_bp {
+                    break;
+                }
+                self.emit_concat(start, concat.take())?;
+                match op {
+                    Token::ParenOpen => self.call()?,
+                    Token::BracketOpen => self.index(min_bp)?,
+                    Token::Dot => self.member(min_bp)?,
+                    _ => {
+                        warn!("Unsupported token parsed as postfix operator: {:?}", op)
+                    }
+                }
+                continue;
+            }
             match Self::infix_bp(op) {
                 Some((l_bp, r_bp)) => {
                     if l_bp < min_bp {
                         break;
                     }
                     let op_span = self.lexer.span();
+                    match &mut concat {
+                        Some(chain) if op == Token::Add && chain.operands < u8::MAX => {
+                            self.lexer.next();
+                            self.expression_bp(r_bp)?;
+                            chain.operands += 1;
+                            chain.span.end = op_span.end;
+                            continue;
+                        }
+                        // The joined string continues the chain
+                        Some(_) if op == Token::Add => {
+                            self.emit_concat(start, concat.take())?;
+                            concat = Some(ConcatChain { operands: 1, span: op_span.clone() });
+                            continue;
+                        }
+                        _ => self.emit_concat(start, concat.take())?,
+                    }
+                    if op == Token::Question {
+                        self.ternary(r_bp)?;
+                        continue;
+                    }
+                    if op == Token::Is {
+                        self.type_check()?;
+                        continue;
+                    }
                     self.lexer.next();
                     self.expression_bp(r_bp)?;
-                    self.code.push_span_info(op_span);
+                    self.code.push_span_info(op_span.clone());
+                    // Both instructions of `!=`, `>=` and `<=` belong to the operator
                     match op {
                         Token::Add => self.code.push_code(OP_ADD),
                         Token::Sub => self.code.push_code(OP_SUB),
                         Token::Mul => self.code.push_code(OP_MUL),
                         Token::Div => self.code.push_code(OP_DIV),
+                        Token::Rem => self.code.push_code(OP_REM),
+                        Token::Pow => self.code.push_code(OP_POW),
                         Token::Eq => self.code.push_code(OP_EQUAL),
                         Token::Neq => {
                             self.code.push_code(OP_EQUAL);
@@ -278,27 +1052,180 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
                             warn!("Unsupported token parsed as infix operator: {:?}", op)
                         }
                     }
+                    self.fold_literals(start, op_span)?;
                 }
                 None => break,
             }
         }
+        self.emit_concat(start, concat)?;
+
+        Ok(())
+    }
 
+    /// `start` is where the expression the chain belongs to starts
+    fn emit_concat(&mut self, start: ExpressionStart, chain: Option<ConcatChain>) -> Result<(), ParsingError> {
+        let Some(ConcatChain { operands, span }) = chain else {
+            return Ok(());
+        };
+        match operands {
+            0 | 1 => return Ok(()),
+            2 => {
+                self.code.push_code_spanned(OP_ADD, span.clone());
+            }
+            n => {
+                self.code.push_code_spanned(OP_CONCAT_N, span.clone());
+                self.code.push_code(n);
+            }
+        }
+        self.fold_literals(start, span)
+    }
+
+    /// Source covered by the code compiled since the offset `start`
+    fn span_since(&self, start: usize) -> Range<usize> {
+        (start..self.code.size())
+            .map(|offset| self.code.find_span_of(offset).1.clone())
+            .reduce(|span, next| span.start.min(next.start)..span.end.max(next.end))
+            .unwrap_or_else(|| self.lexer.span())
+    }
+
+    /// Replaces the code of the expression since `start` with its value, if it only combines
+    /// number, bool and string literals, like `2 * 3.14 * 10`.
+    ///
+    /// Operands of the wrong types are reported at `span` right away. Other failures and
+    /// results that aren't finite are left for the VM, the code might never run.
+    fn fold_literals(&mut self, start: ExpressionStart, span: Range<usize>) -> Result<(), ParsingError> {
+        let mut stack: Vec<Value> = Vec::new();
+        let mut value_span = span.clone();
+        let mut offset = start.code;
+        while let Some(op) = self.code.get_byte(offset) {
+            let op_span = &self.code.find_span_of(offset).1;
+            value_span = value_span.start.min(op_span.start)..value_span.end.max(op_span.end);
+            offset += 1;
+            let (name, rhs) = match op {
+                OP_CONSTANT => {
+                    let constant = self.code.get_byte(offset).and_then(|idx| self.code.get_constant(idx as usize));
+                    match constant {
+                        Some(value) if value.as_float().is_some() || value.as_string(self.heap).is_some() => {
+                            stack.push(*value);
+                            offset += 1;
+                            continue;
+                        }
+                        _ => return Ok(()),
+                    }
+                }
+                OP_TRUE | OP_FALSE => {
+                    stack.push(Value::bool(op == OP_TRUE));
+                    continue;
+                }
+                OP_CONCAT_N => {
+                    let Some(count) = self.code.get_byte(offset) else {
+                        return Ok(());
+                    };
+                    offset += 1;
+                    let operands = stack.split_off(stack.len().saturating_sub(count as usize));
+                    // Numbers and bools are converted, so literals always join
+                    match Value::concat(&operands, self.heap) {
+                        Ok(value) => stack.push(value),
+                        Err(_) => return Ok(()),
+                    }
+                    continue;
+                }
+                OP_NEG | OP_NOT => ("negate", None),
+                OP_ADD => ("add", stack.pop()),
+                OP_SUB => ("subtract", stack.pop()),
+                OP_MUL => ("multiply", stack.pop()),
+                OP_DIV => ("divide", stack.pop()),
+                OP_REM => ("take the remainder of", stack.pop()),
+                OP_POW => ("exponentiate", stack.pop()),
+                OP_EQUAL | OP_LESS | OP_GREATER => ("compare", stack.pop()),
+                _ => return Ok(()),
+            };
+            let Some(lhs) = stack.pop() else {
+                return Ok(());
+            };
+            let result = match (op, rhs) {
+                (OP_NEG, _) => lhs.neg(self.heap),
+                (OP_NOT, _) => lhs.not(self.heap),
+                (OP_ADD, Some(rhs)) => lhs.add(&rhs, self.heap),
+                (OP_SUB, Some(rhs)) => lhs.sub(&rhs, self.heap),
+                (OP_MUL, Some(rhs)) => lhs.mul(&rhs, self.heap),
+                (OP_DIV, Some(rhs)) => lhs.div(&rhs, self.heap),
+                (OP_REM, Some(rhs)) => lhs.rem(&rhs, self.heap),
+                (OP_POW, Some(rhs)) => lhs.pow(&rhs, self.heap),
+                (OP_EQUAL, Some(rhs)) => lhs.equal(&rhs, self.heap),
+                (OP_LESS, Some(rhs)) => lhs.less(&rhs, self.heap),
+                (OP_GREATER, Some(rhs)) => lhs.greater(&rhs, self.heap),
+                _ => return Ok(()),
+            };
+            match result {
+                Ok(value) => stack.push(value),
+                Err(ValueError::UnSupportedOperation) => {
+                    let err = RuntimeError::TypeError {
+                        op: name,
+                        lhs: lhs.type_name(self.heap),
+                        rhs: rhs.map(|rhs| rhs.type_name(self.heap)),
+                    };
+                    return Err(ParsingError::at(span, err.to_string()));
+                }
+                Err(_) => return Ok(()),
+            }
+        }
+
+        let [value] = stack[..] else {
+            return Ok(());
+        };
+        if value.as_float().is_some_and(|num| !num.is_finite()) {
+            return Ok(());
+        }
+        self.code.truncate(start.code, start.constants);
+        match value.kind() {
+            ValueKind::Bool(true) => self.code.push_code_spanned(OP_TRUE, value_span),
+            ValueKind::Bool(false) => self.code.push_code_spanned(OP_FALSE, value_span),
+            ValueKind::Object(key) => {
+                let constant = match self.code.find_object_constant(key) {
+                    Some(constant) => constant,
+                    None => self.push_constant(value)?,
+                };
+                self.code.push_code_spanned(OP_CONSTANT, value_span);
+                self.code.push_code(constant);
+            }
+            _ => {
+                let constant = self.push_constant(value)?;
+                self.code.push_code_spanned(OP_CONSTANT, value_span);
+                self.code.push_code(constant);
+            }
+        }
         Ok(())
     }
 
-    fn number(&mut self) {
+    fn number(&mut self) -> Result<(), ParsingError> {
         let slice = self.lexer.slice();
         let num = slice.parse().expect("Internal panic: Can't parse number");
-        self.emit_constant(Value::Number(num));
+        self.emit_constant(Value::number(num))?;
         self.lexer.next();
+        Ok(())
     }
 
-    fn string(&mut self) {
+    fn integer(&mut self) -> Result<(), ParsingError> {
+        let num = self
+            .lexer
+            .slice()
+            .parse()
+            .ok()
+            .ok_or_else(|| self.error_at_current("Integer literal out of range".to_string()))?;
+        self.emit_constant(Value::int(num))?;
+        self.lexer.next();
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<(), ParsingError> {
         let slice = self.lexer.slice();
         let string = EcoString::from(&slice[1..slice.len() - 1]);
-        let id = self.heap.intern_string(string);
-        self.emit_constant(Value::Object(id));
+        let constant = self.push_string_constant(string)?;
+        self.code.push_code_spanned(OP_CONSTANT, self.lexer.span());
+        self.code.push_code(constant);
         self.lexer.next();
+        Ok(())
     }
 
     fn identifier(&mut self) -> EcoString {
@@ -309,11 +1236,53 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
 
     fn infix_bp(token: Token) -> Option<(u8, u8)> {
         let bp = match token {
+            Token::Question => (3, 2),
             Token::Or => (4, 5),
             Token::And => (6, 7),
-            Token::Eq | Token::Neq | Token::Geq | Token::Leq | Token::Le | Token::Gr => (10, 11),
+            Token::Eq | Token::Neq | Token::Geq | Token::Leq | Token::Le | Token::Gr | Token::Is => (10, 11),
             Token::Add | Token::Sub => (15, 16),
-            Token::Mul | Token::Div => (17, 18),
+            Token::Mul | Token::Div | Token::Rem => (17, 18),
+            // Right associative and above prefix operators, so `-2 ** 2` is `-4`
+            Token::Pow => (27, 26),
+            _ => return None,
+        };
+        Some(bp)
+    }
+
+    /// Recognizes the rest of `x += 1;` or `x = x + 1;` after the assignment operator, for loop counters.
+    ///
+    /// Returns the lexer after the number and the number, the lexer is only advanced if it matches.
+    fn local_increment(&self, compound: bool, name: &str) -> Option<(Lexer<'source>, Value, Range<usize>)> {
+        let mut ahead = self.lexer.clone();
+        if !compound && (ahead.next()? != Token::Identifier || ahead.slice() != name || ahead.next()? != Token::Add) {
+            return None;
+        }
+        let amount = match ahead.next()? {
+            // Out of range literals are left for `integer` to report
+            Token::Integer => Value::int(ahead.slice().parse().ok()?),
+            Token::Number => Value::number(ahead.slice().parse().ok()?),
+            _ => return None,
+        };
+        let span = ahead.span();
+        // Anything else could still bind to the number, like `x = x + 1 * 2`
+        (ahead.peek()? == Token::Semicolon).then_some((ahead, amount, span))
+    }
+
+    /// The arithmetic instruction of a compound assignment like `+=`
+    fn compound_assignment_op(token: Token) -> Option<u8> {
+        match token {
+            Token::AddAssign => Some(OP_ADD),
+            Token::SubAssign => Some(OP_SUB),
+            Token::MulAssign => Some(OP_MUL),
+            Token::DivAssign => Some(OP_DIV),
+            Token::RemAssign => Some(OP_REM),
+            _ => None,
+        }
+    }
+
+    fn postfix_bp(token: Token) -> Option<(u8, ())> {
+        let bp = match token {
+            Token::ParenOpen | Token::BracketOpen | Token::Dot => (30, ()),
             _ => return None,
         };
         Some(bp)
@@ -355,27 +1324,72 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
         }
     }
 
+    /// Adds a local for the value on top of the stack, `span` is the declaration reported if there's no free slot
+    fn declare_local(&mut self, identifier: EcoString, mutable: bool, span: Range<usize>) -> Result<(), ParsingError> {
+        if self.locals.push_local(identifier, mutable) {
+            Ok(())
+        } else {
+            Err(ParsingError::at(span, "Too many local variables (65535)".to_string()))
+        }
+    }
+
+    /// Pushes an instruction belonging to the current token, for ones without a better span
+    fn emit(&mut self, op: u8) {
+        self.code.push_code_spanned(op, self.lexer.span());
+    }
+
+    /// Pushes a variable access, locals past the first 256 slots switch to the wide opcodes
+    fn emit_variable(&mut self, op: u8, arg: u16, span: Range<usize>) {
+        let op = match (op, u8::try_from(arg)) {
+            (OP_GET_LOCAL, Err(_)) => OP_GET_LOCAL_LONG,
+            (OP_SET_LOCAL, Err(_)) => OP_SET_LOCAL_LONG,
+            _ => op,
+        };
+        self.code.push_code_spanned(op, span);
+        match op {
+            OP_GET_LOCAL_LONG | OP_SET_LOCAL_LONG | OP_GET_GLOBAL_SLOT | OP_SET_GLOBAL_SLOT => {
+                for byte in arg.to_be_bytes() {
+                    self.code.push_code(byte);
+                }
+            }
+            _ => self.code.push_code(arg as u8),
+        }
+    }
+
     fn error_at_current(&self, msg: String) -> ParsingError {
         ParsingError::at(self.lexer.span(), msg)
     }
 
-    fn push_string_constant(&mut self, string: EcoString) -> u8 {
-        self.code.push_span_info(self.lexer.span());
+    fn push_constant(&mut self, value: Value) -> Result<u8, ParsingError> {
+        self.code
+            .push_constant(value)
+            .ok_or_else(|| self.error_at_current("Too many constants in one chunk (256)".to_string()))
+    }
+
+    /// Interned strings are only added to the pool once, later uses share the slot
+    fn push_string_constant(&mut self, string: EcoString) -> Result<u8, ParsingError> {
         let obj = self.heap.intern_string(string);
-        self.code.push_constant(Value::Object(obj))
+        match self.code.find_object_constant(obj) {
+            Some(constant) => Ok(constant),
+            None => self.push_constant(Value::object(obj)),
+        }
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        self.code.push_span_info(self.lexer.span());
-        let constant = self.code.push_constant(value);
-        self.code.push_code(OP_CONSTANT);
+    fn emit_constant(&mut self, value: Value) -> Result<(), ParsingError> {
+        let constant = self.push_constant(value)?;
+        self.code.push_code_spanned(OP_CONSTANT, self.lexer.span());
         self.code.push_code(constant);
+        Ok(())
     }
 
-    fn emit_global_definition(&mut self, identifier: EcoString) {
-        let constant = self.push_string_constant(identifier);
-        self.code.push_code(OP_DEF_GLOBAL);
+    /// `span` is the declared identifier
+    fn emit_global_definition(&mut self, identifier: EcoString, span: Range<usize>) -> Result<(), ParsingError> {
+        let key = self.heap.intern_string(identifier.clone());
+        self.heap.declare_global(key);
+        let constant = self.push_string_constant(identifier)?;
+        self.code.push_code_spanned(OP_DEF_GLOBAL, span);
         self.code.push_code(constant);
+        Ok(())
     }
 
     fn emit_jump_full(&mut self, instr: u8, to: usize) -> Result<(), ParsingError> {
@@ -383,14 +1397,14 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
             .try_into()
             .map_err(|_| self.error_at_current("Jump too long".to_owned()))?;
         let [big, little] = relative_jump.to_be_bytes();
-        self.code.push_code(instr);
+        self.emit(instr);
         self.code.push_code(big);
         self.code.push_code(little);
         Ok(())
     }
 
     fn emit_jump_partial(&mut self, instr: u8) -> usize {
-        self.code.push_code(instr);
+        self.emit(instr);
         self.code.push_code(0xFF);
         self.code.push_code(0xFF);
         self.code.size() - 2
@@ -407,15 +1421,54 @@ impl<'source, 'code, 'heap> Parser<'source, 'code, 'heap> {
     }
 }
 
+/// A loop being compiled, used by `break` and `continue`
+#[derive(Debug)]
+struct LoopContext {
+    start: usize,
+    /// Number of locals declared outside of the loop, the rest is popped when jumping out
+    locals: usize,
+    /// Number of deferred statements from outside of the loop
+    defers: usize,
+    breaks: Vec<usize>,
+}
+
+/// A statement from `defer`, compiled again at every exit of its scope
+#[derive(Debug)]
+struct Deferred {
+    depth: u8,
+    /// Where the statement starts in the source
+    start: usize,
+    /// Locals when it was deferred, its names are resolved with them at every exit
+    locals: Locals,
+}
+
+/// Sizes of the chunk when an expression started being compiled
+#[derive(Debug, Clone, Copy)]
+struct ExpressionStart {
+    code: usize,
+    constants: usize,
+}
+
+/// Operands of a string concatenation that are on the stack but not joined yet
+#[derive(Debug)]
+struct ConcatChain {
+    operands: u8,
+    span: Range<usize>,
+}
+
 impl ParsingError {
     fn at(span: Range<usize>, msg: String) -> ParsingError {
         Self { msg, span }
     }
 }
 
-#[derive(Debug)]
+/// Locals a function can have, slots past 255 are addressed with two bytes
+pub const LOCALS_MAX: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone)]
 pub struct Locals {
-    stack: Vec<(EcoString, u8)>,
+    /// Name, scope depth and whether it can be reassigned
+    stack: Vec<(EcoString, u8, bool)>,
     depth: u8,
 }
 
@@ -427,38 +1480,51 @@ impl Locals {
         }
     }
 
-    pub fn find_local(&self, identifier: &str) -> Option<u8> {
+    pub fn find_local(&self, identifier: &str) -> Option<u16> {
         self.stack
             .iter()
             .rev()
-            .position(|(p, _)| p.as_str() == identifier)
-            .map(|idx| (self.stack.len() - 1 - idx) as u8)
+            .position(|(p, _, _)| p.as_str() == identifier)
+            .map(|idx| (self.stack.len() - 1 - idx) as u16)
     }
 
-    pub fn push_local(&mut self, identifier: EcoString) -> bool {
-        if self.stack.len() >= 256 {
+    /// Returns `false` once there are [`LOCALS_MAX`] locals
+    pub fn push_local(&mut self, identifier: EcoString, mutable: bool) -> bool {
+        if self.stack.len() >= LOCALS_MAX {
             false
         } else {
-            self.stack.push((identifier, self.depth));
+            self.stack.push((identifier, self.depth, mutable));
             true
         }
     }
 
+    pub fn is_mutable(&self, local: u16) -> bool {
+        self.stack[local as usize].2
+    }
+
     pub fn enter_scope(&mut self) {
         self.depth += 1;
     }
 
-    pub fn exit_scope(&mut self) -> u8 {
+    pub fn exit_scope(&mut self) -> usize {
         let len = self.stack.len();
-        let partition_point = self.stack.partition_point(|&(_, d)| d < self.depth);
+        let partition_point = self.stack.partition_point(|&(_, d, _)| d < self.depth);
         self.stack.truncate(partition_point);
         self.depth -= 1;
-        (len - partition_point) as u8
+        len - partition_point
     }
 
     pub fn depth(&self) -> u8 {
         self.depth
     }
+
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
 }
 
 impl Default for Locals {
@@ -469,7 +1535,10 @@ impl Default for Locals {
 
 #[cfg(test)]
 mod tests {
-    use crate::bytecode::vm::VM;
+    use crate::{
+        bytecode::vm::{RuntimeError, VM},
+        run_source, RunError,
+    };
 
     use super::*;
 
@@ -485,12 +1554,461 @@ mod tests {
     fn parse_test() {
         init_logger();
 
-        let test_str = "(1 + 5) - - - (8 - 2)";
+        let test_str = "print (1 + 5) - - - (8 - 2);";
         let mut code = CodeChunk::new();
         let mut heap = ObjectHeap::new();
         Parser::parse_source(test_str, &mut code, &mut heap).unwrap();
         eprintln!("{}", code);
 
-        VM::init(&code, &mut heap).run().unwrap();
+        assert_eq!(run_source(test_str).unwrap(), "0\n");
+    }
+
+    /// Printed lines of a program that has to run without errors
+    fn run_lines(source: &str) -> Vec<String> {
+        init_logger();
+        run_source(source).unwrap().lines().map(str::to_string).collect()
+    }
+
+    fn global(heap: &mut ObjectHeap, name: &str) -> Value {
+        let key = heap.intern_string(EcoString::from(name));
+        heap.get_global(key).unwrap()
+    }
+
+    fn run_with_config(source: &str, config: ParserConfig) -> ObjectHeap {
+        init_logger();
+
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        Parser::parse_source_with_config(source, &mut code, &mut heap, config).unwrap();
+        let mut vm = VM::init(&code, &mut heap);
+        vm.run().unwrap();
+        if config.entry_main {
+            vm.call_function("main", &[]).unwrap();
+        }
+        drop(vm);
+        heap
+    }
+
+    #[test]
+    fn function_test() {
+        let source = "
+            fn add(a, b) { return a + b; }
+            fn nothing() { }
+            print add(1, 2);
+            print nothing();
+        ";
+        assert_eq!(run_lines(source), ["3", "nil"]);
+    }
+
+    #[test]
+    fn recursion_test() {
+        let source = "
+            fn fib(n) {
+                if n < 2 { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            print fib(10);
+        ";
+        assert_eq!(run_lines(source), ["55"]);
+    }
+
+    #[test]
+    fn integer_test() {
+        let source = "print 7 / 2; print 7.0 / 2; print 7 % 2;";
+        assert_eq!(run_lines(source), ["3", "3.5", "1"]);
+    }
+
+    #[test]
+    fn concat_n_test() {
+        let prelude = r#"let b = "b"; let d = "d";"#;
+        let chained = format!(r#"{prelude} let x = ("a" + b) + "c" + d;"#);
+        let joined = format!(r#"{prelude} let x = "a" + b + "c" + d;"#);
+
+        let mut chained_heap = run_with_config(&chained, ParserConfig::default());
+        let mut joined_heap = run_with_config(&joined, ParserConfig::default());
+
+        let chained_x = global(&mut chained_heap, "x");
+        let joined_x = global(&mut joined_heap, "x");
+        assert_eq!(chained_x.print_with_heap(&chained_heap).to_string(), "abcd");
+        assert_eq!(joined_x.print_with_heap(&joined_heap).to_string(), "abcd");
+        // "ab" and "abc" are never allocated
+        assert_eq!(chained_heap.live_count() - joined_heap.live_count(), 2);
+        assert!(chained_heap.dynamic_memory_used() > joined_heap.dynamic_memory_used());
+    }
+
+    #[test]
+    fn string_conversion_test() {
+        let source = r#"
+            let score = 42;
+            print "score: " + score;
+            print 1.5 + " points";
+            print "a" + 1 + 2.5 + true + nil;
+            print str(1.5) == "1.5";
+            print str(false) + str(score);
+        "#;
+        assert_eq!(
+            run_lines(source),
+            ["score: 42", "1.5 points", "a12.5truenil", "true", "false42"]
+        );
+    }
+
+    #[test]
+    fn string_index_and_len_test() {
+        let source = r#"
+            print len("héllo");
+            print "abc"[1];
+            print "héllo"[1] == "é";
+            print len([1, 2, 3]) + len({"a": 1});
+        "#;
+        assert_eq!(run_lines(source), ["5", "b", "true", "4"]);
+
+        let Err(RunError::Runtime { error, .. }) = run_source(r#"print "abc"[3];"#) else {
+            panic!("out of range string index didn't fail");
+        };
+        assert_eq!(error.to_string(), "Index 3 is out of bounds for a string of length 3");
+    }
+
+    #[test]
+    fn too_many_locals_test() {
+        let source = format!("{{ {} }}", "let x; ".repeat(LOCALS_MAX + 1));
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let errors = Parser::parse_source(&source, &mut code, &mut heap).unwrap_err();
+
+        assert_eq!(errors[0].msg, "Too many local variables (65535)");
+        // The `let` past the limit
+        let start = 2 + LOCALS_MAX * "let x; ".len();
+        assert_eq!(errors[0].span, start..start + 3);
+    }
+
+    #[test]
+    fn wide_locals_test() {
+        let locals: String = (0..300).map(|i| format!("let x{i}; ")).collect();
+        let source = format!("fn f() {{ {locals} x254 = 1; x256 = 2; x299 = x256 + 3; x299 += x254; return [x0, x256, x299]; }} print f();");
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        Parser::parse_source(&source, &mut code, &mut heap).unwrap();
+        let dump = code.dissasemble().with_heap(&heap).with_color(false).to_string();
+
+        assert!(dump.contains("GET LOCAL LONG 257"), "{dump}");
+        assert!(dump.contains("SET LOCAL LONG 300"), "{dump}");
+        // Slot 0 is the function, so `x254` is the last local with a one byte slot
+        assert!(dump.contains("SET LOCAL  255"), "{dump}");
+        assert_eq!(run_source(&source).unwrap(), "[nil, 2, 6]\n");
+    }
+
+    #[test]
+    fn string_constant_dedup_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let source = r#"let foo = "foo"; foo = foo + "foo";"#;
+        Parser::parse_source(source, &mut code, &mut heap).unwrap();
+
+        // The identifier and the literal are the same interned string
+        assert!(code.get_constant(0).is_some());
+        assert!(code.get_constant(1).is_none());
+        assert_eq!(run_source(&format!("{source} print foo;")).unwrap(), "foofoo\n");
+    }
+
+    #[test]
+    fn list_test() {
+        let source = r#"
+            let list = [1, "two", [3.5], nil,];
+            print list;
+            print list[1];
+            list[0] = list[0] + 10;
+            print list[0];
+            print list[2][0];
+            print [];
+        "#;
+        assert_eq!(run_lines(source), ["[1, \"two\", [3.5], nil]", "two", "11", "3.5", "[]"]);
+
+        let Err(RunError::Runtime { error, .. }) = run_source("let list = [1, 2]; print list[2];") else {
+            panic!("out of bounds index didn't fail");
+        };
+        assert!(matches!(error, RuntimeError::IndexOutOfBounds { index: 2, len: 2, .. }));
+    }
+
+    #[test]
+    fn map_test() {
+        let source = r#"
+            let scores = {"alice": 3, "bob": 5,};
+            scores["carol"] = 7;
+            scores["alice"] = scores["alice"] + 1;
+            print scores["alice"];
+            print scores["carol"];
+            print scores;
+            print {1: "int", 2.5: nil}[1.0];
+            print {};
+            print scores["dave"];
+        "#;
+        assert_eq!(
+            run_lines(source),
+            ["4", "7", r#"{"alice": 4, "bob": 5, "carol": 7}"#, "int", "{}", "nil"]
+        );
+    }
+
+    #[test]
+    fn while_let_test() {
+        let source = "
+            let left = 3;
+            fn next() {
+                if left == 0 { return nil; }
+                left = left - 1;
+                return left;
+            }
+            let total = 0;
+            let rounds = 0;
+            while let x = next() {
+                let doubled = x * 2;
+                total = total + doubled;
+                rounds = rounds + 1;
+            }
+            print total;
+            print rounds;
+        ";
+        assert_eq!(run_lines(source), ["6", "3"]);
+    }
+
+    #[test]
+    fn break_continue_test() {
+        let source = "
+            let pairs = 0;
+            let i = 0;
+            while i < 5 {
+                i = i + 1;
+                if i == 2 { continue; }
+                let j = 0;
+                while true {
+                    let next = j + 1;
+                    j = next;
+                    if j > i { break; }
+                    if j == 1 { continue; }
+                    pairs = pairs + 1;
+                }
+            }
+            print pairs;
+            print i;
+        ";
+        // i = 1, 3, 4, 5 count j = 2..=i
+        assert_eq!(run_lines(source), ["9", "5"]);
+    }
+
+    #[test]
+    fn break_outside_loop_test() {
+        for (source, keyword) in [
+            ("if true { break; }", "break"),
+            ("while true { fn f() { continue; } }", "continue"),
+        ] {
+            let mut code = CodeChunk::new();
+            let mut heap = ObjectHeap::new();
+            let errors = Parser::parse_source(source, &mut code, &mut heap).unwrap_err();
+            assert_eq!(&source[errors[0].span.clone()], keyword);
+        }
+    }
+
+    #[test]
+    fn if_expression_test() {
+        let source = "
+            let x = if true { 1 } else { 2 };
+            let y = 10 + if x > 1 { 100 } else if x == 1 { 200 } else { 300 } * 2;
+            print x;
+            print y;
+        ";
+        assert_eq!(run_lines(source), ["1", "410"]);
+
+        let Err(RunError::Parse(errors)) = run_source("let z = if true { 1 };") else {
+            panic!("if expression without else compiled");
+        };
+        assert!(errors[0].msg.contains("Expected else"));
+    }
+
+    #[test]
+    fn defer_test() {
+        let source = r#"
+            fn f(early) {
+                let name = "f";
+                defer print "first " + name;
+                defer print "second " + name;
+                if early { return 1; }
+                print "body";
+                return 2;
+            }
+            print f(false);
+            print f(true);
+            {
+                defer print "block";
+                print "in block";
+            }
+            let i = 0;
+            while i < 2 {
+                i = i + 1;
+                defer print i;
+                if i == 2 { break; }
+            }
+        "#;
+        let expected = [
+            "body", "second f", "first f", "2", "second f", "first f", "1", "in block", "block",
+            "1", "2",
+        ];
+        assert_eq!(run_lines(source), expected);
+    }
+
+    #[test]
+    fn defer_resolves_names_where_deferred() {
+        let source = r#"
+            { let a = 1; defer print a; let a = 2; print a; }
+            fn f() { let x = "outer"; defer print x; { let x = "inner"; return x; } }
+            print f();
+        "#;
+        assert_eq!(run_lines(source), ["2", "1", "outer", "inner"]);
+    }
+
+    #[test]
+    fn elif_test() {
+        let source = r#"
+            fn grade(n) {
+                if n > 8 { return "a"; } elif n > 5 { return "b"; } elif n > 2 { return "c"; } else { return "d"; }
+            }
+            fn size(n) { return if n < 10 { "small" } elif n < 100 { "medium" } else { "large" }; }
+            print grade(9); print grade(6); print grade(3); print grade(0);
+            print size(1); print size(50); print size(500);
+            if false { print 1; } elif false { print 2; }
+            if false { print 1; } else if true { print 3; } elif true { print 4; }
+        "#;
+        assert_eq!(
+            run_lines(source),
+            ["a", "b", "c", "d", "small", "medium", "large", "3"]
+        );
+    }
+
+    #[test]
+    fn ternary_test() {
+        let source = "
+            let a = 1 < 2 ? 10 : 20;
+            let b = true ? 1 : false ? 2 : 3;
+            let c = false ? 1 : false ? 2 : 3;
+            let d = 1 + 1 == 2 or false ? \"yes\" : \"no\";
+            print a; print b; print c; print d;
+        ";
+        // Left associative grouping would make b 2
+        assert_eq!(run_lines(source), ["10", "1", "3", "yes"]);
+    }
+
+    #[test]
+    fn runtime_error_span_test() {
+        let span_of = |source: &str| match run_source(source) {
+            Err(RunError::Runtime { span, .. }) => source[span].to_string(),
+            res => panic!("expected a runtime error, got {res:?}"),
+        };
+        assert_eq!(span_of("{ let a = 1; print a + nil; }"), "+");
+        assert_eq!(span_of("{ let a = 1; a = -a; print !a < 1; }"), "<");
+        assert_eq!(span_of("let a = 1; { print a; b = a; }"), "b");
+        assert_eq!(span_of("let a = 1; { print a; print b; }"), "b");
+    }
+
+    #[test]
+    fn stack_trace_test() {
+        let source = "fn inner() { return nil + 1; }\nfn outer() { return inner(); }\nprint outer();";
+        let Err(RunError::Runtime { trace, .. }) = run_source(source) else {
+            panic!("error two calls deep wasn't a runtime error");
+        };
+        let frames: Vec<_> = trace
+            .iter()
+            .map(|frame| (frame.function.as_deref(), &source[frame.span.clone()]))
+            .collect();
+        assert_eq!(
+            frames,
+            [(Some("inner"), "+"), (Some("outer"), "()"), (None, "()")]
+        );
+    }
+
+    #[test]
+    fn wrong_argument_count_test() {
+        let res = run_source("print 1; fn f(a) { } f(1, 2);");
+        let Err(RunError::Runtime { error, span, output, .. }) = res else {
+            panic!("wrong argument count wasn't a runtime error");
+        };
+        assert!(matches!(error, RuntimeError::WrongArgumentCount { expected: 1, got: 2 }));
+        assert_eq!(span, 22..28);
+        assert_eq!(output, "1\n");
+    }
+
+    #[test]
+    fn top_level_return_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let errors = Parser::parse_source("return 1;", &mut code, &mut heap).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, 0..6);
+    }
+
+    #[test]
+    fn entry_main_test() {
+        let source = "
+            let ran = false;
+            fn main() { print 1; ran = true; }
+        ";
+        let config = ParserConfig { entry_main: true, ..Default::default() };
+        let mut heap = run_with_config(source, config);
+        assert!(matches!(global(&mut heap, "ran").kind(), ValueKind::Bool(true)));
+    }
+
+    #[test]
+    fn entry_main_rejects_statements_test() {
+        let mut code = CodeChunk::new();
+        let mut heap = ObjectHeap::new();
+        let config = ParserConfig { entry_main: true, ..Default::default() };
+        let source = "fn main() { } print 1;";
+        let errors =
+            Parser::parse_source_with_config(source, &mut code, &mut heap, config).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, 14..19);
+    }
+
+    #[test]
+    fn immutable_let_test() {
+        let config = ParserConfig { immutable_let: true, ..Default::default() };
+        let parse = |source: &str| {
+            let mut code = CodeChunk::new();
+            let mut heap = ObjectHeap::new();
+            Parser::parse_source_with_config(source, &mut code, &mut heap, config)
+        };
+
+        let errors = parse("let x = 1; x = 2;").unwrap_err();
+        assert_eq!(errors[0].msg, "Can't assign to immutable variable 'x', declare it with 'let mut'");
+        assert!(parse("{ let x = 1; x = 2; }").is_err());
+        assert!(parse("let mut x = 1; x = 2;").is_ok());
+        assert!(parse("{ let mut x = 1; { x = 2; } }").is_ok());
+        assert!(parse("let x = 1; let mut x = x; x = 2;").is_ok());
+
+        let mut heap = run_with_config("let mut x = 1; x = x + 1;", config);
+        assert!(matches!(global(&mut heap, "x").kind(), ValueKind::Int(2)));
+        // Without the flag every `let` stays mutable
+        assert_eq!(run_lines("let x = 1; x = 2; let mut y = x; print y;"), ["2"]);
+    }
+
+    #[test]
+    fn incomplete_input_test() {
+        let lines = ["let x = 3;", "if x > 2 {", "    print \"big\";", "} else {", "    print \"small\";", "}"];
+        let config = ParserConfig::default();
+
+        assert!(!Parser::is_incomplete(lines[0], config));
+        let mut source = String::new();
+        for line in &lines[..lines.len() - 1] {
+            source.push_str(line);
+            source.push('\n');
+            if line.contains('{') {
+                assert!(Parser::is_incomplete(&source, config), "{source}");
+            }
+        }
+        source.push_str(lines[lines.len() - 1]);
+        assert!(!Parser::is_incomplete(&source, config));
+        assert_eq!(run_lines(&source), ["big"]);
+
+        assert!(Parser::is_incomplete("print (1 +", config));
+        assert!(Parser::is_incomplete("let list = [1, 2,", config));
+        assert!(!Parser::is_incomplete("let = ; if x {", config));
+        assert!(!Parser::is_incomplete("print 1 +;", config));
     }
 }
@@ -1,3 +1,57 @@
+use std::ops::Range;
+
+use bytecode::{
+    chunk::CodeChunk,
+    object::ObjectHeap,
+    vm::{RuntimeError, StackFrame, VM},
+};
+use compiler::parser::{Parser, ParserConfig, ParsingError};
+
 pub mod compiler;
 pub mod bytecode;
-pub mod cli;
\ No newline at end of file
+pub mod cli;
+
+#[derive(Debug)]
+pub enum RunError {
+    Parse(Vec<ParsingError>),
+    /// `output` holds everything printed before the error, `span` is where the innermost frame of `trace` failed
+    Runtime {
+        error: RuntimeError,
+        span: Range<usize>,
+        trace: Vec<StackFrame>,
+        output: String,
+    },
+}
+
+/// Compiles `source` into a fresh heap, ready to be run with [`VM::init`]
+pub fn compile_source(source: &str, config: ParserConfig) -> Result<(CodeChunk, ObjectHeap), Vec<ParsingError>> {
+    let mut code = CodeChunk::new();
+    let mut heap = ObjectHeap::new();
+    Parser::parse_source_with_config(source, &mut code, &mut heap, config)?;
+    Ok((code, heap))
+}
+
+/// Compiles and runs `source` with a fresh heap, returning everything it printed
+pub fn run_source(source: &str) -> Result<String, RunError> {
+    run_source_with_config(source, ParserConfig::default())
+}
+
+pub fn run_source_with_config(source: &str, config: ParserConfig) -> Result<String, RunError> {
+    let (code, mut heap) = compile_source(source, config).map_err(RunError::Parse)?;
+
+    let mut output = Vec::new();
+    let mut vm = VM::init_with_output(&code, &mut heap, &mut output);
+    let res = vm.run().map_err(|error| (error, vm.capture_stack_trace()));
+    drop(vm);
+
+    let output = String::from_utf8_lossy(&output).into_owned();
+    match res {
+        Ok(()) => Ok(output),
+        Err((error, trace)) => Err(RunError::Runtime {
+            error,
+            span: trace.first().map_or(0..0, |frame| frame.span.clone()),
+            trace,
+            output,
+        }),
+    }
+}
@@ -1,72 +1,340 @@
-use std::path::{Path, PathBuf};
+use std::{path::{Path, PathBuf}, process::ExitCode};
 
-use game_lang::{bytecode::{chunk::CodeChunk, object::ObjectHeap, vm::VM}, cli::reporter::{report_parsing_error, report_runtime_error}, compiler::parser::Parser};
+use rustyline::error::ReadlineError;
+
+use game_lang::{bytecode::{chunk::CodeChunk, object::ObjectHeap, vm::{RuntimeError, StackFrame, VM}}, cli::{color::ColorPolicy, commands::Command, debugger::{Debugger, HELP}, highlighter::ReplHighlighter, history::{default_history_path, load_history, save_history}, reporter::{report_parsing_errors, report_runtime_error, set_tab_width}, session::{default_rc_path, Session}}, compiler::parser::{Parser, ParserConfig}};
 
 #[derive(clap::Parser)]
 struct Args {
     #[arg(short,long)]
-    input: Option<PathBuf>
+    input: Option<PathBuf>,
+    /// Start the program by calling `main`, only declarations are allowed at the top level
+    #[arg(long)]
+    main: bool,
+    /// Only allow reassigning variables declared with `let mut`
+    #[arg(long)]
+    immutable_let: bool,
+    /// Run the peephole and constant folding passes over the compiled code
+    #[arg(short = 'O', long)]
+    optimize: bool,
+    /// Also write the compiled bytecode of the input to this path
+    #[arg(long)]
+    emit: Option<PathBuf>,
+    /// Only compile the input, with `--emit` the bytecode is written without running it
+    #[arg(long)]
+    no_run: bool,
+    /// Run a chunk previously written with `--emit`
+    #[arg(long, alias = "run")]
+    run_bytecode: Option<PathBuf>,
+    /// Startup script to run first, the REPL defaults to `~/.gamelangrc.gl`
+    #[arg(long)]
+    rc: Option<PathBuf>,
+    /// Don't run any startup script
+    #[arg(long)]
+    no_rc: bool,
+    /// Where the REPL keeps its history, defaults to `~/.gamelang_history`
+    #[arg(long)]
+    history: Option<PathBuf>,
+    /// Don't load or save the REPL history
+    #[arg(long)]
+    no_history: bool,
+    /// Print the disassembly of the input instead of running it
+    #[arg(short, long)]
+    disassemble: bool,
+    /// Write the disassembly of the input to this path instead of running it, always without colors
+    #[arg(long)]
+    disassemble_to: Option<PathBuf>,
+    /// Stop programs after this many instructions, the REPL defaults to 100 million
+    #[arg(long)]
+    max_steps: Option<u64>,
+    /// Step through the input one instruction at a time
+    #[arg(long)]
+    debug: bool,
+    /// Disable colored output, same as setting `NO_COLOR`
+    #[arg(long)]
+    no_color: bool,
+    /// Columns between tab stops when showing source in error reports, defaults to 4
+    #[arg(long)]
+    tab_width: Option<usize>,
 }
 
+/// Input data was malformed, here it failed to parse
+const EXIT_DATA_ERR: u8 = 65;
+/// The program failed while running
+const EXIT_SOFTWARE: u8 = 70;
+/// A file couldn't be read or written
+const EXIT_IO_ERR: u8 = 74;
+
+/// Generous enough for anything typed by hand, but a stuck loop still gives the prompt back
+const REPL_MAX_STEPS: u64 = 100_000_000;
+
 /// Simple REPL
-fn main() {
+fn main() -> ExitCode {
+    let args = <Args as clap::Parser>::parse();
+
+    // The debugger shows every instruction itself, tracing them too would bury its output
+    let trace_level = match args.debug {
+        true => log::LevelFilter::Info,
+        false => log::LevelFilter::Trace,
+    };
+    // Output to stdout uses `color`, reports and the log on stderr use the global policy
+    let (color, stderr_color) = match args.no_color {
+        true => (ColorPolicy::Never, ColorPolicy::Never),
+        false => (ColorPolicy::for_stream(&std::io::stdout()), ColorPolicy::for_stream(&std::io::stderr())),
+    };
+    stderr_color.set_global();
+    if let Some(tab_width) = args.tab_width {
+        set_tab_width(tab_width);
+    }
+    let log_style = match stderr_color {
+        ColorPolicy::Always => env_logger::WriteStyle::Always,
+        ColorPolicy::Never => env_logger::WriteStyle::Never,
+    };
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
-        .filter_module("game_lang", log::LevelFilter::Trace)
+        .filter_module("game_lang", trace_level)
         .format_timestamp(None)
+        .write_style(log_style)
         .init();
 
-    let args = <Args as clap::Parser>::parse();
-
-    if let Some(input_path) = args.input {
-        file(&input_path);
+    let disassemble_input = args.disassemble || args.disassemble_to.is_some();
+    let res = if let (true, Some(input_path)) = (disassemble_input, &args.input) {
+        let config = ParserConfig {
+            entry_main: args.main,
+            immutable_let: args.immutable_let,
+            optimize: args.optimize,
+            ..ParserConfig::default()
+        };
+        disassemble(input_path, config, args.disassemble_to.as_deref(), color)
+    } else if let (true, Some(input_path)) = (args.debug, &args.input) {
+        let config = ParserConfig {
+            entry_main: false,
+            immutable_let: args.immutable_let,
+            optimize: args.optimize,
+            ..ParserConfig::default()
+        };
+        debug(input_path, config, color)
+    } else if let Some(bytecode_path) = args.run_bytecode {
+        bytecode(&bytecode_path, args.main)
+    } else if let Some(input_path) = args.input {
+        let config = ParserConfig {
+            entry_main: args.main,
+            immutable_let: args.immutable_let,
+            optimize: args.optimize,
+            ..ParserConfig::default()
+        };
+        let rc = args.rc.filter(|_| !args.no_rc);
+        match args.no_run {
+            true => compile(&input_path, config, args.emit.as_deref()),
+            false => file(&input_path, config, args.emit.as_deref(), rc.as_deref(), args.max_steps),
+        }
     } else {
-        repl();
+        // The default rc file is optional, an explicitly requested one has to exist
+        let rc = match args.no_rc {
+            true => None,
+            false => args.rc.or_else(|| default_rc_path().filter(|path| path.exists())),
+        };
+        let history = match args.no_history {
+            true => None,
+            false => args.history.or_else(default_history_path),
+        };
+        let max_steps = args.max_steps.unwrap_or(REPL_MAX_STEPS);
+        repl(rc.as_deref(), history.as_deref(), color, max_steps);
+        Ok(())
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => ExitCode::from(code),
+    }
+}
+
+fn read_source(path: &Path) -> Result<String, u8> {
+    std::fs::read_to_string(path).map_err(|err| {
+        eprintln!("Can't read {}: {}", path.display(), err);
+        EXIT_IO_ERR
+    })
+}
+
+fn file(input_path: &Path, config: ParserConfig, emit: Option<&Path>, rc: Option<&Path>, max_steps: Option<u64>) -> Result<(), u8> {
+    let input = read_source(input_path)?;
+    let name = input_path.to_string_lossy();
+    let mut code = CodeChunk::new();
+    let mut session = Session::new().with_max_steps(max_steps);
+    if let Some(rc) = rc {
+        session.eval_file(rc);
+    }
+    let heap = session.heap_mut();
+
+    if let Err(errors) = Parser::parse_source_with_config(&input, &mut code, heap, config) {
+        report_parsing_errors(&name, &input, errors);
+        return Err(EXIT_DATA_ERR);
     }
+    if let Some(emit_path) = emit {
+        write_bytecode(emit_path, &code, heap)?;
+    }
+    if let Err((err, trace)) = execute(&code, heap, config.entry_main, max_steps) {
+        report_runtime_error(&name, &input, err, &trace);
+        return Err(EXIT_SOFTWARE);
+    }
+    Ok(())
 }
 
-fn file(input_path: &Path) {
-    let input = std::fs::read_to_string(input_path).unwrap();
+/// Compiles the input without running it, writing the bytecode to `emit` if it's given
+fn compile(input_path: &Path, config: ParserConfig, emit: Option<&Path>) -> Result<(), u8> {
+    let input = read_source(input_path)?;
     let name = input_path.to_string_lossy();
     let mut code = CodeChunk::new();
     let mut heap = ObjectHeap::new();
 
-    if let Err(errors) = Parser::parse_source(&input, &mut code, &mut heap) {
-        for err in errors {
-            report_parsing_error(&name, &input, err);
-        }
-        return;
+    if let Err(errors) = Parser::parse_source_with_config(&input, &mut code, &mut heap, config) {
+        report_parsing_errors(&name, &input, errors);
+        return Err(EXIT_DATA_ERR);
     }
-    let mut vm = VM::init(&code, &mut heap);
-    if let Err(err) = vm.run() {
-        report_runtime_error(&name, &input, err, vm.current_span())
+    match emit {
+        Some(emit_path) => write_bytecode(emit_path, &code, &heap),
+        None => Ok(()),
     }
 }
 
-fn repl() {
-    let mut rl = rustyline::DefaultEditor::new().unwrap();
+fn write_bytecode(path: &Path, code: &CodeChunk, heap: &ObjectHeap) -> Result<(), u8> {
+    std::fs::write(path, code.serialize(heap)).map_err(|err| {
+        eprintln!("Can't write {}: {}", path.display(), err);
+        EXIT_IO_ERR
+    })
+}
 
+fn disassemble(input_path: &Path, config: ParserConfig, output: Option<&Path>, color: ColorPolicy) -> Result<(), u8> {
+    let input = read_source(input_path)?;
+    let name = input_path.to_string_lossy();
+    let mut code = CodeChunk::new();
     let mut heap = ObjectHeap::new();
 
+    if let Err(errors) = Parser::parse_source_with_config(&input, &mut code, &mut heap, config) {
+        report_parsing_errors(&name, &input, errors);
+        return Err(EXIT_DATA_ERR);
+    }
+    let Some(output) = output else {
+        print!("{}", code.dissasemble().with_heap(&heap).with_color(color.enabled()));
+        return Ok(());
+    };
+    let dump = code.dissasemble().with_heap(&heap).with_color(false).to_string();
+    std::fs::write(output, dump).map_err(|err| {
+        eprintln!("Can't write {}: {}", output.display(), err);
+        EXIT_IO_ERR
+    })
+}
+
+fn debug(input_path: &Path, config: ParserConfig, color: ColorPolicy) -> Result<(), u8> {
+    let input = read_source(input_path)?;
+    let name = input_path.to_string_lossy();
+    let mut code = CodeChunk::new();
+    let mut heap = ObjectHeap::new();
+
+    if let Err(errors) = Parser::parse_source_with_config(&input, &mut code, &mut heap, config) {
+        report_parsing_errors(&name, &input, errors);
+        return Err(EXIT_DATA_ERR);
+    }
+    println!("{HELP}");
+    let mut vm = VM::init(&code, &mut heap);
+    if let Err(err) = Debugger::new().with_color(color.enabled()).run(&mut vm, std::io::stdin().lock(), &mut std::io::stdout()) {
+        let trace = vm.capture_stack_trace();
+        report_runtime_error(&name, &input, err, &trace);
+        return Err(EXIT_SOFTWARE);
+    }
+    Ok(())
+}
+
+fn bytecode(bytecode_path: &Path, entry_main: bool) -> Result<(), u8> {
+    let bytes = std::fs::read(bytecode_path).map_err(|err| {
+        eprintln!("Can't read {}: {}", bytecode_path.display(), err);
+        EXIT_IO_ERR
+    })?;
+    let mut heap = ObjectHeap::new();
+    let code = match CodeChunk::deserialize(&bytes, &mut heap) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Can't load {}: {}", bytecode_path.display(), err);
+            return Err(EXIT_DATA_ERR);
+        }
+    };
+    // There is no source to point into, so the span is dropped
+    if let Err((err, _)) = execute(&code, &mut heap, entry_main, None) {
+        eprintln!("Error: {}", err);
+        return Err(EXIT_SOFTWARE);
+    }
+    Ok(())
+}
+
+/// With `max_steps` the top level and `main` each get their own limit
+fn execute(code: &CodeChunk, heap: &mut ObjectHeap, entry_main: bool, max_steps: Option<u64>) -> Result<(), (RuntimeError, Vec<StackFrame>)> {
+    let mut vm = VM::init(code, heap).with_trace_color(ColorPolicy::global().enabled());
+    let mut res = match max_steps {
+        Some(max_steps) => vm.run_with_limit(max_steps),
+        None => vm.run(),
+    };
+    if res.is_ok() && entry_main {
+        res = match max_steps {
+            Some(max_steps) => vm.call_function_with_limit("main", &[], max_steps),
+            None => vm.call_function("main", &[]),
+        }
+        .map(|_| ());
+    }
+    res.map_err(|err| (err, vm.capture_stack_trace()))
+}
+
+fn repl(rc: Option<&Path>, history: Option<&Path>, color: ColorPolicy, max_steps: u64) {
+    let mut rl = rustyline::Editor::<ReplHighlighter, rustyline::history::DefaultHistory>::new().unwrap();
+    rl.set_helper(Some(ReplHighlighter::new(color)));
+    if let Some(history) = history {
+        load_history(rl.history_mut(), history);
+    }
+
+    let mut session = Session::new().with_max_steps(Some(max_steps)).with_color(color.enabled());
+    if let Some(rc) = rc {
+        session.eval_file(rc);
+    }
+
     loop {
-        let line = match rl.readline(">> "){
+        let mut line = match rl.readline(">> "){
             Ok(line) => line,
             Err(err) => { eprintln!("{}", err); break;}
         };
-
-        let mut code = CodeChunk::new();
-        if let Err(errors) = Parser::parse_source(&line, &mut code, &mut heap) {
-            for err in errors {
-                report_parsing_error("REPL", &line, err);
+        // Keep reading until the statement is complete, an empty line submits it as it is
+        if Command::parse(&line).is_none() {
+            while Parser::is_incomplete(&line, ParserConfig::default()) {
+                match rl.readline(".. ") {
+                    Ok(more) if more.trim().is_empty() => break,
+                    Ok(more) => {
+                        line.push('\n');
+                        line.push_str(&more);
+                    }
+                    Err(ReadlineError::Interrupted) => {
+                        line.clear();
+                        break;
+                    }
+                    Err(err) => { eprintln!("{}", err); break; }
+                }
             }
-            continue;
         }
 
-        log::info!("\n{}", code.dissasemble().with_heap(&heap));
-
-        let mut vm = VM::init(&code, &mut heap);
-        if let Err(err) = vm.run() {
-            report_runtime_error("REPL", &line, err, vm.current_span())
+        if !line.trim().is_empty() {
+            let _ = rl.add_history_entry(line.as_str());
+            if let Some(history) = history {
+                save_history(rl.history_mut(), history);
+            }
+        }
+        match Command::parse(&line) {
+            Some(command) => match session.command(command, &mut std::io::stdout()) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => { eprintln!("{}", err); break; }
+            },
+            None => { session.eval("REPL", &line); }
         }
     }
-}
\ No newline at end of file
+
+    if let Some(history) = history {
+        save_history(rl.history_mut(), history);
+    }
+}
@@ -0,0 +1,70 @@
+//! Runs the `game_lang` binary on small scripts and checks how it exits
+
+use std::{path::{Path, PathBuf}, process::Output};
+
+fn script(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("game_lang_cli_{}_{}", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+fn run(path: &Path) -> Output {
+    run_with(&["--input".as_ref(), path.as_os_str()])
+}
+
+fn run_with(args: &[&std::ffi::OsStr]) -> Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_game_lang"))
+        .args(args)
+        .arg("--no-color")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn exit_codes() {
+    let ok = script("ok.gl", "print 1 + 2;");
+    let output = run(&ok);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+
+    let parse_error = script("parse_error.gl", "let = 1;\nprint ;\nlet x = 2;");
+    let output = run(&parse_error);
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("aborting due to 2 previous errors"), "{stderr}");
+
+    let runtime_error = script("runtime_error.gl", "print 1 + nil;");
+    assert_eq!(run(&runtime_error).status.code(), Some(70));
+
+    let missing = std::env::temp_dir().join("game_lang_cli_does_not_exist.gl");
+    let output = run(&missing);
+    assert_eq!(output.status.code(), Some(74));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Can't read"));
+
+    for path in [ok, parse_error, runtime_error] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn emit_then_run() {
+    let source = script(
+        "emitted.gl",
+        "fn square(x) { return x * x; }\nlet xs = [1, 2, 3];\nprint square(xs[2]);\nprint \"done\" + 1;",
+    );
+    let bytecode = std::env::temp_dir().join(format!("game_lang_cli_{}_emitted.gbc", std::process::id()));
+
+    let output = run_with(&["--input".as_ref(), source.as_os_str(), "--emit".as_ref(), bytecode.as_os_str(), "--no-run".as_ref()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+
+    let emitted = run_with(&["--run".as_ref(), bytecode.as_os_str()]);
+    assert_eq!(emitted.status.code(), Some(0));
+    let direct = run(&source);
+    assert_eq!(String::from_utf8_lossy(&emitted.stdout), String::from_utf8_lossy(&direct.stdout));
+    assert_eq!(String::from_utf8_lossy(&direct.stdout), "9\ndone1\n");
+
+    for path in [source, bytecode] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
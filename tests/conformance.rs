@@ -0,0 +1,381 @@
+//! Conformance corpus, every case is a small program documenting one rule of the language.
+//!
+//! Cases pin what a program prints and how it fails, not how it's compiled, so changes to the
+//! compiler or the VM can't change semantics unnoticed. Adding a case is appending to [`CASES`].
+
+use game_lang::{compiler::parser::ParserConfig, run_source_with_config, RunError};
+
+/// Bumped whenever an existing case has to change because the language changed on purpose
+const CORPUS_VERSION: u32 = 8;
+
+struct Case {
+    name: &'static str,
+    source: Source,
+    /// Printed lines, including the ones printed before a runtime error
+    prints: &'static [&'static str],
+    error: Option<Error>,
+}
+
+enum Source {
+    Text(&'static str),
+    /// For programs too big to spell out
+    Generated(fn() -> String),
+}
+
+#[derive(Debug)]
+enum Error {
+    /// Some parsing error contains this message
+    Parse(&'static str),
+    /// The runtime error's message
+    Runtime(&'static str),
+}
+
+use Error::*;
+use Source::*;
+
+const CASES: &[Case] = &[
+    // ===== Arithmetic
+    Case { name: "precedence", source: Text("print 1 + 2 * 3;"), prints: &["7"], error: None },
+    Case { name: "parentheses", source: Text("print (1 + 2) * 3;"), prints: &["9"], error: None },
+    Case { name: "sub_left_assoc", source: Text("print 10 - 4 - 3;"), prints: &["3"], error: None },
+    Case { name: "div_left_assoc", source: Text("print 100 / 10 / 5;"), prints: &["2"], error: None },
+    Case { name: "unary_minus", source: Text("print -3 + 5;"), prints: &["2"], error: None },
+    Case { name: "double_negation", source: Text("print - -4;"), prints: &["4"], error: None },
+    Case { name: "unary_binds_tighter", source: Text("print -2 * 3;"), prints: &["-6"], error: None },
+    Case { name: "int_division_truncates", source: Text("print 7 / 2;"), prints: &["3"], error: None },
+    Case { name: "negative_int_division", source: Text("print -7 / 2;"), prints: &["-3"], error: None },
+    Case { name: "float_division", source: Text("print 7.0 / 2;"), prints: &["3.5"], error: None },
+    Case { name: "mixed_promotes", source: Text("print 1 + 0.5;"), prints: &["1.5"], error: None },
+    Case { name: "remainder", source: Text("print 7 % 3;"), prints: &["1"], error: None },
+    Case { name: "remainder_sign", source: Text("print -7 % 3;"), prints: &["-1"], error: None },
+    Case { name: "float_remainder", source: Text("print 7.5 % 2;"), prints: &["1.5"], error: None },
+    Case { name: "int_div_by_zero", source: Text("print 1 / 0;"), prints: &[], error: Some(Runtime("Division by zero")) },
+    Case { name: "int_rem_by_zero", source: Text("print 1 % 0;"), prints: &[], error: Some(Runtime("Division by zero")) },
+    Case { name: "float_div_by_zero", source: Text("print 1.0 / 0;"), prints: &["inf"], error: None },
+    Case { name: "negate_bool", source: Text("let b = true; print -b;"), prints: &[], error: Some(Runtime("Cannot negate Bool")) },
+    Case { name: "negate_bool_literal", source: Text("print -true;"), prints: &[], error: Some(Parse("Cannot negate Bool")) },
+    Case { name: "add_number_nil", source: Text("print 1 + nil;"), prints: &[], error: Some(Runtime("Cannot add Int and Nil")) },
+    Case { name: "int_literal_out_of_range", source: Text("print 99999999999999999999;"), prints: &[], error: Some(Parse("Integer literal out of range")) },
+    Case { name: "int_overflow_promotes", source: Text("print 9223372036854775807 + 1; print 4611686018427387904 * 4; let m = -9223372036854775807 - 1; print -m; print m - 1 is Number;"), prints: &["9.223372036854776e18", "1.8446744073709552e19", "9.223372036854776e18", "true"], error: None },
+    Case { name: "int_past_48_bits", source: Text("print 140737488355327 + 1; let l = [1, 2, 3]; print l[140737488355328 - 140737488355327]; print 9007199254740993; print -140737488355329 is Int;"), prints: &["140737488355328", "2", "9007199254740993", "true"], error: None },
+    Case { name: "pow_overflow_promotes", source: Text("print 2 ** 70; print 2 ** 62;"), prints: &["1.1805916207174113e21", "4611686018427387904"], error: None },
+    Case { name: "pow_right_associative", source: Text("print 2 ** 3 ** 2;"), prints: &["512"], error: None },
+    Case { name: "pow_binds_tighter_than_negation", source: Text("print -2 ** 2; print 2 * 3 ** 2;"), prints: &["-4", "18"], error: None },
+    Case { name: "pow_negative_exponent", source: Text("print 2 ** -2;"), prints: &["0.25"], error: None },
+    Case { name: "pow_nil", source: Text("print 2 ** nil;"), prints: &[], error: Some(Runtime("Cannot exponentiate Int and Nil")) },
+    Case { name: "integral_float_keeps_point", source: Text("print 5.0; print 10 / 2.0; print 10 / 2;"), prints: &["5.0", "5.0", "5"], error: None },
+    // ===== Comparison and equality
+    Case { name: "comparisons", source: Text("print 1 < 2; print 2 > 1; print 2 <= 2; print 3 >= 4;"), prints: &["true", "true", "true", "false"], error: None },
+    Case { name: "comparison_below_arithmetic", source: Text("print 1 + 1 < 3;"), prints: &["true"], error: None },
+    Case { name: "int_float_equal", source: Text("print 1 == 1.0;"), prints: &["true"], error: None },
+    Case { name: "not_equal", source: Text("print 1 != 2;"), prints: &["true"], error: None },
+    Case { name: "nil_equal", source: Text("print nil == nil;"), prints: &["true"], error: None },
+    Case { name: "bool_equal", source: Text("print true == false;"), prints: &["false"], error: None },
+    Case { name: "mismatched_equal", source: Text("print 1 == nil;"), prints: &[], error: Some(Runtime("Cannot compare Int and Nil")) },
+    Case { name: "compare_bools", source: Text("let t = true; print t < false;"), prints: &[], error: Some(Runtime("Cannot compare Bool and Bool")) },
+    Case { name: "compare_bool_literals", source: Text("print true < false;"), prints: &[], error: Some(Parse("Cannot compare Bool and Bool")) },
+    // ===== Strings
+    Case { name: "string_print", source: Text(r#"print "hello";"#), prints: &["hello"], error: None },
+    Case { name: "string_concat", source: Text(r#"print "a" + "b";"#), prints: &["ab"], error: None },
+    Case { name: "string_concat_chain", source: Text(r#"let b = "b"; print "a" + b + "c" + "d";"#), prints: &["abcd"], error: None },
+    Case { name: "string_interning_equality", source: Text(r#"print "ab" == "a" + "b";"#), prints: &["true"], error: None },
+    Case { name: "string_inequality", source: Text(r#"print "a" == "b";"#), prints: &["false"], error: None },
+    Case { name: "string_ordering", source: Text(r#"print "apple" < "banana"; print "b" > "abc";"#), prints: &["true", "true"], error: None },
+    Case { name: "string_common_prefix", source: Text(r#"print "ab" < "abc"; print "abc" <= "ab";"#), prints: &["true", "false"], error: None },
+    Case { name: "string_compare_number", source: Text(r#"let a = "a"; print a < 1;"#), prints: &[], error: Some(Runtime("Cannot compare String and Int")) },
+    Case { name: "string_literal_subtraction", source: Text(r#"print 1; print "a" - "b";"#), prints: &[], error: Some(Parse("Cannot subtract String and String")) },
+    Case { name: "folded_literals", source: Text(r#"print 2 * 3.14 * 10; print "n" + 1 + 2 * 3; print !(1 < 2) == false;"#), prints: &["62.800000000000004", "n16", "true"], error: None },
+    Case { name: "unreached_literal_division_by_zero", source: Text("if false { print 1 / 0; } print 2;"), prints: &["2"], error: None },
+    Case { name: "string_plus_number", source: Text(r#"print "a" + 1;"#), prints: &["a1"], error: None },
+    Case { name: "number_plus_string", source: Text(r#"print 1.5 + "a";"#), prints: &["1.5a"], error: None },
+    Case { name: "string_plus_bool_and_nil", source: Text(r#"print "" + true + nil;"#), prints: &["truenil"], error: None },
+    Case { name: "string_concat_chain_numbers", source: Text(r#"let n = 2; print "score: " + 1 + n + " of " + 3.5;"#), prints: &["score: 12 of 3.5"], error: None },
+    Case { name: "number_sum_before_string", source: Text(r#"print 1 + 2 + "a";"#), prints: &["3a"], error: None },
+    Case { name: "string_plus_list", source: Text(r#"print "a" + [1];"#), prints: &[], error: Some(Runtime("Cannot add String and List")) },
+    Case { name: "str_native", source: Text(r#"print str(1.5) == "1.5"; print str(nil) + str([1, "a"]);"#), prints: &["true", r#"nil[1, "a"]"#], error: None },
+    Case { name: "str_wrong_argument_count", source: Text("str(1, 2);"), prints: &[], error: Some(Runtime("Expected 1 arguments but got 2")) },
+    Case { name: "write_joins_lines", source: Text(r#"write "a"; write 1; print "b"; print "c";"#), prints: &["a1b", "c"], error: None },
+    Case { name: "write_needs_value", source: Text("write;"), prints: &[], error: Some(Parse("Unexpected token: Semicolon")) },
+    // ===== Truthiness and logic
+    Case { name: "not_nil", source: Text("print !nil;"), prints: &["true"], error: None },
+    Case { name: "not_zero", source: Text("print !0;"), prints: &["false"], error: None },
+    Case { name: "not_empty_string", source: Text(r#"print !"";"#), prints: &["false"], error: None },
+    Case { name: "double_not", source: Text("print !!false;"), prints: &["false"], error: None },
+    Case { name: "zero_is_truthy", source: Text(r#"if 0 { print "yes"; } else { print "no"; }"#), prints: &["yes"], error: None },
+    Case { name: "nil_is_falsey", source: Text(r#"if nil { print "yes"; } else { print "no"; }"#), prints: &["no"], error: None },
+    Case { name: "nil_stops_while", source: Text("let x = 1; while x { print x; x = nil; }"), prints: &["1"], error: None },
+    Case { name: "ternary_truthiness", source: Text("print nil ? 1 : 2; print 0 ? 1 : 2;"), prints: &["2", "1"], error: None },
+    Case { name: "is_nil", source: Text("print is_nil(nil); print is_nil(false); print !false; print is_nil(0);"), prints: &["true", "false", "true", "false"], error: None },
+    Case { name: "and_or", source: Text("print true and false; print false or true;"), prints: &["false", "true"], error: None },
+    Case { name: "and_binds_tighter_than_or", source: Text("print true or true and false;"), prints: &["true"], error: None },
+    Case { name: "and_requires_bools", source: Text("print 1 and true;"), prints: &[], error: Some(Runtime("Cannot apply 'and' to Int and Bool")) },
+    // ===== Globals
+    Case { name: "global_define", source: Text("let a = 1; print a;"), prints: &["1"], error: None },
+    Case { name: "global_default_nil", source: Text("let a; print a;"), prints: &["nil"], error: None },
+    Case { name: "global_assign", source: Text("let a = 1; a = 2; print a;"), prints: &["2"], error: None },
+    Case { name: "global_redefine", source: Text("let a = 1; let a = 2; print a;"), prints: &["2"], error: None },
+    Case { name: "assignment_is_expression", source: Text("let a; print a = 3;"), prints: &["3"], error: None },
+    Case { name: "chained_assignment", source: Text("let a; let b; a = b = 4; print a; print b;"), prints: &["4", "4"], error: None },
+    Case { name: "undefined_global", source: Text("print missing;"), prints: &[], error: Some(Runtime("Undefined global variable 'missing'")) },
+    Case { name: "assign_undefined_global", source: Text("missing = 1;"), prints: &[], error: Some(Runtime("Undefined global variable 'missing'")) },
+    Case { name: "invalid_assignment_target", source: Text("let a; 1 + a = 2;"), prints: &[], error: Some(Parse("Invalid left side of assignment")) },
+    Case { name: "compound_assignment", source: Text("let x = 1; x += 4; print x; x -= 2; print x; x *= 6; print x; x /= 4; print x; x %= 2; print x;"), prints: &["5", "3", "18", "4", "0"], error: None },
+    Case { name: "compound_assignment_is_expression", source: Text("let x = 1; print x += 1; print x;"), prints: &["2", "2"], error: None },
+    Case { name: "compound_assignment_concat", source: Text(r#"let s = "a"; s += 1; print s;"#), prints: &["a1"], error: None },
+    Case { name: "compound_assignment_undefined", source: Text("missing += 1;"), prints: &[], error: Some(Runtime("Undefined global variable 'missing'")) },
+    Case { name: "compound_assignment_in_expression", source: Text("let x = 1; print 1 + x += 1;"), prints: &[], error: Some(Parse("Invalid left side of assignment")) },
+    Case { name: "local_increment", source: Text("{ let i = 0; while i < 5 { i = i + 1; } i += 0.5; print i; print i += 1; }"), prints: &["5.5", "6.5"], error: None },
+    Case { name: "local_increment_string", source: Text(r#"{ let s = "a"; s += 1; s = s + 2; print s; }"#), prints: &["a12"], error: None },
+    Case { name: "local_increment_nil", source: Text("{ let x; x += 1; }"), prints: &[], error: Some(Runtime("Cannot add Nil and Int")) },
+    // ===== Locals and scoping
+    Case { name: "block_local", source: Text("{ let a = 1; print a; }"), prints: &["1"], error: None },
+    Case { name: "local_assign", source: Text("{ let a = 1; a = a + 1; print a; }"), prints: &["2"], error: None },
+    Case { name: "local_shadows_global", source: Text("let a = 1; { let a = 2; print a; } print a;"), prints: &["2", "1"], error: None },
+    Case { name: "nested_shadowing", source: Text("{ let a = 1; { let a = 2; print a; } print a; }"), prints: &["2", "1"], error: None },
+    Case { name: "same_scope_shadowing", source: Text("{ let a = 1; let a = a + 1; print a; }"), prints: &["2"], error: None },
+    Case { name: "local_out_of_scope", source: Text("{ let a = 1; } print a;"), prints: &[], error: Some(Runtime("Undefined global variable 'a'")) },
+    Case { name: "locals_keep_slots", source: Text("{ let a = 1; let b = 2; { let c = 3; } let d = 4; print a + b + d; }"), prints: &["7"], error: None },
+    // ===== Control flow
+    Case { name: "if_else", source: Text(r#"if 1 < 2 { print "then"; } else { print "else"; }"#), prints: &["then"], error: None },
+    Case { name: "nested_if", source: Text("let a = 2; if a == 1 { print 1; } else { if a == 2 { print 2; } else { print 3; } }"), prints: &["2"], error: None },
+    Case { name: "while_counts", source: Text("let i = 0; while i < 3 { print i; i = i + 1; }"), prints: &["0", "1", "2"], error: None },
+    Case { name: "while_false_never_runs", source: Text("while false { print 1; } print 2;"), prints: &["2"], error: None },
+    Case { name: "while_let_until_nil", source: Text("let n = 2; fn next() { if n == 0 { return nil; } n = n - 1; return n; } while let x = next() { print x; }"), prints: &["1", "0"], error: None },
+    Case { name: "while_let_false_keeps_going", source: Text("let n = 0; fn next() { n = n + 1; if n == 3 { return nil; } return false; } while let x = next() { print x; }"), prints: &["false", "false"], error: None },
+    Case { name: "while_let_binding_scoped", source: Text("fn next() { return nil; } while let x = next() { } print x;"), prints: &[], error: Some(Runtime("Undefined global variable 'x'")) },
+    Case { name: "break_pops_locals", source: Text("let i = 0; while true { let a = i; i = i + 1; { let b = a; if b == 2 { break; } } } print i;"), prints: &["3"], error: None },
+    Case { name: "continue_skips_rest", source: Text("let i = 0; while i < 4 { i = i + 1; if i % 2 == 0 { continue; } print i; }"), prints: &["1", "3"], error: None },
+    Case { name: "break_inner_loop_only", source: Text("let i = 0; while i < 2 { i = i + 1; while true { break; } print i; }"), prints: &["1", "2"], error: None },
+    Case { name: "break_while_let", source: Text("fn one() { return 1; } while let x = one() { let y = x + 1; print y; break; } print 0;"), prints: &["2", "0"], error: None },
+    Case { name: "while_body_scoped", source: Text("let i = 0; while i < 2 { let a = i; i = i + 1; } { let b = 7; print b; }"), prints: &["7"], error: None },
+    Case { name: "break_outside_loop", source: Text("break;"), prints: &[], error: Some(Parse("Can't use 'break' outside of a loop")) },
+    Case { name: "continue_outside_loop", source: Text("continue;"), prints: &[], error: Some(Parse("Can't use 'continue' outside of a loop")) },
+    Case { name: "break_in_function_inside_loop", source: Text("while true { fn f() { break; } }"), prints: &[], error: Some(Parse("Can't use 'break' outside of a loop")) },
+    Case { name: "continue_inner_loop_only", source: Text("let i = 0; let n = 0; while i < 3 { i = i + 1; let j = 0; while j < 3 { j = j + 1; if j == 2 { continue; } n = n + 1; } } print n;"), prints: &["6"], error: None },
+    Case { name: "if_body_scoped", source: Text("if true { let a = 1; } { let b = 2; print b; }"), prints: &["2"], error: None },
+    Case { name: "skipped_if_body_scoped", source: Text("{ if false { let a = 1; } let b = 2; print b; }"), prints: &["2"], error: None },
+    Case { name: "if_expression", source: Text("print if 1 > 2 { \"a\" } else { \"b\" };"), prints: &["b"], error: None },
+    Case { name: "if_expression_else_if", source: Text("let n = 0; print if n < 0 { -1 } else if n == 0 { 0 } else { 1 };"), prints: &["0"], error: None },
+    Case { name: "if_expression_requires_else", source: Text("print if true { 1 };"), prints: &[], error: Some(Parse("Expected else")) },
+    Case { name: "defer_lifo", source: Text("{ defer print 1; defer print 2; print 3; }"), prints: &["3", "2", "1"], error: None },
+    Case { name: "defer_on_return", source: Text("fn f() { defer print \"done\"; return \"value\"; } print f();"), prints: &["done", "value"], error: None },
+    Case { name: "defer_sees_later_state", source: Text("{ let a = 1; defer print a; a = 2; }"), prints: &["2"], error: None },
+    Case { name: "defer_on_continue", source: Text("let i = 0; while i < 2 { i = i + 1; defer print i; continue; }"), prints: &["1", "2"], error: None },
+    Case { name: "defer_top_level", source: Text("defer print 1; print 2;"), prints: &["2", "1"], error: None },
+    Case { name: "defer_only_simple_statements", source: Text("{ defer let a = 1; }"), prints: &[], error: Some(Parse("Only expressions and print statements can be deferred")) },
+    Case { name: "ternary", source: Text("print 1 < 2 ? 10 : 20;"), prints: &["10"], error: None },
+    Case { name: "ternary_right_assoc", source: Text("print true ? 1 : false ? 2 : 3;"), prints: &["1"], error: None },
+    Case { name: "ternary_only_evaluates_taken_branch", source: Text("print nil ? missing : 2;"), prints: &["2"], error: None },
+    Case { name: "ternary_nested_in_else", source: Text("let x = 3; print x == 1 ? 10 : x == 2 ? 20 : 30;"), prints: &["30"], error: None },
+    Case { name: "ternary_leaves_one_value", source: Text("fn f(a) { return a ? 1 : 2; } print f(true) + f(nil);"), prints: &["3"], error: None },
+    Case { name: "ternary_assign_in_then", source: Text("let x = 0; true ? x = 1 : 2; print x;"), prints: &["1"], error: None },
+    Case { name: "ternary_assign_in_else", source: Text("let x = 0; false ? 1 : x = 2;"), prints: &[], error: Some(Parse("Invalid left side of assignment")) },
+    Case { name: "ternary_missing_colon", source: Text("print true ? 1;"), prints: &[], error: Some(Parse("Expected Some(Colon)")) },
+    Case { name: "jump_too_long", source: Generated(jump_too_long), prints: &[], error: Some(Parse("Jump too long")) },
+    Case { name: "assert_passes", source: Text("let x = 2; assert x == 2; assert x, \"unused\"; print x;"), prints: &["2"], error: None },
+    Case { name: "assert_fails", source: Text("let x = 1; print 0; assert x == 2; print 1;"), prints: &["0"], error: Some(Runtime("Assertion failed")) },
+    Case { name: "assert_message", source: Text(r#"let hp = 0; assert hp > 0, "hp is " + hp;"#), prints: &[], error: Some(Runtime("Assertion failed: hp is 0")) },
+    Case { name: "assert_nil", source: Text("assert nil;"), prints: &[], error: Some(Runtime("Assertion failed")) },
+    Case { name: "assert_requires_expression", source: Text("assert;"), prints: &[], error: Some(Parse("Unexpected token: Semicolon")) },
+    Case { name: "else_if_chain", source: Text("let x = 2; if x == 1 { print 1; } else if x == 2 { print 2; } else { print 3; }"), prints: &["2"], error: None },
+    Case { name: "elif_chain", source: Text("let x = 3; if x == 1 { print 1; } elif x == 2 { print 2; } elif x == 3 { print 3; } else { print 4; }"), prints: &["3"], error: None },
+    Case { name: "elif_without_if", source: Text("elif true { print 1; }"), prints: &[], error: Some(Parse("Unexpected token: Elif")) },
+    // ===== Functions
+    Case { name: "function_call", source: Text("fn add(a, b) { return a + b; } print add(1, 2);"), prints: &["3"], error: None },
+    Case { name: "implicit_nil_return", source: Text("fn f() { } print f();"), prints: &["nil"], error: None },
+    Case { name: "bare_return", source: Text("fn f(a) { if a { return; } print 1; } print f(true); f(false);"), prints: &["nil", "1"], error: None },
+    Case { name: "return_discards_locals", source: Text("fn f(a) { let b = a + 1; { let c = b * 2; while true { let d = c; return d; } } } let x = 1; print f(x) + f(x); print x;"), prints: &["8", "1"], error: None },
+    Case { name: "return_in_nested_block_at_top_level", source: Text("{ if true { return 1; } }"), prints: &[], error: Some(Parse("Can't return from top-level code")) },
+    Case { name: "argument_order", source: Text("fn sub(a, b) { return a - b; } print sub(5, 2);"), prints: &["3"], error: None },
+    Case { name: "recursion", source: Text("fn fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); } print fib(15);"), prints: &["610"], error: None },
+    Case { name: "functions_are_values", source: Text("fn twice(f, x) { return f(f(x)); } fn inc(x) { return x + 1; } print twice(inc, 1);"), prints: &["3"], error: None },
+    Case { name: "function_print", source: Text("fn f() { } print f;"), prints: &["<fn f>"], error: None },
+    Case { name: "local_function", source: Text("{ fn f() { return 1; } print f(); }"), prints: &["1"], error: None },
+    Case { name: "no_captures", source: Text("{ let a = 1; fn f() { return a; } f(); }"), prints: &[], error: Some(Runtime("Undefined global variable 'a'")) },
+    Case { name: "prints_before_error", source: Text("fn f() { print 1; return nil + 1; } f();"), prints: &["1"], error: Some(Runtime("Cannot add Nil and Int")) },
+    Case { name: "wrong_argument_count", source: Text("fn f(a) { } f(1, 2);"), prints: &[], error: Some(Runtime("Expected 1 arguments but got 2")) },
+    Case { name: "call_non_function", source: Text("let a = 1; a();"), prints: &[], error: Some(Runtime("Cannot call Int")) },
+    Case { name: "call_string", source: Text(r#""f"();"#), prints: &[], error: Some(Runtime("Cannot call String")) },
+    Case { name: "unbounded_recursion", source: Text("fn f() { return f(); } f();"), prints: &[], error: Some(Runtime("Stack overflow")) },
+    Case { name: "top_level_return", source: Text("return 1;"), prints: &[], error: Some(Parse("Can't return from top-level code")) },
+    Case { name: "bind_first_argument", source: Text("fn sub(a, b) { return a - b; } let from_ten = bind(sub, 10); print from_ten(3); print from_ten;"), prints: &["7", "<bound <fn sub>>"], error: None },
+    Case { name: "method_reference", source: Text("let l = [1]; let pop = l.pop; print pop(); print l;"), prints: &["1", "[]"], error: None },
+    // ===== Lists
+    Case { name: "list_literal", source: Text("print [1, 2, 3];"), prints: &["[1, 2, 3]"], error: None },
+    Case { name: "list_index", source: Text("let l = [10, 20]; print l[1];"), prints: &["20"], error: None },
+    Case { name: "list_index_assign", source: Text("let l = [0]; print l[0] = 5; print l;"), prints: &["5", "[5]"], error: None },
+    Case { name: "list_shared_by_reference", source: Text("let a = [1]; let b = a; b[0] = 2; print a[0];"), prints: &["2"], error: None },
+    Case { name: "list_nested_index", source: Text("let l = [[1, 2], [3]]; l[0][1] = 9; print l;"), prints: &["[[1, 9], [3]]"], error: None },
+    Case { name: "list_quotes_strings", source: Text("print [\"a\"];"), prints: &["[\"a\"]"], error: None },
+    Case { name: "list_contains_itself", source: Text("let l = [0]; l[0] = l; print l;"), prints: &["[[[[[[[[[...]]]]]]]]]"], error: None },
+    Case { name: "list_repeat", source: Text("print [nil; 3]; print [5; 4]; print [1; 0];"), prints: &["[nil, nil, nil]", "[5, 5, 5, 5]", "[]"], error: None },
+    Case { name: "list_repeat_shares_objects", source: Text("let grid = [[0; 2]; 2]; grid[0][0] = 1; print grid;"), prints: &["[[1, 0], [1, 0]]"], error: None },
+    Case { name: "list_repeat_negative", source: Text("print [0; -1];"), prints: &[], error: Some(Runtime("List repeat count can't be negative, got -1")) },
+    Case { name: "list_repeat_huge_count", source: Text("print len([0; 70368744177664]);"), prints: &[], error: Some(Runtime("Out of memory")) },
+    Case { name: "list_repeat_float_count", source: Text("print [0; 2.0];"), prints: &[], error: Some(Runtime("List repeat count must be Int, not Number")) },
+    Case { name: "list_index_out_of_bounds", source: Text("print [1][1];"), prints: &[], error: Some(Runtime("Index 1 is out of bounds for a list of length 1")) },
+    Case { name: "list_negative_index", source: Text("print [1][-1];"), prints: &[], error: Some(Runtime("Index -1 is out of bounds for a list of length 1")) },
+    Case { name: "list_float_index", source: Text("print [1][0.0];"), prints: &[], error: Some(Runtime("List indices must be Int, not Number")) },
+    Case { name: "index_non_list", source: Text("print 1[0];"), prints: &[], error: Some(Runtime("Cannot index Int")) },
+    Case { name: "index_assign_in_expression", source: Text("let l = [1]; print 1 + l[0] = 2;"), prints: &[], error: Some(Parse("Invalid left side of assignment")) },
+    Case { name: "string_index", source: Text(r#"print "héllo"[1] + "abc"[2];"#), prints: &["éc"], error: None },
+    Case { name: "string_index_out_of_bounds", source: Text(r#"print "ab"[-1];"#), prints: &[], error: Some(Runtime("Index -1 is out of bounds for a string of length 2")) },
+    Case { name: "string_float_index", source: Text(r#"print "ab"[1.0];"#), prints: &[], error: Some(Runtime("String indices must be Int, not Number")) },
+    Case { name: "string_index_assign", source: Text(r#"let s = "ab"; s[0] = "c";"#), prints: &[], error: Some(Runtime("Cannot assign to an index of String")) },
+    Case { name: "len_native", source: Text(r#"print len("héllo"); print len([]); print len({1: 2});"#), prints: &["5", "0", "1"], error: None },
+    Case { name: "len_of_number", source: Text("print len(1);"), prints: &[], error: Some(Runtime("Cannot take the length of Int")) },
+    Case { name: "float_list_print", source: Text("print [0.1, 0.2]; print [0.1 + 0.2, 1.0, -0.0, 0.0000001];"), prints: &["[0.1, 0.2]", "[0.30000000000000004, 1.0, -0.0, 1e-7]"], error: None },
+    // ===== Maps
+    Case { name: "map_literal", source: Text(r#"print {"a": 1, "b": [2]};"#), prints: &[r#"{"a": 1, "b": [2]}"#], error: None },
+    Case { name: "map_prints_sorted", source: Text(r#"print {"b": 1, "a": 2, 10: 0, 9.5: 0, -1: 0, true: 0, nil: 0};"#), prints: &[r#"{nil: 0, true: 0, -1: 0, 9.5: 0, 10: 0, "a": 2, "b": 1}"#], error: None },
+    Case { name: "map_set_and_get", source: Text(r#"let m = {}; m["k"] = 1; m["k"] = m["k"] + 1; print m["k"];"#), prints: &["2"], error: None },
+    Case { name: "map_int_and_float_keys", source: Text("let m = {1: \"one\"}; print m[1.0];"), prints: &["one"], error: None },
+    Case { name: "map_nested", source: Text(r#"let m = {"inner": {"x": 1}}; m["inner"]["x"] = 5; print m["inner"]["x"];"#), prints: &["5"], error: None },
+    Case { name: "map_missing_key", source: Text(r#"print {"a": 1}["b"];"#), prints: &["nil"], error: None },
+    Case { name: "map_field_get", source: Text(r#"let m = {"x": 1}; print m.x;"#), prints: &["1"], error: None },
+    Case { name: "map_field_set_chain", source: Text(r#"let m = {"a": {"b": {}}}; m.a.b.c = 1; print m.a.b.c; print m;"#), prints: &["1", r#"{"a": {"b": {"c": 1}}}"#], error: None },
+    Case { name: "map_field_missing", source: Text(r#"print {"a": 1}.b;"#), prints: &[], error: Some(Runtime(r#"Key "b" not found in the map"#)) },
+    Case { name: "map_field_shadows_method", source: Text(r#"let m = {"len": 5}; print m.len; print m.len();"#), prints: &["5", "1"], error: None },
+    Case { name: "set_field_of_non_map", source: Text("let x = 1; x.y = 2;"), prints: &[], error: Some(Runtime("Cannot set a field of Int")) },
+    Case { name: "get_field_of_non_map", source: Text("let x = 1; print x.y;"), prints: &[], error: Some(Runtime("Int has no method 'y'")) },
+    Case { name: "field_assign_in_expression", source: Text("let m = {}; print 1 + m.a = 2;"), prints: &[], error: Some(Parse("Invalid left side of assignment")) },
+    Case { name: "map_missing_colon", source: Text(r#"print {"a" 1};"#), prints: &[], error: Some(Parse("Colon")) },
+    // ===== Sets
+    Case { name: "set_literal_dedups", source: Text("let s = {1, 1, 2}; print set_len(s); print s is Set;"), prints: &["2", "true"], error: None },
+    Case { name: "set_membership", source: Text("let s = {1, 2,}; print set_contains(s, 1.0); print set_contains(s, 3);"), prints: &["true", "false"], error: None },
+    Case { name: "set_remove", source: Text("let s = {3, 1}; print set_remove(s, 3); print s; print set_remove(s, 3);"), prints: &["true", "{1}", "false"], error: None },
+    Case { name: "set_prints_sorted", source: Text(r#"print {"b", "a"}; print {-1.5, 2.5, 0.5, 2};"#), prints: &[r#"{"a", "b"}"#, "{-1.5, 0.5, 2, 2.5}"], error: None },
+    Case { name: "set_single_element", source: Text(r#"print {"x"};"#), prints: &[r#"{"x"}"#], error: None },
+    Case { name: "empty_braces_are_map", source: Text("print {} is Map;"), prints: &["true"], error: None },
+    Case { name: "set_then_entry", source: Text("print {1, 2: 3};"), prints: &[], error: Some(Parse("BraceClose")) },
+    // ===== Classes
+    Case { name: "class_init_and_method", source: Text("class Point { fn init(x, y) { this.x = x; this.y = y; } fn sum() { return this.x + this.y; } } let p = Point(1, 2); print p.sum(); print p.x;"), prints: &["3", "1"], error: None },
+    Case { name: "class_method_takes_instance", source: Text("class V { fn init(x) { this.x = x; } fn dot(o) { return this.x * o.x; } } print V(2).dot(V(3));"), prints: &["6"], error: None },
+    Case { name: "class_without_init", source: Text("class Bag {} let b = Bag(); b.items = 2; print b.items; print b; print Bag;"), prints: &["2", "<Bag instance>", "<class Bag>"], error: None },
+    Case { name: "class_init_returns_instance", source: Text("class C { fn init() { this.a = 1; return; } } print C().init().a;"), prints: &["1"], error: None },
+    Case { name: "class_method_reference", source: Text("class C { fn init(n) { this.n = n; } fn get() { return this.n; } } let get = C(4).get; print get();"), prints: &["4"], error: None },
+    Case { name: "class_field_shadows_method", source: Text("fn seven() { return 7; } class C { fn f() { return 1; } } let c = C(); c.f = seven; print c.f();"), prints: &["7"], error: None },
+    Case { name: "class_is_type", source: Text("class A {} class B {} let a = A(); print a is A; print a is B; print a is Instance; print A is Class;"), prints: &["true", "false", "true", "true"], error: None },
+    Case { name: "class_local", source: Text("{ class L { fn one() { return 1; } } print L().one(); }"), prints: &["1"], error: None },
+    Case { name: "class_missing_field", source: Text("class P {} print P().x;"), prints: &[], error: Some(Runtime("P has no field or method 'x'")) },
+    Case { name: "class_init_arity", source: Text("class P { fn init(x) {} } P();"), prints: &[], error: Some(Runtime("Expected 1 arguments but got 0")) },
+    Case { name: "class_no_init_arity", source: Text("class P {} P(1);"), prints: &[], error: Some(Runtime("Expected 0 arguments but got 1")) },
+    Case { name: "this_at_top_level", source: Text("print this;"), prints: &[], error: Some(Parse("Can't use 'this' outside of a method")) },
+    Case { name: "this_in_function", source: Text("fn f() { return this; }"), prints: &[], error: Some(Parse("Can't use 'this' outside of a method")) },
+    Case { name: "init_returns_value", source: Text("class C { fn init() { return 1; } }"), prints: &[], error: Some(Parse("Can't return a value from 'init'")) },
+    Case { name: "class_body_only_methods", source: Text("class C { let x = 1; }"), prints: &[], error: Some(Parse("Fn")) },
+    // ===== Types
+    Case { name: "is_exact_type", source: Text(r#"print 1 is Int; print 1.5 is Number; print "a" is String; print nil is Nil;"#), prints: &["true", "true", "true", "true"], error: None },
+    Case { name: "is_other_type", source: Text(r#"class Point {} print 1 is Number; print [] is Map; print "a" is Point;"#), prints: &["false", "false", "false"], error: None },
+    Case { name: "is_class_identity", source: Text("class A {} let B = A; let a = A(); class A {} print a is B; print a is A; print A() is A;"), prints: &["true", "false", "true"], error: None },
+    Case { name: "is_undefined_class", source: Text("print 1 is Nonexistent;"), prints: &[], error: Some(Runtime("Undefined global variable 'Nonexistent'")) },
+    Case { name: "is_not_a_class", source: Text("let x = 1; print x is x;"), prints: &[], error: Some(Runtime("Right side of 'is' must be a class or a type, not Int")) },
+    Case { name: "is_functions", source: Text("fn f() {} print f is Function; print len is Function;"), prints: &["true", "true"], error: None },
+    Case { name: "is_binds_like_comparison", source: Text("print 1 + 1 is Int == true;"), prints: &["true"], error: None },
+    Case { name: "is_needs_type_name", source: Text("print 1 is 2;"), prints: &[], error: Some(Parse("Identifier")) },
+    // ===== Coroutines
+    Case { name: "yield_resumes_with_nil", source: Text("print yield 1; print 2;"), prints: &["nil", "2"], error: None },
+    Case { name: "yield_without_value", source: Text("fn f() { yield; return 1; } print f();"), prints: &["1"], error: None },
+    Case { name: "coroutine_interleaved", source: Text("fn f() { let x = yield 1; yield x + 1; return 0; } let a = coroutine(f); let b = coroutine(f); print resume(a, nil); print resume(b, nil); print resume(a, 10); print resume(b, 20); print resume(a, nil); print coroutine_done(a);"), prints: &["1", "1", "11", "21", "0", "true"], error: None },
+    Case { name: "coroutine_resume_without_value", source: Text("fn f() { let x = yield 1; print x; yield 2; } let co = coroutine(f); print resume(co); print resume(co); print resume(co, 1, 2);"), prints: &["1", "nil", "2"], error: Some(Runtime("Expected 2 arguments but got 3")) },
+    Case { name: "coroutine_resume_finished", source: Text("fn f() {} let co = coroutine(f); resume(co, nil); resume(co, nil);"), prints: &[], error: Some(Runtime("Can't resume a coroutine that has finished")) },
+    // ===== Syntax
+    Case { name: "comments_ignored", source: Text("// nothing here\nprint 1; // trailing"), prints: &["1"], error: None },
+    Case { name: "missing_semicolon", source: Text("print 1"), prints: &[], error: Some(Parse("Expected Some(Semicolon)")) },
+    Case { name: "unexpected_token", source: Text("print *;"), prints: &[], error: Some(Parse("Unexpected token")) },
+    Case { name: "too_many_locals", source: Generated(too_many_locals), prints: &[], error: Some(Parse("Too many local variables (65535)")) },
+    Case { name: "too_many_constants", source: Generated(too_many_constants), prints: &[], error: Some(Parse("Too many constants")) },
+    Case { name: "repeated_identifier_shares_constant", source: Generated(repeated_identifier), prints: &["1"], error: None },
+    Case { name: "max_constants", source: Generated(max_constants), prints: &["32640"], error: None },
+];
+
+/// A branch whose body is too long for a 16 bit jump, without using any constants
+fn jump_too_long() -> String {
+    format!("if true {{ {} }}", "print nil;".repeat(20_000))
+}
+
+/// Exactly as many constants as a chunk can hold
+fn max_constants() -> String {
+    let terms: Vec<String> = (0..256).map(|i| i.to_string()).collect();
+    format!("print {};", terms.join(" + "))
+}
+
+/// Far more uses of one global than a chunk has constant slots
+fn repeated_identifier() -> String {
+    format!("let x = 1; {} print x;", "x = x;".repeat(300))
+}
+
+fn too_many_constants() -> String {
+    (0..257).map(|i| format!("print {i};")).collect()
+}
+
+/// One block declaring more locals than a frame has slots
+fn too_many_locals() -> String {
+    format!("{{ {} }}", "let x; ".repeat(65536))
+}
+
+/// Printed output and either the parsing error messages or the runtime error message
+fn run(source: &str, config: ParserConfig) -> (String, Option<Result<String, Vec<String>>>) {
+    match run_source_with_config(source, config) {
+        Ok(output) => (output, None),
+        Err(RunError::Parse(errors)) => {
+            let messages = errors.into_iter().map(|err| err.msg).collect();
+            (String::new(), Some(Err(messages)))
+        }
+        Err(RunError::Runtime { error, output, .. }) => (output, Some(Ok(error.to_string()))),
+    }
+}
+
+fn check(case: &Case, config: ParserConfig) -> Result<(), String> {
+    let source = match case.source {
+        Text(source) => source.to_string(),
+        Generated(generate) => generate(),
+    };
+    let (output, error) = run(&source, config);
+
+    let printed: Vec<&str> = output.lines().collect();
+    if printed != case.prints {
+        return Err(format!("printed {printed:?}, expected {:?}", case.prints));
+    }
+    match (&case.error, error) {
+        (None, None) => Ok(()),
+        (Some(Parse(msg)), Some(Err(errors))) if errors.iter().any(|err| err.contains(msg)) => Ok(()),
+        (Some(Runtime(kind)), Some(Ok(err))) if err == *kind => Ok(()),
+        (expected, got) => Err(format!("failed with {got:?}, expected {expected:?}")),
+    }
+}
+
+fn check_all(config: ParserConfig) {
+    let failures: Vec<String> = CASES
+        .iter()
+        .filter_map(|case| check(case, config).err().map(|err| format!("{}: {}", case.name, err)))
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} cases failed (corpus v{}):\n{}",
+        failures.len(),
+        CASES.len(),
+        CORPUS_VERSION,
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn conformance() {
+    check_all(ParserConfig::default());
+}
+
+/// The optimization passes can't change what any program does
+#[test]
+fn optimized_conformance() {
+    check_all(ParserConfig { optimize: true, ..ParserConfig::default() });
+}
+
+#[test]
+fn case_names_are_unique() {
+    let mut names: Vec<_> = CASES.iter().map(|case| case.name).collect();
+    names.sort_unstable();
+    names.dedup();
+    assert_eq!(names.len(), CASES.len());
+}